@@ -1,11 +1,16 @@
 use crate::cursor::Cursor;
+use crate::highlight::Highlight;
+use crate::layout::{Layout, LayoutParams, Metrics};
+use crate::line_cache::LineCache;
+use crate::syntax;
 use crate::Position;
 #[allow(unused_imports)]
 use log::{debug, error, info, trace, warn};
-use rusttype::Font;
+use std::cell::{Cell, RefCell};
 use std::cmp::{max, min};
 use std::fs;
 use std::io::Read;
+use std::path::{Path, PathBuf};
 
 #[cfg(test)]
 mod tests {
@@ -17,15 +22,18 @@ mod tests {
     #[test]
     fn test_new_buffer() {
         let buff = TextBuffer::new(BufferOrigin::Empty, None, None, 1.0);
-        assert_eq!(buff.buffer, Vec::<String>::new());
+        assert_eq!(buff.buffer, vec![String::new()]);
         assert_eq!(buff.cursor.text_pos, (0, 0));
-        assert_eq!(buff.view_pos, (0, 0));
+        let mut empty = TextBuffer::new(BufferOrigin::Buffer(String::new()), None, None, 1.0);
+        empty.move_cursor_absolute(0, 0);
+        assert_eq!(empty.cursor.text_pos, (0, 0));
+        assert_eq!(buff.view_pos.get(), (0, 0));
         assert!(buff.file.is_none());
         assert_eq!(buff.buffer_type, BufferType::Clear);
         assert!(buff.file.is_none());
         let buff = TextBuffer::new(BufferOrigin::Empty, Some((1, 2)), Some((3, 4)),1.0);
         assert_eq!(buff.cursor.text_pos, (1, 2));
-        assert_eq!(buff.view_pos, (3, 4));
+        assert_eq!(buff.view_pos.get(), (3, 4));
         let buff = TextBuffer::new(BufferOrigin::Buffer("test".to_string()), None, None, 1.0);
         assert_eq!(buff.buffer, vec!["test".to_string()]);
         match std::fs::File::open("./Cargo.toml") {
@@ -59,34 +67,37 @@ fa"
             ),
             None,
             None,
+            1.0,
         );
-        assert_eq!(buff.cursor, (0, 0));
+        assert_eq!(buff.cursor.text_pos, (0, 0));
         buff.move_cursor_absolute(1, 2);
-        assert_eq!(buff.cursor, (1, 2));
+        assert_eq!(buff.cursor.text_pos, (1, 2));
         buff.move_cursor_absolute(2, 1);
-        assert_eq!(buff.cursor, (2, 1));
+        assert_eq!(buff.cursor.text_pos, (2, 1));
         buff.move_cursor_absolute(1000, 100);
-        assert_eq!(buff.cursor, (3, 2));
+        assert_eq!(buff.cursor.text_pos, (3, 2));
 
         buff.move_cursor_relative(-1000, -10000);
-        assert_eq!(buff.cursor, (0, 0));
+        assert_eq!(buff.cursor.text_pos, (0, 0));
         buff.move_cursor_relative(1, 2);
-        assert_eq!(buff.cursor, (1, 2));
+        assert_eq!(buff.cursor.text_pos, (1, 2));
         buff.move_cursor_relative(1, 1);
-        assert_eq!(buff.cursor, (2, 3));
+        assert_eq!(buff.cursor.text_pos, (2, 3));
         buff.move_cursor_relative(0, 1);
-        assert_eq!(buff.cursor, (3, 0));
+        assert_eq!(buff.cursor.text_pos, (3, 0));
         buff.move_cursor_relative(-1, 0);
-        assert_eq!(buff.cursor, (2, 0));
+        assert_eq!(buff.cursor.text_pos, (2, 0));
         buff.move_cursor_relative(0, -1);
-        assert_eq!(buff.cursor, (1, 14));
+        assert_eq!(buff.cursor.text_pos, (1, 14));
     }
 }
 
 /// Indicates if bUffer contains a source code
-#[derive(Debug, PartialEq)]
+#[derive(Debug, PartialEq, Clone)]
 pub enum BufferType {
-    Lang(Lang),
+    /// source code, contains name of syntect syntax used for highlighting
+    Lang(String),
+    /// plain text, not highlighted
     Clear,
 }
 
@@ -102,41 +113,49 @@ pub enum BufferOrigin {
     Empty,
 }
 
-/// List of programming languages
-#[derive(Debug, PartialEq)]
-pub enum Lang {
-    Rust,
-}
-
 // File buffer, optionaly tied to file
-pub struct TextBuffer<'a> {
+pub struct TextBuffer {
     pub buffer: Vec<String>,
     pub buffer_type: BufferType,
     ///row and column where curosr is located
     pub cursor: Cursor,
-    pub view_pos: (usize, usize),
+    /// first visible row and column, updated by frontend when it scrolls to
+    /// cursor
+    pub view_pos: Cell<(usize, usize)>,
+    /// start of Visual mode selection, text between it and cursor is selected
+    pub selection: Option<(usize, usize)>,
     pub file: Option<fs::File>,
-    pub font: Font<'a>,
+    /// path of file tied to buffer
+    pub path: Option<PathBuf>,
+    /// font size overriding global one, set by `:setlocal fontsize`
+    pub font_size: Option<f32>,
+    /// display lines of buffer, parameters are updated by frontend
+    pub layout: RefCell<Layout>,
+    /// cached syntax highlighting, updated by frontend
+    pub highlight: RefCell<Highlight>,
+    /// glyphs of lines drawn by renderer
+    pub glyphs: RefCell<LineCache>,
 }
 
-impl TextBuffer<'_> {
+impl TextBuffer {
     pub fn new(
         buffer: BufferOrigin,
         cursor: Option<(usize, usize)>,
         view_pos: Option<(usize, usize)>,
         screen_scale: f32,
     ) -> Self {
-        trace!("Loading font \"./Hack-Regular.ttf\"");
-        let font_path = std::env::current_dir().unwrap().join("./Hack-Regular.ttf");
-        let data = std::fs::read(&font_path).unwrap();
-        let font = Font::try_from_vec(data).unwrap();
         let mut buf = TextBuffer {
             buffer: Vec::<String>::new(),
             buffer_type: BufferType::Clear,
             cursor: Cursor::new(),
-            view_pos: (0, 0),
+            view_pos: Cell::new((0, 0)),
+            selection: None,
             file: None,
-            font,
+            path: None,
+            font_size: None,
+            layout: RefCell::new(Layout::new(LayoutParams::default())),
+            highlight: RefCell::new(Highlight::new()),
+            glyphs: RefCell::new(LineCache::new()),
         };
         buf.cursor.screen_scale =  screen_scale;
 
@@ -144,9 +163,8 @@ impl TextBuffer<'_> {
             buf.cursor
                 .move_to(Position::Absolute(row), Position::Absolute(col));
         }
-        if let Some((row, col)) = view_pos {
-            buf.view_pos.0 = row;
-            buf.view_pos.1 = col;
+        if let Some(view_pos) = view_pos {
+            buf.view_pos.set(view_pos);
         }
 
         match buffer {
@@ -167,51 +185,63 @@ impl TextBuffer<'_> {
             }
             BufferOrigin::Empty => {}
         }
+        // cursor is always on some line, even in empty file
+        if buf.buffer.is_empty() {
+            buf.buffer.push(String::new());
+        }
+        buf.buffer_type = syntax::detect(None, &buf.buffer);
         return buf;
     }
 
+    /// opens file at `path` and detects its language
+    pub fn open(path: &Path, screen_scale: f32) -> Result<Self, failure::Error> {
+        let file = fs::File::open(path)
+            .map_err(|e| format_err!("Could not open {:?}: {}", path, e))?;
+        let mut buf = Self::new(BufferOrigin::File(file), None, None, screen_scale);
+        buf.buffer_type = syntax::detect(Some(path), &buf.buffer);
+        buf.path = Some(path.to_path_buf());
+        Ok(buf)
+    }
+
+    /// sets language used for highlighting, see [syntax::find](syntax::find)
+    pub fn set_filetype(&mut self, name: &str) -> Result<(), failure::Error> {
+        self.buffer_type = syntax::find(name)?;
+        trace!("Filetype set to {:?}", self.buffer_type);
+        Ok(())
+    }
+
     /// Inserts char at cursor position or `pos` position if specified
     /// if `pos` is not specified moves cursor 1 position to the right
     /// `pos` is `(row, col)`.
     pub fn insert(&mut self, ch: char, pos: Option<(usize, usize)>) {
         let (mut row, mut col) = self.cursor.text_pos;
 
-        col = min(col, self.buffer[row].len());
+        col = min(col, self.buffer[row].chars().count());
 
         if let Some((r, c)) = pos {
             row = r;
             col = c;
         }
 
+        self.invalidate_from(row);
         match ch {
             '\r' => {
-                let mut tmp = self.buffer[row].clone();
-                let (left, right) = tmp.split_at_mut(col);
-                self.buffer[row] = right.to_string();
-                self.buffer.insert(row, left.to_string());
+                // column is in chars
+                let line = &self.buffer[row];
+                let split = line.char_indices().nth(col).map_or(line.len(), |(i, _)| i);
+                let right = self.buffer[row].split_off(split);
+                self.buffer.insert(row + 1, right);
                 if let None = pos {
                     self.cursor
                         .move_to(Position::Relative(1), Position::Absolute(0));
                 }
             }
             _ => {
-                let mut new_str = String::new();
-                let mut i: usize = 0;
-                if col <= self.buffer[row].len(){
-                loop {
-                    if i == col {
-                        new_str.push(ch);
-                    }
-                    if let Some(ch) = self.buffer[row].chars().nth(i) {
-                        new_str.push(ch);
-                    } else {
-                        break;
-                    }
-                    i += 1;
-                }
-                self.buffer[row] = new_str;
-                }else{
-                    self.buffer[row].push(ch);
+                // column is in chars, columns after the end append to line
+                let line = &mut self.buffer[row];
+                match line.char_indices().nth(col) {
+                    Some((i, _)) => line.insert(i, ch),
+                    None => line.push(ch),
                 }
                 if let None = pos {
                     self.cursor
@@ -221,13 +251,78 @@ impl TextBuffer<'_> {
         }
     }
 
+    /// Inserts line `text` before line `row` (after the last line if `row`
+    /// is number of lines), cursor moves to its end
+    pub fn insert_line(&mut self, row: usize, text: &str) {
+        let row = min(row, self.buffer.len());
+        self.invalidate_from(row);
+        self.buffer.insert(row, text.to_string());
+        self.cursor
+            .move_to(Position::Absolute(row), Position::Absolute(text.chars().count()));
+    }
+
+    /// Replaces leading whitespace of line `row` by `indent`. Cursor on the
+    /// line stays at the same text, or goes after the indentation if it was
+    /// in it.
+    pub fn set_indent(&mut self, row: usize, indent: &str) {
+        self.invalidate_from(row);
+        let line = &mut self.buffer[row];
+        let old = line.chars().take_while(|c| c.is_whitespace()).count();
+        let text: String = line.chars().skip(old).collect();
+        *line = indent.to_string() + &text;
+        let (cursor_row, col) = self.cursor.text_pos;
+        if cursor_row == row {
+            let col = col.saturating_sub(old) + indent.chars().count();
+            self.cursor
+                .move_to(Position::Absolute(row), Position::Absolute(col));
+        }
+    }
+
+    /// Removes chars `start..end` of line `row` and returns them, columns
+    /// are in chars. Cursor moves to `start`.
+    pub fn remove(&mut self, row: usize, start: usize, end: usize) -> String {
+        self.invalidate_from(row);
+        let line = &mut self.buffer[row];
+        let byte = |col: usize| line.char_indices().nth(col).map_or(line.len(), |(i, _)| i);
+        let (start_byte, end_byte) = (byte(start), byte(end));
+        let removed = line.drain(start_byte..end_byte).collect();
+        self.move_cursor_absolute(row, start);
+        removed
+    }
+
+    /// Writes lines to `path` or to file of buffer if `path` is `None`.
+    /// Buffer without file gets `path`.
+    pub fn save(&mut self, path: Option<&Path>) -> Result<(), failure::Error> {
+        let path = match (path, &self.path) {
+            (Some(path), _) => path.to_path_buf(),
+            (None, Some(path)) => path.clone(),
+            (None, None) => bail!("No file name"),
+        };
+        let mut text = self.buffer.join("\n");
+        text.push('\n');
+        fs::write(&path, text).map_err(|e| format_err!("Could not write {:?}: {}", path, e))?;
+        info!("Written {:?}", path);
+        if self.path.is_none() {
+            self.path = Some(path);
+        }
+        Ok(())
+    }
+
+    /// cached layout, highlighting and glyphs of line `row` and following
+    /// lines are no longer valid
+    fn invalidate_from(&mut self, row: usize) {
+        self.layout.get_mut().invalidate_from(row);
+        self.highlight.get_mut().invalidate_from(row);
+        self.glyphs.get_mut().invalidate_from(row);
+    }
+
     /// sets cursor to absolute x and y or to the end of line/buffer
     /// cursor is first moved vertically and then horizontaly, if horizontal
     /// move is past the end of line, it continues on the next line(except for
     /// the end of file, where is stops)
     pub fn move_cursor_absolute(&mut self, vertical: usize, horizontal: usize) {
-        let  row = min(vertical, self.buffer.len() - 1);
-        let  col = horizontal;
+        let row = min(vertical, self.buffer.len() - 1);
+        let col = min(horizontal, self.buffer[row].chars().count());
         self.cursor
             .move_to(Position::Absolute(row), Position::Absolute(col))
     }
@@ -245,13 +340,13 @@ impl TextBuffer<'_> {
         if new_horizontal < 0 {
             if new_vertical != 0{
                 new_vertical -=1;
-                new_horizontal = self.buffer[new_vertical].len() as i32;
+                new_horizontal = self.buffer[new_vertical].chars().count() as i32;
             }else{
                 new_horizontal = 0;
             }
-        }else if new_horizontal > self.buffer[new_vertical].len() as i32{
+        }else if new_horizontal > self.buffer[new_vertical].chars().count() as i32{
             if new_vertical as usize == self.buffer.len() -1{
-                new_horizontal = self.buffer[new_vertical].len() as i32;
+                new_horizontal = self.buffer[new_vertical].chars().count() as i32;
             }else{
                 if vertical == 0{
 
@@ -281,10 +376,13 @@ impl TextBuffer<'_> {
         }
 
 
-        col = min(col, self.buffer[row].len());
+        col = min(col, self.buffer[row].chars().count());
+        self.invalidate_from(row.saturating_sub(1));
         self.move_cursor_relative(0, -1);
         if col != 0{
-            self.buffer[row].remove(col-1);
+            // column is in chars
+            let (i, _) = self.buffer[row].char_indices().nth(col - 1).unwrap();
+            self.buffer[row].remove(i);
         }else{
             let mut tmp = self.buffer[row-1].clone();
             tmp.push_str(self.buffer[row].as_str());
@@ -292,4 +390,15 @@ impl TextBuffer<'_> {
             self.buffer.remove(row);
         }
     }
+
+    /// moves cursor by `lines` display lines up (negative) or down, used by `gj` and `gk`
+    /// cursor keeps its horizontal position on screen, `metrics` are metrics
+    /// of the frontend drawing the buffer
+    pub fn move_cursor_display(&mut self, metrics: &dyn Metrics, lines: i32) {
+        let (row, col) =
+            self.layout
+                .get_mut()
+                .move_display(metrics, &self.buffer, self.cursor.text_pos, lines);
+        self.move_cursor_absolute(row, col);
+    }
 }
@@ -0,0 +1,107 @@
+use crate::key::{parse_keys, Key};
+use crate::editor::State;
+
+/// Command entered in Command mode
+#[derive(Debug, PartialEq)]
+pub enum Command {
+    /// `:set option...`
+    Set(Vec<String>),
+    /// `:setlocal option...`, sets options of current buffer
+    SetLocal(Vec<String>),
+    /// `:map lhs rhs`, `:noremap lhs rhs` and their mode variants
+    Map {
+        modes: Vec<State>,
+        lhs: Vec<Key>,
+        rhs: Vec<Key>,
+        noremap: bool,
+    },
+    /// `:unmap lhs` and its mode variants
+    Unmap { modes: Vec<State>, lhs: Vec<Key> },
+    /// `:colorscheme [name]`, without name shows current theme
+    Colorscheme(Option<String>),
+    /// `:dumpatlas [file]`, saves glyph cache texture to PNG for debugging
+    DumpAtlas(Option<String>),
+    /// `:write [file]`, saves buffer to its file or to `file`
+    Write(Option<String>),
+    /// `:quit`, closes the editor
+    Quit,
+}
+
+/// returns modes affected by mapping command with prefix `prefix` (e.g. `n`
+/// for `:nmap`)
+fn map_modes(prefix: &str) -> Option<Vec<State>> {
+    Some(match prefix {
+        "" => vec![State::Normal, State::Visual],
+        "n" => vec![State::Normal],
+        "i" => vec![State::Insert],
+        "v" | "x" => vec![State::Visual],
+        "c" => vec![State::Command],
+        _ => return None,
+    })
+}
+
+impl Command {
+    /// Parses command line (without leading `:`)
+    pub fn parse(line: &str) -> Result<Self, failure::Error> {
+        let mut words = line.split_whitespace();
+        let name = match words.next() {
+            Some(name) => name,
+            None => bail!("Empty command"),
+        };
+        if name == "set" || name == "se" {
+            return Ok(Command::Set(words.map(|w| w.to_string()).collect()));
+        }
+        if name == "setlocal" || name == "setl" {
+            return Ok(Command::SetLocal(words.map(|w| w.to_string()).collect()));
+        }
+
+        if name == "colorscheme" || name == "colo" {
+            return Ok(Command::Colorscheme(words.next().map(|w| w.to_string())));
+        }
+        if name == "write" || name == "w" {
+            return Ok(Command::Write(words.next().map(|w| w.to_string())));
+        }
+        if name == "quit" || name == "q" {
+            return Ok(Command::Quit);
+        }
+        if name == "dumpatlas" {
+            return Ok(Command::DumpAtlas(words.next().map(|w| w.to_string())));
+        }
+
+        if let Some(prefix) = name.strip_suffix("unmap") {
+            if let Some(modes) = map_modes(prefix) {
+                let lhs = match words.next() {
+                    Some(lhs) => parse_keys(lhs)?,
+                    None => bail!("Argument required"),
+                };
+                return Ok(Command::Unmap { modes, lhs });
+            }
+        }
+
+        let (prefix, noremap) = if let Some(prefix) = name.strip_suffix("noremap") {
+            (Some(prefix), true)
+        } else {
+            (name.strip_suffix("map"), false)
+        };
+        if let Some(modes) = prefix.and_then(map_modes) {
+            // rest of line is right hand side, it can contain spaces
+            let lhs = words.next();
+            let rhs = words.collect::<Vec<_>>().join(" ");
+            let (lhs, rhs) = match lhs {
+                Some(lhs) if !rhs.is_empty() => (parse_keys(lhs)?, parse_keys(&rhs)?),
+                _ => bail!("Usage: {} lhs rhs", name),
+            };
+            if lhs.is_empty() {
+                bail!("Argument required");
+            }
+            return Ok(Command::Map {
+                modes,
+                lhs,
+                rhs,
+                noremap,
+            });
+        }
+
+        bail!("Not an editor command: {}", line)
+    }
+}
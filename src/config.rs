@@ -0,0 +1,290 @@
+//! Configuration file.
+//!
+//! Config is read from `$XDG_CONFIG_HOME/sietor/config.toml` (or
+//! `~/.config/sietor/config.toml`). All keys are optional, missing ones keep
+//! their default value. Unknown keys are reported as errors.
+//!
+//! ```toml
+//! [editor]
+//! tabstop = 4          # number of spaces inserted by tab
+//! wrap = true          # wrap long lines
+//! linebreak = false    # wrap at word boundary
+//! showbreak = ""       # string displayed at start of wrapped lines
+//! timeoutlen = 1000    # ms to wait for next key of mapped sequence
+//! mousescroll = 3      # display lines scrolled by one step of mouse wheel
+//! autoindent = true    # new line keeps indentation of previous line
+//! smartindent = true   # indent after `{`, `(`, ... and dedent closing brackets
+//!
+//! [font]
+//! family = "Hack"     # family name or path of font file, built-in font if empty
+//! size = 24.0
+//! fallback = ["DejaVuSans.ttf", "NotoSansCJK-Regular.ttc"]  # fonts for missing glyphs
+//!
+//! [theme]
+//! name = "base16-ocean.dark"
+//!
+//! [window]
+//! width = 512
+//! height = 512
+//!
+//! # smartindent rules of filetype, `open` chars at end of line indent next
+//! # line, `close` brackets are dedented, missing keys keep built-in rules
+//! [indent.Python]
+//! open = "{([:"
+//! close = "})]"
+//! ```
+//!
+//! File is watched for changes and reloaded while editor is running.
+//!
+//! State that should survive restart but is not configuration (zoom level)
+//! is saved to `$XDG_STATE_HOME/sietor/state.toml` (or
+//! `~/.local/state/sietor/state.toml`).
+use crate::buffer::BufferType;
+use crate::indent::{self, Rules};
+use crate::options::Options;
+use crate::syntax;
+#[allow(unused_imports)]
+use log::{debug, error, info, trace, warn};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+
+#[cfg(test)]
+mod tests {
+    use super::{load_state, parse, save_state, SavedState};
+    use crate::indent::Rules;
+    use crate::options::Options;
+
+    #[test]
+    fn test_parse() {
+        assert_eq!(parse("").unwrap(), Options::default());
+        let opts = parse(
+            "[editor]
+tabstop = 8
+wrap = false
+[font]
+size = 12.5
+[window]
+width = 800",
+        )
+        .unwrap();
+        assert_eq!(opts.tabstop, 8);
+        assert!(!opts.wrap);
+        assert_eq!(opts.font_size, 12.5);
+        assert_eq!(opts.window_size, (800, 512));
+        assert_eq!(opts.theme, Options::default().theme);
+
+        assert!(parse("[editor]\ntabsize = 8").is_err());
+        assert!(parse("[editor]\ntabstop = 0").is_err());
+        assert!(parse("[font]\nsize = \"big\"").is_err());
+    }
+
+    #[test]
+    fn test_indent() {
+        let opts = parse("[indent.rust]\nopen = \"{\"\n[indent.Markdown]\nclose = \")\"").unwrap();
+        let rules = |open: &str, close: &str| Rules {
+            open: open.to_string(),
+            close: close.to_string(),
+        };
+        // filetype names are resolved to syntax names, missing keys are
+        // built-in rules
+        assert_eq!(opts.indent["Rust"], rules("{", "})]"));
+        assert_eq!(opts.indent["Markdown"], rules("", ")"));
+
+        assert!(parse("[indent.nosuchlang]\nopen = \"{\"").is_err());
+        assert!(parse("[indent.text]\nopen = \"{\"").is_err());
+        assert!(parse("[indent.Rust]\nclose = \"}:\"").is_err());
+        assert!(parse("[indent.Rust]\nindent = 2").is_err());
+    }
+
+    #[test]
+    fn test_state() {
+        let path =
+            std::env::temp_dir().join(format!("sietor-test-{}/state.toml", std::process::id()));
+        assert_eq!(load_state(&path), SavedState::default());
+        save_state(&path, &SavedState { zoom: -3 }).unwrap();
+        assert_eq!(load_state(&path).zoom, -3);
+        std::fs::write(&path, "zoom = \"big\"").unwrap();
+        assert_eq!(load_state(&path), SavedState::default());
+        std::fs::remove_dir_all(path.parent().unwrap()).unwrap();
+    }
+}
+
+#[derive(Deserialize, Default)]
+#[serde(deny_unknown_fields)]
+struct ConfigFile {
+    #[serde(default)]
+    editor: EditorSection,
+    #[serde(default)]
+    font: FontSection,
+    #[serde(default)]
+    theme: ThemeSection,
+    #[serde(default)]
+    window: WindowSection,
+    /// sections `[indent.<filetype>]`
+    #[serde(default)]
+    indent: HashMap<String, IndentSection>,
+}
+
+#[derive(Deserialize, Default)]
+#[serde(deny_unknown_fields)]
+struct EditorSection {
+    tabstop: Option<usize>,
+    wrap: Option<bool>,
+    linebreak: Option<bool>,
+    showbreak: Option<String>,
+    timeoutlen: Option<u64>,
+    mousescroll: Option<usize>,
+    autoindent: Option<bool>,
+    smartindent: Option<bool>,
+}
+
+#[derive(Deserialize, Default)]
+#[serde(deny_unknown_fields)]
+struct FontSection {
+    #[serde(alias = "family")]
+    path: Option<String>,
+    size: Option<f32>,
+    fallback: Option<Vec<String>>,
+}
+
+#[derive(Deserialize, Default)]
+#[serde(deny_unknown_fields)]
+struct ThemeSection {
+    name: Option<String>,
+}
+
+#[derive(Deserialize, Default)]
+#[serde(deny_unknown_fields)]
+struct WindowSection {
+    width: Option<u32>,
+    height: Option<u32>,
+}
+
+#[derive(Deserialize, Default)]
+#[serde(deny_unknown_fields)]
+struct IndentSection {
+    open: Option<String>,
+    close: Option<String>,
+}
+
+/// Editor state saved between runs
+#[derive(Serialize, Deserialize, Default, Debug, PartialEq)]
+#[serde(default)]
+pub struct SavedState {
+    /// zoom steps of window, see [zoomed_size](crate::options::zoomed_size)
+    pub zoom: i32,
+}
+
+/// returns path of file with saved state
+pub fn state_path() -> Option<PathBuf> {
+    let dir = match std::env::var_os("XDG_STATE_HOME") {
+        Some(dir) if !dir.is_empty() => PathBuf::from(dir),
+        _ => PathBuf::from(std::env::var_os("HOME")?).join(".local/state"),
+    };
+    Some(dir.join("sietor/state.toml"))
+}
+
+/// Loads saved state, missing or invalid file is the default state
+pub fn load_state(path: &Path) -> SavedState {
+    match std::fs::read_to_string(path) {
+        Ok(text) => toml::from_str(&text).unwrap_or_else(|e| {
+            warn!("Invalid state file {:?}: {}", path, e);
+            SavedState::default()
+        }),
+        Err(_) => SavedState::default(),
+    }
+}
+
+/// saves state to `path`, creating its directory
+pub fn save_state(path: &Path, state: &SavedState) -> Result<(), failure::Error> {
+    if let Some(dir) = path.parent() {
+        std::fs::create_dir_all(dir)?;
+    }
+    std::fs::write(path, toml::to_string(state)?)
+        .map_err(|e| format_err!("Could not save state to {:?}: {}", path, e))
+}
+
+/// returns directory with configuration files
+pub fn config_dir() -> Option<PathBuf> {
+    match std::env::var_os("XDG_CONFIG_HOME") {
+        Some(dir) if !dir.is_empty() => Some(PathBuf::from(dir).join("sietor")),
+        _ => std::env::var_os("HOME").map(|home| PathBuf::from(home).join(".config/sietor")),
+    }
+}
+
+/// returns path of config file
+pub fn config_path() -> Option<PathBuf> {
+    config_dir().map(|dir| dir.join("config.toml"))
+}
+
+/// Parses config file contents, values not present in config are taken from
+/// `Options::default()`
+pub fn parse(text: &str) -> Result<Options, failure::Error> {
+    let file: ConfigFile = toml::from_str(text)?;
+    let mut opts = Options::default();
+
+    let e = file.editor;
+    opts.tabstop = e.tabstop.unwrap_or(opts.tabstop);
+    opts.wrap = e.wrap.unwrap_or(opts.wrap);
+    opts.linebreak = e.linebreak.unwrap_or(opts.linebreak);
+    opts.showbreak = e.showbreak.unwrap_or(opts.showbreak);
+    opts.timeoutlen = e.timeoutlen.unwrap_or(opts.timeoutlen);
+    opts.mousescroll = e.mousescroll.unwrap_or(opts.mousescroll);
+    opts.autoindent = e.autoindent.unwrap_or(opts.autoindent);
+    opts.smartindent = e.smartindent.unwrap_or(opts.smartindent);
+    opts.font = file.font.path.unwrap_or(opts.font);
+    opts.font_size = file.font.size.unwrap_or(opts.font_size);
+    opts.fallback = file.font.fallback.unwrap_or(opts.fallback);
+    opts.theme = file.theme.name.unwrap_or(opts.theme);
+    opts.window_size.0 = file.window.width.unwrap_or(opts.window_size.0);
+    opts.window_size.1 = file.window.height.unwrap_or(opts.window_size.1);
+    for (name, section) in file.indent {
+        let lang = match syntax::find(&name)? {
+            BufferType::Lang(lang) => lang,
+            BufferType::Clear => bail!("Plain text has no indent rules"),
+        };
+        let defaults = indent::default_rules(&lang).unwrap_or_default();
+        let rules = Rules {
+            open: section.open.unwrap_or(defaults.open),
+            close: section.close.unwrap_or(defaults.close),
+        };
+        opts.indent.insert(lang, rules);
+    }
+
+    opts.validate()?;
+    Ok(opts)
+}
+
+/// Loads config from `path`, missing file is not an error
+pub fn load(path: &Path) -> Result<Options, failure::Error> {
+    if !path.exists() {
+        trace!("Config file {:?} doesn't exist, using defaults", path);
+        return Ok(Options::default());
+    }
+    trace!("Loading config {:?}", path);
+    let text = std::fs::read_to_string(path)?;
+    parse(&text).map_err(|e| format_err!("Invalid config file {:?}: {}", path, e))
+}
+
+/// Spawns thread that calls `on_change` every time config file at `path` is
+/// modified (or created or deleted)
+pub fn watch<F>(path: PathBuf, on_change: F)
+where
+    F: Fn() + Send + 'static,
+{
+    let modified = |path: &Path| -> Option<SystemTime> { std::fs::metadata(path).ok()?.modified().ok() };
+    std::thread::spawn(move || {
+        let mut last = modified(&path);
+        loop {
+            std::thread::sleep(Duration::from_millis(500));
+            let current = modified(&path);
+            if current != last {
+                last = current;
+                trace!("Config file {:?} changed", path);
+                on_change();
+            }
+        }
+    });
+}
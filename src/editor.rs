@@ -0,0 +1,834 @@
+//! Editing state and behavior independent of window system.
+//!
+//! Frontends translate events of their window system or terminal to [Input],
+//! pass them to [Editor::apply] and draw [View] of the editor. Caches of
+//! buffers (layout, highlighting, glyphs) and their scroll position are
+//! updated while drawing, so they can be changed through the view.
+use crate::buffer::{BufferOrigin, TextBuffer};
+use crate::command::Command;
+use crate::config;
+use crate::debug::failure_to_string;
+use crate::indent;
+use crate::key::Key;
+use crate::keymap::{Action, Keymap, Resolved};
+use crate::layout::Metrics;
+use crate::options::{zoomed_size, Options, MAX_ZOOM};
+use crate::replay::Recorder;
+#[allow(unused_imports)]
+use log::{debug, error, info, trace, warn};
+use std::path::PathBuf;
+use std::time::{Duration, Instant};
+
+#[cfg(test)]
+mod tests {
+    use super::{word_at, Ime, Mouse, State};
+    use std::time::Duration;
+    use crate::buffer::BufferType;
+    use crate::harness::Harness;
+
+    #[test]
+    fn test_insert_and_delete() {
+        let mut h = Harness::new("ab\ncd");
+        h.keys("jix<Esc>");
+        h.assert_text("ab\nxcd");
+        h.assert_cursor((1, 1));
+        h.assert_mode(State::Normal);
+
+        h.keys("i<BS><BS>");
+        h.assert_text("abcd");
+        h.assert_cursor((0, 2));
+        h.keys("<CR>");
+        h.assert_text("ab\ncd");
+        h.assert_mode(State::Insert);
+    }
+
+    #[test]
+    fn test_multibyte_cursor() {
+        // columns are chars, not bytes
+        let mut h = Harness::new("日本\nabcdef");
+        h.keys("jlllllk");
+        h.assert_cursor((0, 2));
+        h.keys("ix<Esc>");
+        h.assert_text("日本x\nabcdef");
+    }
+
+    #[test]
+    fn test_commands() {
+        let mut h = Harness::new("ab\ncd");
+        h.keys(":setl ft=rust");
+        h.assert_mode(State::Command);
+        assert_eq!(h.editor.command_line, "setl ft=rust");
+        h.keys("<CR>");
+        h.assert_mode(State::Normal);
+        assert_eq!(h.buffer().buffer_type, BufferType::Lang("Rust".to_string()));
+
+        // backspace on empty command line leaves Command mode
+        h.keys(":s<BS><BS>");
+        h.assert_mode(State::Normal);
+        h.keys(":q<CR>");
+        assert!(h.editor.quit);
+    }
+
+    #[test]
+    fn test_mappings() {
+        let mut h = Harness::new("a");
+        h.keys(":inoremap jk <lt>Esc><CR>");
+        h.keys("ijj");
+        h.assert_mode(State::Insert);
+        h.keys("jk");
+        h.assert_mode(State::Normal);
+        h.assert_text("jja");
+        // unfinished mapping is typed after timeout
+        h.keys("ij");
+        h.assert_text("jjja");
+        h.assert_mode(State::Insert);
+    }
+
+    #[test]
+    fn test_indent() {
+        let mut h = Harness::new("fn main() {}");
+        h.keys(":setl ft=rust<CR>");
+        h.keys("llllllllllli<CR>");
+        h.assert_text("fn main() {\n    \n}");
+        h.assert_cursor((1, 4));
+        h.keys("if a {<CR>b<CR>}");
+        h.assert_text("fn main() {\n    if a {\n        b\n    }\n}");
+        h.keys("<Esc>kO");
+        h.assert_text("fn main() {\n    if a {\n        \n        b\n    }\n}");
+        h.assert_mode(State::Insert);
+        h.keys("<Esc>jjo");
+        h.assert_cursor((5, 4));
+        h.keys("<Esc>:set noai nosi<CR>o");
+        h.assert_cursor((6, 0));
+
+        // columns of lines with multibyte chars are in chars
+        let mut h = Harness::new("  é {}");
+        h.keys(":setl ft=rust<CR>lllli<CR>");
+        h.assert_text("  é \n  {}");
+        let mut h = Harness::new("日本");
+        h.keys("lli<CR>");
+        h.assert_text("日本\n");
+        h.assert_cursor((1, 0));
+    }
+
+    #[test]
+    fn test_visual_mode() {
+        let mut h = Harness::new("abc\ndef");
+        h.keys("lvj");
+        h.assert_mode(State::Visual);
+        assert_eq!(h.buffer().selection, Some((0, 1)));
+        h.assert_cursor((1, 1));
+        h.keys("<Esc>");
+        assert_eq!(h.buffer().selection, None);
+    }
+
+    #[test]
+    fn test_mouse() {
+        let mut h = Harness::new("foo bar.baz\nline two\n3\n4\n5\n6");
+        h.mouse(Mouse::Press(5.0, 0.0));
+        h.assert_cursor((0, 5));
+        h.assert_mode(State::Normal);
+        h.mouse(Mouse::Press(5.0, 0.0));
+        h.assert_mode(State::Visual);
+        assert_eq!(h.buffer().selection, Some((0, 4)));
+        h.assert_cursor((0, 6));
+        h.mouse(Mouse::Press(5.0, 0.0));
+        assert_eq!(h.buffer().selection, Some((0, 0)));
+        h.assert_cursor((0, 10));
+
+        // too late for double click
+        h.mouse(Mouse::Press(2.0, 1.0));
+        h.wait(Duration::from_secs(1));
+        h.mouse(Mouse::Press(2.0, 1.0));
+        h.assert_mode(State::Normal);
+        h.assert_cursor((1, 2));
+        h.mouse(Mouse::Drag(6.0, 1.0));
+        h.assert_mode(State::Visual);
+        assert_eq!(h.buffer().selection, Some((1, 2)));
+        h.assert_cursor((1, 6));
+
+        h.keys("<Esc>:set mousescroll=2<CR>");
+        h.mouse(Mouse::Press(0.0, 0.0));
+        h.mouse(Mouse::Wheel(2));
+        h.assert_cursor((4, 0));
+        assert_eq!(h.buffer().view_pos.get().0, 4);
+        h.mouse(Mouse::Wheel(-1));
+        h.assert_cursor((2, 0));
+        assert_eq!(h.buffer().view_pos.get().0, 2);
+    }
+
+    #[test]
+    fn test_ime() {
+        let mut h = Harness::new("ab");
+        h.keys("li");
+        h.ime(Ime::Preedit("にほ".to_string(), Some(6)));
+        assert_eq!(h.editor.view().preedit.map(|p| p.text.as_str()), Some("にほ"));
+        h.assert_text("ab");
+        h.ime(Ime::Commit("日本".to_string()));
+        assert_eq!(h.editor.preedit, None);
+        h.assert_text("a日本b");
+        h.assert_cursor((0, 3));
+        h.keys("<BS>");
+        h.assert_text("a日b");
+        h.keys("<CR>");
+        h.assert_text("a日\nb");
+        h.ime(Ime::Commit("本".to_string()));
+        h.keys("<BS><BS>");
+        h.assert_text("a日b");
+        h.assert_cursor((0, 2));
+
+        // empty preedit cancels composition
+        h.ime(Ime::Preedit("ご".to_string(), None));
+        h.ime(Ime::Preedit(String::new(), None));
+        assert_eq!(h.editor.preedit, None);
+
+        h.keys("<Esc>:");
+        h.ime(Ime::Commit("語".to_string()));
+        assert_eq!(h.editor.command_line, "語");
+        // in Normal mode committed text is typed as keys
+        h.keys("<Esc>");
+        h.ime(Ime::Commit("v".to_string()));
+        h.assert_mode(State::Visual);
+
+        // committed text after multibyte text is inserted at char column
+        let mut h = Harness::new("日本");
+        h.keys("li");
+        h.ime(Ime::Commit("語x".to_string()));
+        h.assert_text("日語x本");
+        h.assert_cursor((0, 3));
+        // column past the end but inside byte length of the line appends
+        h.editor.buffers[0].insert('!', Some((0, 5)));
+        h.assert_text("日語x本!");
+    }
+
+    #[test]
+    fn test_registers() {
+        let path = std::env::temp_dir().join(format!("sietor-write-{}.txt", std::process::id()));
+        let mut h = Harness::new("one two\nthree");
+        h.keys(&format!(":w {}<CR>", path.display()));
+        h.keys("dwjp<Esc>:w<CR>");
+        h.assert_text("two\ntone hree");
+        h.assert_cursor((1, 4));
+        h.assert_register("one ");
+        h.assert_mode(State::Normal);
+        let written = std::fs::read_to_string(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(written, "two\ntone hree\n");
+
+        // yank keeps text, last word of line ends at its end
+        h.keys("hhhyw");
+        h.assert_register("one ");
+        h.assert_text("two\ntone hree");
+        h.keys("khdw");
+        h.assert_text("\ntone hree");
+        h.assert_register("two");
+        h.keys("p");
+        h.assert_text("two\ntone hree");
+        h.assert_cursor((0, 2));
+    }
+
+    #[test]
+    fn test_word_at() {
+        assert_eq!(word_at("foo bar.baz", 0), (0, 2));
+        assert_eq!(word_at("foo bar.baz", 5), (4, 6));
+        assert_eq!(word_at("foo bar.baz", 7), (7, 7));
+        assert_eq!(word_at("a   b", 2), (1, 3));
+        assert_eq!(word_at("ab", 10), (0, 1));
+    }
+
+    #[test]
+    fn test_display_lines() {
+        let mut h = Harness::new("abcdefghij\nab");
+        h.set_width(4);
+        h.keys("gj");
+        h.assert_cursor((0, 4));
+        h.keys("gjgj");
+        h.assert_cursor((1, 0));
+        h.keys("gk");
+        h.assert_cursor((0, 8));
+    }
+}
+
+/// longest time between clicks of double and triple click
+const DOUBLE_CLICK: Duration = Duration::from_millis(500);
+
+/// returns first and last column of word, whitespace or punctuation at
+/// `col` of `line`
+fn word_at(line: &str, col: usize) -> (usize, usize) {
+    let chars: Vec<char> = line.chars().collect();
+    if chars.is_empty() {
+        return (0, 0);
+    }
+    let class = |c: char| {
+        if c.is_alphanumeric() || c == '_' {
+            0
+        } else if c.is_whitespace() {
+            1
+        } else {
+            2
+        }
+    };
+    let col = col.min(chars.len() - 1);
+    let same = |c: &char| class(*c) == class(chars[col]);
+    let start = col - chars[..col].iter().rev().take_while(|c| same(c)).count();
+    let end = col + chars[col + 1..].iter().take_while(|c| same(c)).count();
+    (start, end)
+}
+
+/// Returns column after word, whitespace or punctuation at `col` of
+/// `line` and whitespace following it, which is where `w` moves in the line
+fn word_end(line: &str, col: usize) -> usize {
+    let (_, end) = word_at(line, col);
+    let mut chars = line.chars().skip(col);
+    match chars.next() {
+        Some(c) if !c.is_whitespace() => {
+            end + 1 + line.chars().skip(end + 1).take_while(|c| c.is_whitespace()).count()
+        }
+        Some(_) => end + 1,
+        None => col,
+    }
+}
+
+/// Mode of editor
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum State {
+    Normal,
+    Insert,
+    Visual,
+    Command,
+}
+
+/// Input of editor
+#[derive(Clone, Debug, PartialEq)]
+pub enum Input {
+    /// key typed by user
+    Key(Key),
+    /// time passed, keys of unfinished mapping are resolved if mapping
+    /// timeout expired
+    Tick,
+    /// window (in pixels) or terminal (in cells) was resized, editor only
+    /// records it
+    Resize(u32, u32),
+    Mouse(Mouse),
+    /// text composed by input method
+    Ime(Ime),
+}
+
+/// Mouse input, positions are `(x, y)` from top left corner of text area in
+/// units of layout (pixels in window, cells in terminal)
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Mouse {
+    /// left button pressed, repeated presses select word and line
+    Press(f32, f32),
+    /// mouse moved with left button held
+    Drag(f32, f32),
+    /// wheel turned by steps, positive is down
+    Wheel(i32),
+}
+
+/// Composition of input method (for CJK text)
+#[derive(Clone, Debug, PartialEq)]
+pub enum Ime {
+    /// Text being composed and byte offset of cursor in it, `None` hides the
+    /// cursor. Empty text ends composition.
+    Preedit(String, Option<usize>),
+    /// text is finished and typed
+    Commit(String),
+}
+
+/// Text being composed by input method, drawn at cursor
+#[derive(Clone, Debug, PartialEq)]
+pub struct Preedit {
+    pub text: String,
+    /// byte offset of cursor in `text`
+    pub cursor: Option<usize>,
+}
+
+/// State of editor shown by frontends
+pub struct View<'a> {
+    pub state: State,
+    /// text typed in Command mode
+    pub command_line: &'a str,
+    pub options: &'a Options,
+    /// active buffer
+    pub buffer: &'a TextBuffer,
+    pub preedit: Option<&'a Preedit>,
+}
+
+/// Buffers, modes and commands shared by all frontends
+pub struct Editor {
+    pub buffers: Vec<TextBuffer>,
+    pub active_buffer: usize,
+    pub state: State,
+    pub options: Options,
+    /// text typed in Command mode
+    pub command_line: String,
+    pub keymap: Keymap,
+    /// zoom steps of window, saved to state file
+    pub zoom: i32,
+    pub state_path: Option<PathBuf>,
+    /// file requested by `:dumpatlas`, frontend saves its glyph cache to it
+    pub atlas_dump: Option<PathBuf>,
+    /// set by `:quit`, frontend exits when it sees it
+    pub quit: bool,
+    /// records processed inputs, started by `--record`
+    pub recorder: Option<Recorder>,
+    /// composition of input method in progress
+    pub preedit: Option<Preedit>,
+    /// unnamed register, filled by deletes and yanks, inserted by put
+    pub register: String,
+    /// time, position and count of last presses of mouse button at the same
+    /// position
+    last_click: Option<(Instant, (usize, usize), u32)>,
+}
+
+impl Editor {
+    pub fn new() -> Self {
+        Editor {
+            buffers: vec![],
+            active_buffer: 0,
+            state: State::Normal,
+            options: Options::default(),
+            command_line: String::new(),
+            keymap: Keymap::new(Duration::from_millis(Options::default().timeoutlen)),
+            zoom: 0,
+            state_path: None,
+            atlas_dump: None,
+            quit: false,
+            recorder: None,
+            preedit: None,
+            register: String::new(),
+            last_click: None,
+        }
+    }
+
+    /// Loads config file and saved state, returns path of config file to
+    /// watch
+    pub fn load_config(&mut self) -> Option<PathBuf> {
+        let config_path = config::config_path();
+        if let Some(path) = &config_path {
+            match config::load(path) {
+                Ok(opts) => self.options = opts,
+                Err(e) => error!("{}", failure_to_string(e)),
+            }
+        }
+
+        self.state_path = config::state_path();
+        if let Some(path) = &self.state_path {
+            self.zoom = config::load_state(path).zoom.clamp(-MAX_ZOOM, MAX_ZOOM);
+        }
+        config_path
+    }
+
+    /// opens buffers of `files`, welcome buffer is opened if there are none
+    pub fn open_files(&mut self, files: &[PathBuf], scale_factor: f32) {
+        for path in files {
+            match TextBuffer::open(path, scale_factor) {
+                Ok(buff) => self.buffers.push(buff),
+                Err(e) => error!("{}", failure_to_string(e)),
+            }
+        }
+        if self.buffers.is_empty() {
+            let buff = TextBuffer::new(
+                BufferOrigin::Buffer(
+                    "Welcome to Sietor
+idk, welcome text"
+                        .to_string(),
+                ),
+                None,
+                None,
+                scale_factor,
+            );
+            self.buffers.push(buff);
+        }
+    }
+
+    /// returns what frontends draw
+    pub fn view(&self) -> View<'_> {
+        View {
+            state: self.state,
+            command_line: &self.command_line,
+            options: &self.options,
+            buffer: &self.buffers[self.active_buffer],
+            preedit: self.preedit.as_ref(),
+        }
+    }
+
+    /// returns time when frontend has to send [Input::Tick], `None` if it
+    /// isn't waiting for anything
+    pub fn deadline(&self) -> Option<Instant> {
+        self.keymap.deadline()
+    }
+
+    /// Processes `input`, `metrics` are metrics of glyphs of active buffer
+    /// used by motions over display lines
+    pub fn apply(&mut self, input: Input, metrics: &dyn Metrics) {
+        self.apply_at(input, Instant::now(), metrics);
+    }
+
+    /// Like [apply](Editor::apply), but `input` arrived at `now`, used when
+    /// time is not real (tests)
+    pub fn apply_at(&mut self, input: Input, now: Instant, metrics: &dyn Metrics) {
+        if let Some(recorder) = &mut self.recorder {
+            if let Err(e) = recorder.record(&input, now) {
+                error!("Recording stopped: {}", failure_to_string(e));
+                self.recorder = None;
+            }
+        }
+        match input {
+            Input::Key(key) => {
+                trace!("Key {} in {:?} mode", key, self.state);
+                self.keymap.push(key, now);
+            }
+            Input::Mouse(mouse) => self.mouse(mouse, now, metrics),
+            Input::Ime(ime) => self.ime(ime, now, metrics),
+            Input::Tick | Input::Resize(..) => {}
+        }
+        self.process_keys(now, metrics);
+    }
+
+    /// executes actions of pending keys that can be resolved at `now`
+    fn process_keys(&mut self, now: Instant, metrics: &dyn Metrics) {
+        while let Some(resolved) = self.keymap.next(self.state, now) {
+            match resolved {
+                Resolved::Action(action) => self.do_action(action, metrics),
+                Resolved::Unmapped(key) => self.unmapped_key(key),
+                Resolved::RecursionLimit => error!("Recursive mapping"),
+            }
+        }
+    }
+
+    pub fn set_state(&mut self, state: State) {
+        trace!("Switched to {:?} mode", state);
+        if state != State::Visual {
+            self.buffers[self.active_buffer].selection = None;
+        }
+        self.state = state;
+    }
+
+    /// Keeps composed text for drawing, committed text is inserted in
+    /// Insert and Command mode as typed, bypassing mappings. In other modes
+    /// it's handled as typed keys. Keys typed before it are resolved first,
+    /// as if their mapping timed out.
+    fn ime(&mut self, ime: Ime, now: Instant, metrics: &dyn Metrics) {
+        let text = match ime {
+            Ime::Preedit(text, cursor) => {
+                self.preedit = match text.is_empty() {
+                    true => None,
+                    false => Some(Preedit { text, cursor }),
+                };
+                return;
+            }
+            Ime::Commit(text) => text,
+        };
+        self.preedit = None;
+        if let Some(deadline) = self.keymap.deadline() {
+            self.process_keys(deadline.max(now), metrics);
+        }
+        trace!("Committed {:?} in {:?} mode", text, self.state);
+        match self.state {
+            State::Insert => {
+                let buff = &mut self.buffers[self.active_buffer];
+                for ch in text.chars() {
+                    buff.insert(ch, None);
+                }
+            }
+            State::Command => self.command_line.push_str(&text),
+            _ => {
+                for ch in text.chars() {
+                    self.keymap.push(Key::from_char(ch), now);
+                }
+            }
+        }
+    }
+
+    /// Moves cursor to clicked char, double click selects word and triple
+    /// click line. Dragging selects text in Visual mode, wheel scrolls view
+    /// and cursor by `mousescroll` display lines per step.
+    fn mouse(&mut self, mouse: Mouse, now: Instant, metrics: &dyn Metrics) {
+        let buff = &mut self.buffers[self.active_buffer];
+        if self.state == State::Command || buff.buffer.is_empty() {
+            return;
+        }
+        let (x, y) = match mouse {
+            Mouse::Press(x, y) | Mouse::Drag(x, y) => (x, y),
+            Mouse::Wheel(steps) => {
+                let lines = steps * self.options.mousescroll as i32;
+                let (top, left) = buff.view_pos.get();
+                let top = buff.layout.get_mut().scroll(metrics, &buff.buffer, top, lines);
+                buff.view_pos.set((top, left));
+                return buff.move_cursor_display(metrics, lines);
+            }
+        };
+        let (row, col) =
+            buff.layout
+                .get_mut()
+                .hit_test(metrics, &buff.buffer, buff.view_pos.get(), (x, y));
+        if let Mouse::Drag(..) = mouse {
+            if self.state != State::Visual {
+                buff.selection = Some(buff.cursor.text_pos);
+                self.set_state(State::Visual);
+            }
+            return self.buffers[self.active_buffer].move_cursor_absolute(row, col);
+        }
+
+        let clicks = match self.last_click {
+            Some((time, pos, clicks)) if pos == (row, col) && now - time <= DOUBLE_CLICK => {
+                clicks % 3 + 1
+            }
+            _ => 1,
+        };
+        self.last_click = Some((now, (row, col), clicks));
+        let (start, end) = match clicks {
+            1 => {
+                self.set_state(State::Normal);
+                return self.buffers[self.active_buffer].move_cursor_absolute(row, col);
+            }
+            2 => word_at(&buff.buffer[row], col),
+            _ => (0, buff.buffer[row].chars().count().saturating_sub(1)),
+        };
+        buff.selection = Some((row, start));
+        buff.move_cursor_absolute(row, end);
+        self.set_state(State::Visual);
+    }
+
+    fn do_action(&mut self, action: Action, metrics: &dyn Metrics) {
+        let buff = &mut self.buffers[self.active_buffer];
+        match action {
+            Action::MoveLeft => buff.move_cursor_relative(0, -1),
+            Action::MoveRight => buff.move_cursor_relative(0, 1),
+            Action::MoveUp => buff.move_cursor_relative(-1, 0),
+            Action::MoveDown => buff.move_cursor_relative(1, 0),
+            Action::MoveDisplayDown => buff.move_cursor_display(metrics, 1),
+            Action::MoveDisplayUp => buff.move_cursor_display(metrics, -1),
+            Action::NormalMode => self.set_state(State::Normal),
+            Action::InsertMode => self.set_state(State::Insert),
+            Action::VisualMode => {
+                buff.selection = Some(buff.cursor.text_pos);
+                self.set_state(State::Visual);
+            }
+            Action::CommandMode => {
+                self.command_line.clear();
+                self.set_state(State::Command);
+            }
+            Action::Backspace => match self.state {
+                State::Command => {
+                    if self.command_line.pop().is_none() {
+                        self.set_state(State::Normal);
+                    }
+                }
+                _ => buff.delete(None, None),
+            },
+            Action::Newline => self.newline(),
+            Action::OpenBelow | Action::OpenAbove => {
+                let row = buff.cursor.text_pos.0;
+                let line = &buff.buffer[row];
+                let (row, indent) = match action {
+                    Action::OpenBelow => {
+                        let (indent, _) = indent::newline(
+                            &self.options,
+                            &buff.buffer_type,
+                            &buff.highlight.borrow(),
+                            &buff.buffer[..row],
+                            line,
+                            "",
+                        );
+                        (row + 1, indent)
+                    }
+                    _ => (row, indent::open_above(&self.options, &buff.buffer_type, line)),
+                };
+                buff.insert_line(row, &indent);
+                self.set_state(State::Insert);
+            }
+            Action::DeleteWord | Action::YankWord => {
+                let (row, col) = buff.cursor.text_pos;
+                let end = word_end(&buff.buffer[row], col);
+                if end > col {
+                    self.register = match action {
+                        Action::DeleteWord => buff.remove(row, col, end),
+                        _ => buff.buffer[row].chars().skip(col).take(end - col).collect(),
+                    };
+                }
+            }
+            Action::Put => {
+                if self.register.is_empty() {
+                    return;
+                }
+                let (row, col) = buff.cursor.text_pos;
+                buff.move_cursor_absolute(row, col + 1);
+                for ch in self.register.chars() {
+                    buff.insert(if ch == '\n' { '\r' } else { ch }, None);
+                }
+                // cursor stays on last inserted char
+                let (row, col) = buff.cursor.text_pos;
+                buff.move_cursor_absolute(row, col.saturating_sub(1));
+            }
+            Action::Tab => {
+                for _ in 0..self.options.tabstop {
+                    buff.insert(' ', None);
+                }
+            }
+            Action::ExecuteCommand => {
+                self.set_state(State::Normal);
+                let line = std::mem::take(&mut self.command_line);
+                if let Err(e) = self.execute(&line) {
+                    error!("{}", failure_to_string(e));
+                }
+            }
+            Action::ZoomIn => self.set_zoom(self.zoom + 1),
+            Action::ZoomOut => self.set_zoom(self.zoom - 1),
+            Action::ZoomReset => self.set_zoom(0),
+            Action::ZoomInBuffer | Action::ZoomOutBuffer => {
+                let size = buff
+                    .font_size
+                    .unwrap_or_else(|| zoomed_size(self.options.font_size, self.zoom));
+                let step = if let Action::ZoomInBuffer = action {
+                    1
+                } else {
+                    -1
+                };
+                buff.font_size = Some(zoomed_size(size, step));
+            }
+            Action::ZoomResetBuffer => buff.font_size = None,
+        }
+    }
+
+    /// returns font size of window with zoom applied
+    pub fn font_size(&self) -> f32 {
+        zoomed_size(self.options.font_size, self.zoom)
+    }
+
+    /// sets zoom of window and saves it, frontend applies it
+    fn set_zoom(&mut self, zoom: i32) {
+        let zoom = zoom.clamp(-MAX_ZOOM, MAX_ZOOM);
+        if zoom == self.zoom {
+            return;
+        }
+        self.zoom = zoom;
+        if let Some(path) = &self.state_path {
+            if let Err(e) = config::save_state(path, &config::SavedState { zoom }) {
+                error!("{}", failure_to_string(e));
+            }
+        }
+    }
+
+    /// handles key that's not bound to any action
+    fn unmapped_key(&mut self, key: Key) {
+        match (self.state, key.printable()) {
+            (State::Insert, Some(ch)) => {
+                trace!("Inserting {:?}", ch);
+                self.dedent(ch);
+                self.buffers[self.active_buffer].insert(ch, None);
+            }
+            (State::Command, Some(ch)) => self.command_line.push(ch),
+            _ => trace!("Unprocessed key {} in {:?} mode", key, self.state),
+        }
+    }
+
+    /// Splits line at cursor, new line is indented by `autoindent` and
+    /// `smartindent`. Cursor between brackets leaves closing one on its own
+    /// line.
+    fn newline(&mut self) {
+        let buff = &mut self.buffers[self.active_buffer];
+        let (row, col) = buff.cursor.text_pos;
+        let line = &buff.buffer[row];
+        let before: String = line.chars().take(col).collect();
+        let after: String = line.chars().skip(col).collect();
+        let (indent, close) = indent::newline(
+            &self.options,
+            &buff.buffer_type,
+            &buff.highlight.borrow(),
+            &buff.buffer[..row],
+            &before,
+            &after,
+        );
+        buff.insert('\r', None);
+        if !self.options.autoindent && !self.options.smartindent {
+            return;
+        }
+        buff.set_indent(row + 1, &indent);
+        if let Some(close) = close {
+            buff.insert('\r', None);
+            buff.set_indent(row + 2, &close);
+            buff.move_cursor_absolute(row + 1, indent.chars().count());
+        }
+    }
+
+    /// With `smartindent`, closing bracket `ch` typed at start of line moves
+    /// the line to indentation of line with the opening one
+    fn dedent(&mut self, ch: char) {
+        let buff = &mut self.buffers[self.active_buffer];
+        let (row, col) = buff.cursor.text_pos;
+        if !self.options.smartindent || !buff.buffer[row].chars().take(col).all(char::is_whitespace) {
+            return;
+        }
+        let indent = indent::rules(&self.options, &buff.buffer_type).and_then(|rules| {
+            indent::matching_indent(&rules, &buff.highlight.borrow(), &buff.buffer[..row], ch)
+        });
+        if let Some(indent) = indent {
+            buff.set_indent(row, &indent);
+        }
+    }
+
+    /// executes command typed in Command mode
+    fn execute(&mut self, line: &str) -> Result<(), failure::Error> {
+        trace!("Executing command {:?}", line);
+        match Command::parse(line)? {
+            Command::SetLocal(args) => {
+                let buff = &mut self.buffers[self.active_buffer];
+                for arg in args {
+                    match arg.split_once('=') {
+                        Some(("filetype", ft)) | Some(("ft", ft)) => buff.set_filetype(ft)?,
+                        // empty value means global font size
+                        Some(("fontsize", "")) => buff.font_size = None,
+                        Some(("fontsize", _)) => {
+                            // value is checked like value of global option
+                            let mut opts = self.options.clone();
+                            opts.set(&arg)?;
+                            buff.font_size = Some(opts.font_size);
+                        }
+                        _ => bail!("Option \"{}\" is not local to buffer", arg),
+                    }
+                }
+            }
+            Command::Set(args) => {
+                for arg in args {
+                    // filetype is local to buffer
+                    match arg.split_once('=') {
+                        Some(("filetype", ft)) | Some(("ft", ft)) => {
+                            self.buffers[self.active_buffer].set_filetype(ft)?
+                        }
+                        _ => self.options.set(&arg)?,
+                    }
+                }
+            }
+            Command::Map {
+                modes,
+                lhs,
+                rhs,
+                noremap,
+            } => {
+                for mode in modes {
+                    self.keymap.map(mode, &lhs, rhs.clone(), noremap);
+                }
+            }
+            Command::Unmap { modes, lhs } => {
+                for mode in modes {
+                    self.keymap.unmap(mode, &lhs)?;
+                }
+            }
+            // theme is applied by frontend
+            Command::Colorscheme(Some(name)) => self.options.theme = name,
+            Command::Colorscheme(None) => info!("{}", self.options.theme),
+            Command::DumpAtlas(path) => {
+                self.atlas_dump = Some(PathBuf::from(path.as_deref().unwrap_or("atlas.png")))
+            }
+            Command::Write(path) => {
+                let path = path.map(PathBuf::from);
+                self.buffers[self.active_buffer].save(path.as_deref())?
+            }
+            Command::Quit => self.quit = true,
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,490 @@
+//! Loading of fonts.
+//!
+//! Font family consists of regular face and optional bold, italic and bold
+//! italic faces, which are found next to the regular one by their file name
+//! (`Hack-Regular.ttf` -> `Hack-Bold.ttf`, `Hack-Italic.ttf`, ...).
+//!
+//! [Fonts] is the configured family followed by fallback families, every
+//! char is drawn with the first one that has a glyph for it. Fonts are given
+//! by path, file name or family name (`DejaVu Sans Mono`), which are looked
+//! up in XDG and fontconfig font directories. DejaVu Sans Mono embedded in
+//! the binary is the primary font when no font is configured, otherwise it's
+//! the last fallback.
+#[allow(unused_imports)]
+use log::{debug, error, info, trace, warn};
+use rusttype::Font;
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use syntect::highlighting::FontStyle;
+
+#[cfg(test)]
+mod tests {
+    use super::{face_paths, family_key, font_files, Face, Fonts, DEFAULT_FONT};
+    use std::path::PathBuf;
+    use syntect::highlighting::FontStyle;
+
+    #[test]
+    fn test_faces() {
+        assert_eq!(
+            face_paths("fonts/Hack-Regular.ttf".as_ref(), Face::BoldItalic),
+            vec![
+                PathBuf::from("fonts/Hack-BoldItalic.ttf"),
+                PathBuf::from("fonts/Hack-BoldOblique.ttf")
+            ]
+        );
+        assert_eq!(
+            face_paths("DejaVuSansMono.ttf".as_ref(), Face::Italic)[1],
+            PathBuf::from("DejaVuSansMono-Oblique.ttf")
+        );
+        assert_eq!(Face::from_style(FontStyle::BOLD | FontStyle::UNDERLINE), Face::Bold);
+        assert_eq!(Face::from_style(FontStyle::BOLD | FontStyle::ITALIC), Face::BoldItalic);
+    }
+
+    #[test]
+    fn test_font_files() {
+        let dir = std::env::temp_dir().join(format!("sietor-fonts-{}", std::process::id()));
+        std::fs::create_dir_all(dir.join("a/truetype")).unwrap();
+        std::fs::create_dir_all(dir.join("b")).unwrap();
+        std::fs::write(dir.join("a/truetype/Symbola.ttf"), "").unwrap();
+        std::fs::write(dir.join("b/Symbola.ttf"), "").unwrap();
+        let files = font_files(&[dir.join("b"), dir.join("a"), dir.join("missing")]);
+        // earlier directory wins
+        assert_eq!(files["symbola.ttf"], dir.join("b/Symbola.ttf"));
+        assert_eq!(files.len(), 1);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_family_key() {
+        assert_eq!(family_key("DejaVu Sans Mono"), "dejavusansmono");
+        assert_eq!(family_key("DejaVuSansMono.ttf"), "dejavusansmono");
+        assert_eq!(family_key("Hack-Regular.ttf"), "hack");
+        assert_eq!(family_key("Hack-Bold.ttf"), "hackbold");
+        assert_eq!(family_key("Regular"), "regular");
+    }
+
+    #[test]
+    fn test_embedded() {
+        let fonts = Fonts::load("", &[]).unwrap();
+        assert!(fonts.primary().glyph('a').id().0 != 0);
+        assert!(Fonts::load("surely-not-installed-font", &[]).is_err());
+
+        // fallback family with bold face, built-in font has only regular one
+        let dir = std::env::temp_dir().join(format!("sietor-embedded-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("Test-Regular.ttf"), DEFAULT_FONT).unwrap();
+        std::fs::write(dir.join("Test-Bold.ttf"), DEFAULT_FONT).unwrap();
+        let fallback = [dir.join("Test-Regular.ttf").to_string_lossy().to_string()];
+        let fonts = Fonts::load("", &fallback).unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(fonts.families.len(), 2);
+        assert!(fonts.families[0].faces[Face::Bold as usize].is_none());
+        assert!(fonts.families[1].faces[Face::Bold as usize].is_some());
+    }
+}
+
+/// number of faces of family, font ids in glyph cache are
+/// `family index * FACES + face`
+const FACES: usize = 4;
+
+/// Face of font family
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Face {
+    Regular = 0,
+    Bold = 1,
+    Italic = 2,
+    BoldItalic = 3,
+}
+
+impl Face {
+    /// returns face used for text highlighted with `style`, underline is
+    /// drawn separately
+    pub fn from_style(style: FontStyle) -> Self {
+        match (style.contains(FontStyle::BOLD), style.contains(FontStyle::ITALIC)) {
+            (false, false) => Face::Regular,
+            (true, false) => Face::Bold,
+            (false, true) => Face::Italic,
+            (true, true) => Face::BoldItalic,
+        }
+    }
+
+    /// suffixes of file names of the face
+    fn suffixes(self) -> &'static [&'static str] {
+        match self {
+            Face::Regular => &["Regular"],
+            Face::Bold => &["Bold"],
+            Face::Italic => &["Italic", "Oblique"],
+            Face::BoldItalic => &["BoldItalic", "BoldOblique"],
+        }
+    }
+}
+
+/// returns possible paths of `face` of the same family as `regular`
+fn face_paths(regular: &Path, face: Face) -> Vec<PathBuf> {
+    let stem = regular.file_stem().and_then(|s| s.to_str()).unwrap_or("");
+    let family = stem
+        .strip_suffix("-Regular")
+        .or_else(|| stem.strip_suffix("Regular"))
+        .unwrap_or(stem);
+    let extension = regular.extension().and_then(|e| e.to_str()).unwrap_or("ttf");
+    face.suffixes()
+        .iter()
+        .map(|suffix| regular.with_file_name(format!("{}-{}.{}", family, suffix, extension)))
+        .collect()
+}
+
+/// loads font from `path` relative to current directory
+pub fn load_font(path: &Path) -> Result<Font<'static>, failure::Error> {
+    trace!("Loading font {:?}", path);
+    let font_path = std::env::current_dir()?.join(path);
+    let data = std::fs::read(&font_path)
+        .map_err(|e| format_err!("Could not read font {:?}: {}", font_path, e))?;
+    match Font::try_from_vec(data) {
+        Some(font) => Ok(font),
+        None => bail!("File {:?} is not a valid font", font_path),
+    }
+}
+
+/// Faces of one font family, missing faces are replaced by regular one
+#[derive(Clone)]
+pub struct FontFamily<'a> {
+    /// faces indexed by [Face]
+    faces: [Option<Font<'a>>; FACES],
+}
+
+impl FontFamily<'static> {
+    /// Loads regular face from `path` and other faces found next to it
+    pub fn load(path: &str) -> Result<Self, failure::Error> {
+        let regular = Path::new(path);
+        let mut faces = [Some(load_font(regular)?), None, None, None];
+        for face in [Face::Bold, Face::Italic, Face::BoldItalic] {
+            faces[face as usize] = face_paths(regular, face)
+                .iter()
+                .filter(|p| p.is_file())
+                .find_map(|p| match load_font(p) {
+                    Ok(font) => Some(font),
+                    Err(e) => {
+                        warn!("{}", e);
+                        None
+                    }
+                });
+            if faces[face as usize].is_none() {
+                debug!("{:?} face of {:?} not found", face, path);
+            }
+        }
+        Ok(FontFamily { faces })
+    }
+}
+
+impl<'a> FontFamily<'a> {
+    pub fn regular(&self) -> &Font<'a> {
+        self.faces[0].as_ref().unwrap()
+    }
+
+    /// Returns id of font used for `face` in glyph cache and the font, which
+    /// is regular one if family doesn't have the face
+    pub fn face(&self, face: Face) -> (usize, &Font<'a>) {
+        match &self.faces[face as usize] {
+            Some(font) => (face as usize, font),
+            None => (Face::Regular as usize, self.regular()),
+        }
+    }
+}
+
+/// built-in font, used when font is not set
+static DEFAULT_FONT: &[u8] = include_bytes!("../assets/fonts/DejaVuSansMono.ttf");
+
+/// extensions of font files
+const EXTENSIONS: &[&str] = &["ttf", "otf", "ttc"];
+
+/// Returns font directories listed in fontconfig config `path` and files it
+/// includes. Only `<dir>` and `<include>` elements are processed.
+fn fontconfig_dirs(
+    path: &Path,
+    home: Option<&Path>,
+    data_home: &Path,
+    depth: usize,
+) -> Vec<PathBuf> {
+    let text = match std::fs::read_to_string(path) {
+        Ok(text) => text,
+        Err(_) => return Vec::new(),
+    };
+    let element =
+        regex::Regex::new(r"<(dir|include)([^>]*)>\s*([^<]*?)\s*</(?:dir|include)>").unwrap();
+    let base = path.parent().unwrap_or_else(|| Path::new("/"));
+    let mut dirs = Vec::new();
+    for cap in element.captures_iter(&text) {
+        let value = &cap[3];
+        let resolved = if cap[2].contains("prefix=\"xdg\"") {
+            data_home.join(value)
+        } else if let Some(rest) = value.strip_prefix("~/") {
+            match home {
+                Some(home) => home.join(rest),
+                None => continue,
+            }
+        } else {
+            base.join(value)
+        };
+        if &cap[1] == "dir" {
+            dirs.push(resolved);
+        } else if depth < 4 {
+            let mut files: Vec<PathBuf> = match std::fs::read_dir(&resolved) {
+                Ok(entries) => entries.flatten().map(|e| e.path()).collect(),
+                Err(_) => vec![resolved],
+            };
+            files.sort();
+            for file in files.iter().filter(|f| f.extension().is_some_and(|e| e == "conf")) {
+                dirs.extend(fontconfig_dirs(file, home, data_home, depth + 1));
+            }
+        }
+    }
+    dirs
+}
+
+/// returns directories where fonts are installed
+pub fn font_dirs() -> Vec<PathBuf> {
+    let mut dirs = Vec::new();
+    let home = std::env::var_os("HOME").map(PathBuf::from);
+    let data_home = match std::env::var_os("XDG_DATA_HOME") {
+        Some(dir) if !dir.is_empty() => Some(PathBuf::from(dir)),
+        _ => home.as_ref().map(|h| h.join(".local/share")),
+    };
+    dirs.extend(data_home.as_ref().map(|d| d.join("fonts")));
+    dirs.extend(home.as_ref().map(|h| h.join(".fonts")));
+    let data_dirs = std::env::var("XDG_DATA_DIRS")
+        .ok()
+        .filter(|d| !d.is_empty())
+        .unwrap_or_else(|| "/usr/local/share:/usr/share".to_string());
+    dirs.extend(data_dirs.split(':').map(|d| Path::new(d).join("fonts")));
+    if let Some(data_home) = &data_home {
+        let config = std::env::var_os("FONTCONFIG_FILE")
+            .map(PathBuf::from)
+            .unwrap_or_else(|| PathBuf::from("/etc/fonts/fonts.conf"));
+        for dir in fontconfig_dirs(&config, home.as_deref(), data_home, 0) {
+            if !dirs.contains(&dir) {
+                dirs.push(dir);
+            }
+        }
+    }
+    if cfg!(target_os = "macos") {
+        dirs.extend(home.as_ref().map(|h| h.join("Library/Fonts")));
+        dirs.push("/Library/Fonts".into());
+        dirs.push("/System/Library/Fonts".into());
+    }
+    if cfg!(target_os = "windows") {
+        let windir = std::env::var_os("WINDIR").unwrap_or_else(|| "C:\\Windows".into());
+        dirs.push(PathBuf::from(windir).join("Fonts"));
+    }
+    dirs
+}
+
+/// Converts family name or file name to key used to compare them, e.g.
+/// `DejaVu Sans Mono` and `DejaVuSansMono-Regular.ttf` are `dejavusansmono`
+fn family_key(name: &str) -> String {
+    let name = name.to_lowercase();
+    let mut name = name.as_str();
+    for ext in EXTENSIONS {
+        name = name.strip_suffix(&format!(".{}", ext)).unwrap_or(name);
+    }
+    let key: String = name.chars().filter(|c| c.is_alphanumeric()).collect();
+    for suffix in ["regular", "book"] {
+        if let Some(family) = key.strip_suffix(suffix) {
+            if !family.is_empty() {
+                return family.to_string();
+            }
+        }
+    }
+    key
+}
+
+/// Returns font files in `dirs` and their subdirectories by lowercase file
+/// name, first one wins if there are more files with the same name
+fn font_files(dirs: &[PathBuf]) -> HashMap<String, PathBuf> {
+    let mut files = HashMap::new();
+    let mut stack: Vec<PathBuf> = dirs.iter().rev().cloned().collect();
+    while let Some(dir) = stack.pop() {
+        let entries = match std::fs::read_dir(&dir) {
+            Ok(entries) => entries,
+            Err(_) => continue,
+        };
+        let mut subdirs = Vec::new();
+        for entry in entries.flatten() {
+            let path = entry.path();
+            if path.is_dir() {
+                subdirs.push(path);
+            } else if let Some(name) = path.file_name().and_then(|n| n.to_str()) {
+                let is_font = path
+                    .extension()
+                    .and_then(|e| e.to_str())
+                    .is_some_and(|e| EXTENSIONS.contains(&e.to_lowercase().as_str()));
+                if is_font {
+                    files.entry(name.to_lowercase()).or_insert(path);
+                }
+            }
+        }
+        subdirs.sort();
+        stack.extend(subdirs.into_iter().rev());
+    }
+    files
+}
+
+/// Primary font family and its fallbacks
+#[derive(Clone)]
+pub struct Fonts<'a> {
+    families: Vec<FontFamily<'a>>,
+}
+
+/// Installed fonts, indexed on first lookup
+struct Installed {
+    /// fonts by lowercase file name
+    files: HashMap<String, PathBuf>,
+    /// fonts by [family_key] of file name
+    families: HashMap<String, PathBuf>,
+}
+
+impl Installed {
+    fn new() -> Self {
+        let dirs = font_dirs();
+        trace!("Indexing fonts in {:?}", dirs);
+        let files = font_files(&dirs);
+        let mut families = HashMap::new();
+        // regular face is preferred, e.g. `Hack-Regular.ttf` over `Hack-Bold.ttf`
+        let mut sorted: Vec<(&String, &PathBuf)> = files.iter().collect();
+        sorted.sort_by_key(|(name, _)| (name.len(), name.to_string()));
+        for (name, path) in sorted {
+            families.entry(family_key(name)).or_insert_with(|| path.clone());
+        }
+        Installed { files, families }
+    }
+
+    /// returns path of font given by file name or family name
+    fn find(&self, name: &str) -> Option<&PathBuf> {
+        self.files
+            .get(&name.to_lowercase())
+            .or_else(|| self.families.get(&family_key(name)))
+    }
+}
+
+/// Finds font given by path (absolute, relative to current directory or to
+/// home with `~/`), file name or family name. Installed fonts are indexed
+/// only when `name` is not a path.
+fn find_font(name: &str, installed: &mut Option<Installed>) -> Option<PathBuf> {
+    let path = match (name.strip_prefix("~/"), std::env::var_os("HOME")) {
+        (Some(rest), Some(home)) => PathBuf::from(home).join(rest),
+        _ => PathBuf::from(name),
+    };
+    if path.is_file() {
+        return Some(path);
+    }
+    installed.get_or_insert_with(Installed::new).find(name).cloned()
+}
+
+impl FontFamily<'static> {
+    /// returns family with only built-in font
+    fn embedded() -> Self {
+        let font = Font::try_from_bytes(DEFAULT_FONT).expect("embedded font is valid");
+        FontFamily {
+            faces: [Some(font), None, None, None],
+        }
+    }
+}
+
+impl Fonts<'static> {
+    /// Loads font family `name` and `fallback` fonts, which can be paths,
+    /// file names or family names of installed fonts. Empty `name` is the
+    /// built-in font. Fallback fonts that can't be found are skipped.
+    pub fn load(name: &str, fallback: &[String]) -> Result<Self, failure::Error> {
+        let mut installed = None;
+        let mut families = Vec::new();
+        if name.is_empty() {
+            families.push(FontFamily::embedded());
+        } else {
+            match find_font(name, &mut installed) {
+                Some(path) => families.push(FontFamily::load(&path.to_string_lossy())?),
+                None => bail!("Font \"{}\" not found", name),
+            }
+        }
+        for name in fallback {
+            let path = match find_font(name, &mut installed) {
+                Some(path) => path,
+                None => {
+                    debug!("Fallback font {:?} not found", name);
+                    continue;
+                }
+            };
+            match FontFamily::load(&path.to_string_lossy()) {
+                Ok(family) => families.push(family),
+                Err(e) => warn!("{}", e),
+            }
+        }
+        if !name.is_empty() {
+            families.push(FontFamily::embedded());
+        }
+        Ok(Fonts { families })
+    }
+
+    /// returns built-in font without fallbacks
+    pub fn embedded() -> Self {
+        Fonts {
+            families: vec![FontFamily::embedded()],
+        }
+    }
+}
+
+/// Fonts shared by all buffers, owned by renderer
+pub struct FontRegistry<'a> {
+    fonts: Fonts<'a>,
+    /// font size in points of buffers that don't set their own
+    size: f32,
+}
+
+impl<'a> FontRegistry<'a> {
+    pub fn new(fonts: Fonts<'a>, size: f32) -> Self {
+        FontRegistry { fonts, size }
+    }
+
+    pub fn fonts(&self) -> &Fonts<'a> {
+        &self.fonts
+    }
+
+    pub fn set_fonts(&mut self, fonts: Fonts<'a>) {
+        self.fonts = fonts;
+    }
+
+    pub fn set_size(&mut self, size: f32) {
+        self.size = size;
+    }
+
+    /// returns font size of buffer with font size `local`
+    pub fn size(&self, local: Option<f32>) -> f32 {
+        local.unwrap_or(self.size)
+    }
+}
+
+impl<'a> Fonts<'a> {
+    /// returns regular face of primary family
+    pub fn primary(&self) -> &Font<'a> {
+        self.families[0].regular()
+    }
+
+    /// returns font with id `id` in glyph cache
+    pub fn font(&self, id: usize) -> &Font<'a> {
+        let family = &self.families[id / FACES];
+        family.faces[id % FACES]
+            .as_ref()
+            .unwrap_or_else(|| family.regular())
+    }
+
+    /// Returns id in glyph cache and font used to draw `c` with `face`, it's
+    /// the first family that has glyph for `c`
+    pub fn glyph_font(&self, c: char, face: Face) -> (usize, &Font<'a>) {
+        for (i, family) in self.families.iter().enumerate() {
+            let (id, font) = family.face(face);
+            if font.glyph(c).id().0 != 0 {
+                return (i * FACES + id, font);
+            }
+        }
+        // not defined glyph of primary font
+        self.families[0].face(face)
+    }
+}
@@ -0,0 +1,123 @@
+//! Headless harness for testing editor behavior.
+//!
+//! Starts an [Editor] with given text and feeds it keys in Vim notation,
+//! e.g. `h.keys("jix<Esc>:setl ft=rust<CR>")`, then asserts on buffer,
+//! cursor, mode and register. Time is simulated: after each [keys](Harness::keys)
+//! call pending keys are flushed as if `timeoutlen` expired. Text is laid
+//! out in terminal cells ([CellMetrics]), so `gj`/`gk` work with `width`
+//! given in columns.
+
+use std::time::{Duration, Instant};
+
+use rusttype::Scale;
+
+use crate::buffer::{BufferOrigin, TextBuffer};
+use crate::editor::{Editor, Ime, Input, Mouse, State};
+use crate::key::parse_keys;
+use crate::layout::{CellMetrics, LayoutParams};
+
+pub struct Harness {
+    pub editor: Editor,
+    /// simulated time of last input
+    now: Instant,
+}
+
+impl Harness {
+    /// editor with a single buffer containing `text`, cursor is at its start
+    pub fn new(text: &str) -> Self {
+        let mut editor = Editor::new();
+        let buff = TextBuffer::new(BufferOrigin::Buffer(text.to_string()), None, None, 1.0);
+        editor.buffers.push(buff);
+        let mut h = Harness {
+            editor,
+            now: Instant::now(),
+        };
+        h.set_width(80);
+        h
+    }
+
+    /// sets width of text area in columns, lines longer than it wrap
+    pub fn set_width(&mut self, width: usize) -> &mut Self {
+        let params = LayoutParams::new(&self.editor.options, Scale::uniform(1.0), width as f32);
+        self.buffer_mut().layout.get_mut().set_params(params);
+        self
+    }
+
+    /// Feeds `keys` in Vim notation, panics if they can't be parsed. Keys
+    /// waiting for longer mapping are resolved after it returns.
+    pub fn keys(&mut self, keys: &str) -> &mut Self {
+        let keys = parse_keys(keys).unwrap_or_else(|e| panic!("{:?}: {}", keys, e));
+        for key in keys {
+            self.editor
+                .apply_at(Input::Key(key), self.now, &CellMetrics);
+        }
+        if let Some(deadline) = self.editor.deadline() {
+            self.now = deadline;
+            self.editor.apply_at(Input::Tick, self.now, &CellMetrics);
+        }
+        self
+    }
+
+    /// feeds mouse input at current time
+    pub fn mouse(&mut self, mouse: Mouse) -> &mut Self {
+        self.editor
+            .apply_at(Input::Mouse(mouse), self.now, &CellMetrics);
+        self
+    }
+
+    /// feeds input method event at current time
+    pub fn ime(&mut self, ime: Ime) -> &mut Self {
+        self.editor.apply_at(Input::Ime(ime), self.now, &CellMetrics);
+        self
+    }
+
+    /// advances simulated time
+    pub fn wait(&mut self, time: Duration) -> &mut Self {
+        self.now += time;
+        self.editor.apply_at(Input::Tick, self.now, &CellMetrics);
+        self
+    }
+
+    pub fn buffer(&self) -> &TextBuffer {
+        &self.editor.buffers[self.editor.active_buffer]
+    }
+
+    fn buffer_mut(&mut self) -> &mut TextBuffer {
+        &mut self.editor.buffers[self.editor.active_buffer]
+    }
+
+    /// lines of active buffer joined by `\n`
+    pub fn text(&self) -> String {
+        self.buffer().buffer.join("\n")
+    }
+
+    /// `(row, col)` of cursor
+    pub fn cursor(&self) -> (usize, usize) {
+        self.buffer().cursor.text_pos
+    }
+
+    pub fn mode(&self) -> State {
+        self.editor.state
+    }
+
+    #[track_caller]
+    pub fn assert_text(&self, text: &str) {
+        assert_eq!(self.text(), text, "buffer text");
+    }
+
+    #[track_caller]
+    pub fn assert_cursor(&self, pos: (usize, usize)) {
+        assert_eq!(self.cursor(), pos, "cursor of {:?}", self.text());
+    }
+
+    #[track_caller]
+    pub fn assert_mode(&self, mode: State) {
+        assert_eq!(self.mode(), mode, "mode");
+    }
+
+    /// checks contents of unnamed register
+    #[track_caller]
+    pub fn assert_register(&self, text: &str) {
+        assert_eq!(self.editor.register, text, "register");
+    }
+}
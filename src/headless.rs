@@ -0,0 +1,402 @@
+//! Offscreen rendering without window or display server.
+//!
+//! OpenGL context is created through EGL on Mesa's surfaceless platform, so it
+//! works on machines without GPU (llvmpipe). Buffers are rendered to a texture
+//! and read back as images, which is used by golden-image tests. Golden images
+//! are in `tests/golden`, run tests with `SIETOR_BLESS=1` to update them.
+use crate::buffer::{BufferOrigin, TextBuffer};
+use crate::editor::Preedit;
+use crate::font::Fonts;
+use crate::options::Options;
+use crate::renderer::Renderer;
+use glium::backend::{Backend, Context};
+use glium::debug::DebugCallbackBehavior;
+use glium::framebuffer::SimpleFrameBuffer;
+use glium::texture::{RawImage2d, Texture2d};
+use glium::{Surface, SwapBuffersError};
+use glutin_egl_sys::egl;
+use image::RgbaImage;
+use libloading::Library;
+#[allow(unused_imports)]
+use log::{debug, error, info, trace, warn};
+use std::ffi::{c_void, CString};
+use std::path::Path;
+use std::rc::Rc;
+use std::time::{Duration, Instant};
+
+// tests are at the top of the module like in other modules
+#[cfg(test)]
+#[allow(clippy::items_after_test_module)]
+mod tests {
+    use super::Headless;
+    use crate::buffer::{BufferOrigin, TextBuffer};
+    use crate::editor::Preedit;
+    use crate::options::Options;
+    use crate::rects::{RectBatch, RectStyle};
+    use crate::renderer::{px_rect, Renderer};
+    use image::RgbaImage;
+    use syntect::highlighting::Color;
+    use std::path::PathBuf;
+
+    /// Returns context for rendering offscreen. Tests fail if it can't be
+    /// created, unless `SIETOR_SKIP_GOLDEN` is set, then `None` is returned.
+    fn headless() -> Option<Headless> {
+        match Headless::new(320, 160) {
+            Ok(h) => Some(h),
+            Err(e) if std::env::var_os("SIETOR_SKIP_GOLDEN").is_some() => {
+                eprintln!("Skipping golden-image test: {}", e);
+                None
+            }
+            Err(e) => panic!(
+                "Can't render offscreen ({}), set SIETOR_SKIP_GOLDEN=1 to skip golden-image tests",
+                e
+            ),
+        }
+    }
+
+    fn buffer(text: &str, cursor: (usize, usize)) -> TextBuffer {
+        TextBuffer::new(BufferOrigin::Buffer(text.to_string()), Some(cursor), None, 1.0)
+    }
+
+    /// Compares `image` with golden image `name`, golden image is written
+    /// only if `SIETOR_BLESS` is set, missing one is an error. Small
+    /// differences are allowed, because drivers antialias differently.
+    fn compare(name: &str, image: &RgbaImage) {
+        let path = PathBuf::from(env!("CARGO_MANIFEST_DIR"))
+            .join("tests/golden")
+            .join(format!("{}.png", name));
+        if std::env::var_os("SIETOR_BLESS").is_some() {
+            std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+            image.save(&path).unwrap();
+            eprintln!("Saved golden image {:?}", path);
+            return;
+        }
+        let golden = match image::open(&path) {
+            Ok(golden) => golden.to_rgba8(),
+            Err(e) => panic!(
+                "Can't open golden image {:?} ({}), create it with SIETOR_BLESS=1",
+                path, e
+            ),
+        };
+        assert_eq!(golden.dimensions(), image.dimensions(), "size of {}", name);
+        let different = golden
+            .pixels()
+            .zip(image.pixels())
+            .filter(|(a, b)| a.0.iter().zip(b.0.iter()).any(|(a, b)| a.max(b) - a.min(b) > 32))
+            .count();
+        if different * 200 > image.width() as usize * image.height() as usize {
+            let actual = path.with_extension("actual.png");
+            image.save(&actual).unwrap();
+            panic!("{} differs from {:?} in {} pixels, see {:?}", name, path, different, actual);
+        }
+    }
+
+    #[test]
+    fn test_golden_layout() {
+        let h = match headless() {
+            Some(h) => h,
+            None => return,
+        };
+        let mut rend = Renderer::new(h.facade(), 1.0, || {}).unwrap();
+        let opts = Options {
+            showbreak: "> ".to_string(),
+            ..Options::default()
+        };
+        let buff = buffer("short\nlong line that is wrapped at word boundaries\nend", (2, 3));
+        let image = h.render(&mut rend, &opts, &buff, None).unwrap();
+        compare("layout", &image);
+    }
+
+    #[test]
+    fn test_golden_cursor_and_selection() {
+        let h = match headless() {
+            Some(h) => h,
+            None => return,
+        };
+        let mut rend = Renderer::new(h.facade(), 1.0, || {}).unwrap();
+        let mut buff = buffer("first line\nsecond line\nthird", (1, 4));
+        buff.selection = Some((0, 6));
+        let image = h.render(&mut rend, &Options::default(), &buff, None).unwrap();
+        compare("cursor", &image);
+    }
+
+    #[test]
+    fn test_golden_highlight() {
+        let h = match headless() {
+            Some(h) => h,
+            None => return,
+        };
+        let mut rend = Renderer::new(h.facade(), 1.0, || {}).unwrap();
+        let mut buff = buffer("// comment\nfn main() {\n    let s = \"str\";\n}", (0, 0));
+        buff.set_filetype("rust").unwrap();
+        let image = h
+            .render_highlighted(&mut rend, &Options::default(), &buff)
+            .unwrap();
+        assert!(buff.highlight.borrow().line(3).is_some());
+        compare("highlight", &image);
+    }
+
+    #[test]
+    fn test_golden_preedit() {
+        let h = match headless() {
+            Some(h) => h,
+            None => return,
+        };
+        let mut rend = Renderer::new(h.facade(), 1.0, || {}).unwrap();
+        let buff = buffer("first line\nsecond line", (0, 6));
+        let preedit = Preedit {
+            text: "nihon".to_string(),
+            cursor: Some(3),
+        };
+        let image = h
+            .render(&mut rend, &Options::default(), &buff, Some(&preedit))
+            .unwrap();
+        compare("preedit", &image);
+    }
+
+    #[test]
+    fn test_golden_rects() {
+        let h = match headless() {
+            Some(h) => h,
+            None => return,
+        };
+        let color = |r, g, b| Color { r, g, b, a: 0xff }.into();
+        let mut rects = RectBatch::new(h.facade()).unwrap();
+        let image = h
+            .draw(|target| {
+                rects.push(&px_rect(10.0, 10.0, 60.0, 40.0), color(0xff, 0xff, 0xff));
+                let style = RectStyle {
+                    radius: 20.0,
+                    border: 4.0,
+                    border_color: color(0xff, 0, 0),
+                };
+                rects.push_styled(&px_rect(100.0, 20.0, 160.0, 120.0), color(0, 0, 0xff), style);
+                rects.draw(h.facade(), target);
+            })
+            .unwrap();
+        let rgb = |x, y| {
+            let p = image.get_pixel(x, y).0;
+            [p[0], p[1], p[2]]
+        };
+        assert_eq!(rgb(10, 10), [0xff, 0xff, 0xff]);
+        // corner outside of radius isn't drawn
+        assert_eq!(rgb(101, 21), [0, 0, 0]);
+        assert_eq!(rgb(180, 22), [0xff, 0, 0]);
+        assert_eq!(rgb(180, 80), [0, 0, 0xff]);
+        compare("rects", &image);
+    }
+}
+
+/// `EGL_PLATFORM_SURFACELESS_MESA` from `EGL_MESA_platform_surfaceless`
+const PLATFORM_SURFACELESS_MESA: egl::types::EGLenum = 0x31DD;
+
+/// OpenGL context without any surface, it draws only to framebuffer objects
+struct EglContext {
+    egl: egl::Egl,
+    display: egl::types::EGLDisplay,
+    context: egl::types::EGLContext,
+    size: (u32, u32),
+    /// libEGL has to stay loaded while context exists
+    _lib: Library,
+}
+
+impl EglContext {
+    fn new(size: (u32, u32)) -> Result<Self, failure::Error> {
+        unsafe {
+            let lib = Library::new("libEGL.so.1")
+                .map_err(|e| format_err!("Could not load libEGL: {}", e))?;
+            let egl = egl::Egl::load_with(|name| {
+                let name = CString::new(name).unwrap();
+                match lib.get::<*const c_void>(name.as_bytes_with_nul()) {
+                    Ok(sym) => *sym,
+                    Err(_) => std::ptr::null(),
+                }
+            });
+
+            let display = egl.GetPlatformDisplay(
+                PLATFORM_SURFACELESS_MESA,
+                std::ptr::null_mut(),
+                std::ptr::null(),
+            );
+            if display == egl::NO_DISPLAY {
+                bail!("Surfaceless EGL platform is not available");
+            }
+            let (mut major, mut minor) = (0, 0);
+            if egl.Initialize(display, &mut major, &mut minor) == 0 {
+                bail!("Could not initialize EGL");
+            }
+            trace!("Initialized EGL {}.{}", major, minor);
+            if egl.BindAPI(egl::OPENGL_API) == 0 {
+                bail!("EGL doesn't support OpenGL");
+            }
+
+            // default surface type is window, surfaceless platform has only
+            // pbuffer configs
+            let attribs = [
+                egl::SURFACE_TYPE as i32,
+                egl::PBUFFER_BIT as i32,
+                egl::RENDERABLE_TYPE as i32,
+                egl::OPENGL_BIT as i32,
+                egl::NONE as i32,
+            ];
+            let mut config = std::ptr::null();
+            let mut count = 0;
+            if egl.ChooseConfig(display, attribs.as_ptr(), &mut config, 1, &mut count) == 0
+                || count == 0
+            {
+                bail!("No EGL config supports OpenGL");
+            }
+            let attribs = [
+                egl::CONTEXT_MAJOR_VERSION as i32,
+                3,
+                egl::CONTEXT_MINOR_VERSION as i32,
+                3,
+                egl::CONTEXT_OPENGL_PROFILE_MASK as i32,
+                egl::CONTEXT_OPENGL_CORE_PROFILE_BIT as i32,
+                egl::NONE as i32,
+            ];
+            let context = egl.CreateContext(display, config, egl::NO_CONTEXT, attribs.as_ptr());
+            if context == egl::NO_CONTEXT {
+                bail!("Could not create OpenGL 3.3 context");
+            }
+            Ok(EglContext {
+                egl,
+                display,
+                context,
+                size,
+                _lib: lib,
+            })
+        }
+    }
+}
+
+unsafe impl Backend for EglContext {
+    fn swap_buffers(&self) -> Result<(), SwapBuffersError> {
+        // nothing is presented
+        Ok(())
+    }
+
+    unsafe fn get_proc_address(&self, symbol: &str) -> *const c_void {
+        let symbol = CString::new(symbol).unwrap();
+        self.egl.GetProcAddress(symbol.as_ptr()) as *const c_void
+    }
+
+    fn get_framebuffer_dimensions(&self) -> (u32, u32) {
+        self.size
+    }
+
+    fn is_current(&self) -> bool {
+        unsafe { self.egl.GetCurrentContext() == self.context }
+    }
+
+    unsafe fn make_current(&self) {
+        self.egl
+            .MakeCurrent(self.display, egl::NO_SURFACE, egl::NO_SURFACE, self.context);
+    }
+}
+
+impl Drop for EglContext {
+    fn drop(&mut self) {
+        unsafe {
+            if self.is_current() {
+                self.egl
+                    .MakeCurrent(self.display, egl::NO_SURFACE, egl::NO_SURFACE, egl::NO_CONTEXT);
+            }
+            self.egl.DestroyContext(self.display, self.context);
+        }
+    }
+}
+
+/// OpenGL context rendering buffers to images
+pub struct Headless {
+    context: Rc<Context>,
+}
+
+impl Headless {
+    /// creates context rendering images of size `width` × `height`
+    pub fn new(width: u32, height: u32) -> Result<Self, failure::Error> {
+        let backend = EglContext::new((width, height))?;
+        let context = unsafe { Context::new(backend, false, DebugCallbackBehavior::Ignore) }
+            .map_err(|e| format_err!("OpenGL context is not supported: {:?}", e))?;
+        Ok(Headless { context })
+    }
+
+    /// returns facade for creating [Renderer]
+    pub fn facade(&self) -> &Rc<Context> {
+        &self.context
+    }
+
+    /// Draws `buff` like window of context size with scale factor 1 would,
+    /// with `preedit` of input method at cursor
+    pub fn render(
+        &self,
+        rend: &mut Renderer,
+        opts: &Options,
+        buff: &TextBuffer,
+        preedit: Option<&Preedit>,
+    ) -> Result<RgbaImage, failure::Error> {
+        self.draw(|target| {
+            rend.render(0.0, 0.0, 1.0, 1.0, opts, &self.context, target, buff, preedit)
+        })
+    }
+
+    /// Returns image drawn by `draw` to texture of context size, which is
+    /// black before drawing
+    pub fn draw<F>(&self, draw: F) -> Result<RgbaImage, failure::Error>
+    where
+        F: FnOnce(&mut SimpleFrameBuffer),
+    {
+        let (width, height) = self.context.get_framebuffer_dimensions();
+        let texture = Texture2d::empty(&self.context, width, height)?;
+        let mut target = SimpleFrameBuffer::new(&self.context, &texture)?;
+        target.clear_color(0.0, 0.0, 0.0, 1.0);
+        draw(&mut target);
+
+        let raw: RawImage2d<u8> = texture.read();
+        let image = RgbaImage::from_raw(raw.width, raw.height, raw.data.into_owned())
+            .ok_or_else(|| format_err!("Invalid size of rendered image"))?;
+        // OpenGL rows go from bottom to top
+        Ok(image::imageops::flip_vertical(&image))
+    }
+
+    /// Like [render](Headless::render), but waits (at most 10 s) until
+    /// visible lines are highlighted
+    pub fn render_highlighted(
+        &self,
+        rend: &mut Renderer,
+        opts: &Options,
+        buff: &TextBuffer,
+    ) -> Result<RgbaImage, failure::Error> {
+        // first frame requests highlighting of visible lines
+        self.render(rend, opts, buff, None)?;
+        let deadline = Instant::now() + Duration::from_secs(10);
+        while buff.highlight.borrow().is_pending() && Instant::now() < deadline {
+            for done in rend.highlight_results() {
+                buff.highlight.borrow_mut().apply(done);
+            }
+            std::thread::sleep(Duration::from_millis(10));
+        }
+        self.render(rend, opts, buff, None)
+    }
+}
+
+/// Renders file `file` (or empty buffer) in window of size set by `opts` to
+/// PNG image `out`, used by `--screenshot`
+pub fn screenshot(out: &Path, file: Option<&Path>, opts: &Options) -> Result<(), failure::Error> {
+    let (width, height) = opts.window_size;
+    let headless = Headless::new(width, height)?;
+    let mut rend = Renderer::new(headless.facade(), 1.0, || {})?;
+    rend.set_theme(&opts.theme)?;
+    rend.set_fonts(Fonts::load(&opts.font, &opts.fallback)?);
+    rend.set_font_size(opts.font_size);
+    let buff = match file {
+        Some(path) => TextBuffer::open(path, 1.0)?,
+        None => TextBuffer::new(BufferOrigin::Buffer(String::new()), None, None, 1.0),
+    };
+    let image = headless.render_highlighted(&mut rend, opts, &buff)?;
+    image
+        .save(out)
+        .map_err(|e| format_err!("Could not save {:?}: {}", out, e))?;
+    info!("Saved screenshot to {:?}", out);
+    Ok(())
+}
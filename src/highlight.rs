@@ -0,0 +1,394 @@
+//! Incremental syntax highlighting on a background thread.
+//!
+//! Parsing of a line depends on state left by previous line, so [Highlight]
+//! keeps syntect state at the end of every parsed line together with styles
+//! of its chars. Edits invalidate lines from the first changed one and only
+//! lines up to the last visible row are parsed again.
+//!
+//! Parsing is done by [Worker] thread. Every edit increases version of
+//! [Highlight] and results computed for older version are discarded. Until
+//! results arrive, lines are drawn without highlighting.
+//!
+//! Besides styles, every char is marked as code or as part of string or
+//! comment, which is used by [smartindent](crate::indent).
+use crate::buffer::BufferType;
+use crate::syntax;
+#[allow(unused_imports)]
+use log::{debug, error, info, trace, warn};
+use once_cell::sync::Lazy;
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc::{channel, Receiver, Sender, TryIter};
+use std::sync::Arc;
+use std::thread;
+use syntect::easy::ScopeRegionIterator;
+use syntect::highlighting::{HighlightIterator, HighlightState, Highlighter, Style, Theme};
+use syntect::parsing::{ParseState, Scope, ScopeStack};
+
+#[cfg(test)]
+mod tests {
+    use super::{Highlight, Worker};
+    use crate::buffer::BufferType;
+    use std::sync::Arc;
+    use std::time::Duration;
+    use syntect::highlighting::ThemeSet;
+
+    /// waits for results of requested job and applies them
+    fn wait(hl: &mut Highlight, worker: &Worker) {
+        let done = worker.results.recv_timeout(Duration::from_secs(10)).unwrap();
+        hl.apply(done);
+    }
+
+    #[test]
+    fn test_incremental() {
+        let theme = Arc::new(ThemeSet::load_defaults().themes["base16-ocean.dark"].clone());
+        let worker = Worker::start(|| {});
+        let rust = BufferType::Lang("Rust".to_string());
+        let mut buff: Vec<String> = vec!["/* a".into(), "b */".into(), "fn x() {}".into()];
+        let mut hl = Highlight::new();
+
+        hl.request(&rust, &theme, &buff, 1, &worker);
+        assert_eq!(hl.styles.len(), 0);
+        wait(&mut hl, &worker);
+        assert_eq!(hl.styles.len(), 2);
+        let comment = hl.line(0).unwrap()[0];
+        assert_eq!(hl.line(1).unwrap()[0], comment);
+        assert!(!hl.is_code(1, 0));
+        assert!(hl.line(2).is_none());
+
+        // results of old version are discarded
+        hl.request(&rust, &theme, &buff, 2, &worker);
+        buff[0].push_str(" */");
+        hl.invalidate_from(0);
+        wait(&mut hl, &worker);
+        assert_eq!(hl.styles.len(), 0);
+
+        // closing the comment on first line changes style of second one
+        hl.request(&rust, &theme, &buff, 2, &worker);
+        wait(&mut hl, &worker);
+        assert_eq!(hl.styles.len(), 3);
+        assert_ne!(hl.line(1).unwrap()[0], comment);
+        assert!(hl.is_code(1, 0));
+        // lines that aren't highlighted are code
+        assert!(hl.is_code(5, 0));
+
+        // plain text isn't highlighted
+        hl.request(&BufferType::Clear, &theme, &buff, 2, &worker);
+        assert_eq!(hl.styles.len(), 0);
+    }
+}
+
+/// scopes of strings and comments, chars in them aren't code
+static NOT_CODE: Lazy<[Scope; 2]> =
+    Lazy::new(|| [Scope::new("string").unwrap(), Scope::new("comment").unwrap()]);
+
+/// returns false if innermost scopes of `stack` are in string or comment
+fn is_code(stack: &ScopeStack) -> bool {
+    !stack
+        .as_slice()
+        .iter()
+        .any(|scope| NOT_CODE.iter().any(|n| n.is_prefix_of(*scope)))
+}
+
+/// state of parser and highlighter at the end of line
+#[derive(Clone)]
+struct LineState {
+    parse: ParseState,
+    highlight: HighlightState,
+}
+
+/// Lines to be highlighted by [Worker]
+struct Job {
+    /// id of [Highlight] that requested the job
+    id: usize,
+    version: u64,
+    /// name of syntax
+    lang: String,
+    theme: Arc<Theme>,
+    /// row of first line, lines before it didn't change since last job
+    start: usize,
+    lines: Vec<String>,
+}
+
+/// Highlighted lines sent back by [Worker]
+pub struct Done {
+    id: usize,
+    version: u64,
+    start: usize,
+    styles: Vec<Vec<Style>>,
+    /// whether chars are code, see [Highlight::is_code]
+    code: Vec<Vec<bool>>,
+}
+
+impl Done {
+    /// returns id of [Highlight] that requested the lines
+    pub fn id(&self) -> usize {
+        self.id
+    }
+}
+
+/// Parser states of one buffer kept by worker thread, they can't be sent
+/// between threads
+#[derive(Default)]
+struct States {
+    lang: String,
+    /// `lines[i]` is state at the end of line `i`
+    lines: Vec<LineState>,
+}
+
+impl States {
+    fn run(&mut self, job: Job) -> Done {
+        let ps = syntax::syntax_set();
+        let highlighter = Highlighter::new(&job.theme);
+        if job.start == 0 || job.lang != self.lang {
+            self.lang = job.lang.clone();
+            self.lines.clear();
+        }
+        self.lines.truncate(job.start);
+        if self.lines.len() < job.start {
+            // states were dropped by newer job, buffer has to request lines
+            // from here again
+            return Done {
+                id: job.id,
+                version: job.version,
+                start: self.lines.len(),
+                styles: Vec::new(),
+                code: Vec::new(),
+            };
+        }
+
+        let mut state = match self.lines.last() {
+            Some(state) => state.clone(),
+            None => {
+                let syntax = ps
+                    .find_syntax_by_name(&job.lang)
+                    .unwrap_or_else(|| ps.find_syntax_plain_text());
+                LineState {
+                    parse: ParseState::new(syntax),
+                    highlight: HighlightState::new(&highlighter, ScopeStack::new()),
+                }
+            }
+        };
+
+        let mut styles = Vec::with_capacity(job.lines.len());
+        let mut code = Vec::with_capacity(job.lines.len());
+        for (i, line) in job.lines.iter().enumerate() {
+            // syntaxes are loaded for lines with newlines
+            let line = format!("{}\n", line);
+            let ops = match state.parse.parse_line(&line, ps) {
+                Ok(ops) => ops,
+                Err(e) => {
+                    error!("Could not parse line {}: {}", job.start + i, e);
+                    Vec::new()
+                }
+            };
+            let mut stack = state.highlight.path.clone();
+            let mut line_code = Vec::new();
+            for (text, op) in ScopeRegionIterator::new(&ops, &line) {
+                if let Err(e) = stack.apply(op) {
+                    error!("Invalid scopes of line {}: {:?}", job.start + i, e);
+                }
+                line_code.extend(text.chars().map(|_| is_code(&stack)));
+            }
+            code.push(line_code);
+            let mut line_styles = Vec::new();
+            for (style, text) in
+                HighlightIterator::new(&mut state.highlight, &ops, &line, &highlighter)
+            {
+                for _ in text.chars() {
+                    line_styles.push(style);
+                }
+            }
+            styles.push(line_styles);
+            self.lines.push(state.clone());
+        }
+        Done {
+            id: job.id,
+            version: job.version,
+            start: job.start,
+            styles,
+            code,
+        }
+    }
+}
+
+/// Background thread highlighting lines of all buffers
+pub struct Worker {
+    jobs: Sender<Job>,
+    results: Receiver<Done>,
+}
+
+impl Worker {
+    /// Starts highlighting thread, `notify` is called from that thread
+    /// whenever results are ready
+    pub fn start<F: Fn() + Send + 'static>(notify: F) -> Self {
+        let (jobs, job_rx) = channel::<Job>();
+        let (done_tx, results) = channel();
+        thread::spawn(move || {
+            let mut buffers: HashMap<usize, States> = HashMap::new();
+            // thread ends when Worker is dropped
+            while let Ok(job) = job_rx.recv() {
+                // only newest job of every buffer is worth doing
+                let mut newest: HashMap<usize, Job> = HashMap::new();
+                for job in std::iter::once(job).chain(job_rx.try_iter()) {
+                    newest.insert(job.id, job);
+                }
+                for (id, job) in newest {
+                    trace!("Highlighting {} lines from {}", job.lines.len(), job.start);
+                    let done = buffers.entry(id).or_default().run(job);
+                    if done_tx.send(done).is_err() {
+                        return;
+                    }
+                    notify();
+                }
+            }
+        });
+        Worker { jobs, results }
+    }
+
+    /// returns results received since last call
+    pub fn results(&self) -> TryIter<'_, Done> {
+        self.results.try_iter()
+    }
+}
+
+/// source of unique ids of [Highlight]s
+static NEXT_ID: AtomicUsize = AtomicUsize::new(0);
+
+/// Cached highlighting of one buffer
+pub struct Highlight {
+    id: usize,
+    /// increased by every change of cached lines
+    version: u64,
+    /// language of cached lines
+    lang: BufferType,
+    /// style of every char of parsed lines
+    styles: Vec<Vec<Style>>,
+    /// whether chars of parsed lines are code
+    code: Vec<Vec<bool>>,
+    /// version and last row of job sent to worker
+    pending: Option<(u64, usize)>,
+}
+
+impl Highlight {
+    pub fn new() -> Self {
+        Highlight {
+            id: NEXT_ID.fetch_add(1, Ordering::Relaxed),
+            version: 0,
+            lang: BufferType::Clear,
+            styles: Vec::new(),
+            code: Vec::new(),
+            pending: None,
+        }
+    }
+
+    pub fn id(&self) -> usize {
+        self.id
+    }
+
+    /// drops cached lines starting at `row`, has to be called when line
+    /// `row` changes
+    pub fn invalidate_from(&mut self, row: usize) {
+        self.styles.truncate(row);
+        self.code.truncate(row);
+        self.version += 1;
+        self.pending = None;
+    }
+
+    /// drops whole cache, has to be called when theme changes
+    pub fn clear(&mut self) {
+        self.invalidate_from(0);
+    }
+
+    /// returns true if requested lines weren't highlighted yet
+    pub fn is_pending(&self) -> bool {
+        self.pending.is_some()
+    }
+
+    /// returns styles of chars of `row`, `None` if it isn't highlighted
+    pub fn line(&self, row: usize) -> Option<&[Style]> {
+        self.styles.get(row).map(|s| s.as_slice())
+    }
+
+    /// Returns false if char `col` of `row` is in string or comment. Chars
+    /// of lines that aren't highlighted are code.
+    pub fn is_code(&self, row: usize, col: usize) -> bool {
+        self.code
+            .get(row)
+            .and_then(|line| line.get(col))
+            .copied()
+            .unwrap_or(true)
+    }
+
+    /// Requests highlighting of lines of `buff` up to `last_row` (inclusive)
+    /// that aren't cached yet
+    pub fn request(
+        &mut self,
+        lang: &BufferType,
+        theme: &Arc<Theme>,
+        buff: &[String],
+        last_row: usize,
+        worker: &Worker,
+    ) {
+        if *lang != self.lang {
+            self.lang = lang.clone();
+            self.clear();
+        }
+        let name = match &self.lang {
+            BufferType::Lang(name) => name,
+            BufferType::Clear => return,
+        };
+        let start = self.styles.len();
+        let last_row = last_row.min(buff.len().saturating_sub(1));
+        if start >= buff.len() || start > last_row {
+            return;
+        }
+        if let Some((version, pending_row)) = self.pending {
+            if version == self.version && pending_row >= last_row {
+                return;
+            }
+        }
+
+        let job = Job {
+            id: self.id,
+            version: self.version,
+            lang: name.clone(),
+            theme: theme.clone(),
+            start,
+            lines: buff[start..=last_row].to_vec(),
+        };
+        if worker.jobs.send(job).is_err() {
+            error!("Highlighting thread is not running");
+            return;
+        }
+        self.pending = Some((self.version, last_row));
+    }
+
+    /// Stores highlighted lines, returns false if they were requested by
+    /// other [Highlight] or for older version of buffer
+    pub fn apply(&mut self, done: Done) -> bool {
+        if done.id != self.id || done.version != self.version || done.start > self.styles.len() {
+            return false;
+        }
+        if done.styles.is_empty() {
+            // worker doesn't have states of lines before `done.start`
+            self.styles.truncate(done.start);
+            self.code.truncate(done.start);
+            self.pending = None;
+            return true;
+        }
+        let end = done.start + done.styles.len();
+        if let Some((_, pending_row)) = self.pending {
+            if end > pending_row {
+                self.pending = None;
+            }
+        }
+        if end > self.styles.len() {
+            self.styles.truncate(done.start);
+            self.styles.extend(done.styles);
+            self.code.truncate(done.start);
+            self.code.extend(done.code);
+        }
+        true
+    }
+}
@@ -0,0 +1,349 @@
+//! Indentation of new lines.
+//!
+//! With `autoindent` a new line gets indentation of the line it's split
+//! from. `smartindent` also follows [Rules] of buffer language: a line
+//! ending with one of `open` chars is followed by line indented one level
+//! more and a line starting with one of `close` brackets gets indentation of
+//! the line with the matching opening bracket. Brackets in strings and
+//! comments are ignored once the lines are highlighted. One level is a tab
+//! if the line is indented by tabs, `tabstop` spaces otherwise.
+//!
+//! Rules are configured per language in `[indent.<filetype>]` sections of
+//! [config](crate::config), [default_rules] are used for languages without
+//! them.
+use crate::buffer::BufferType;
+use crate::highlight::Highlight;
+use crate::options::Options;
+#[allow(unused_imports)]
+use log::{debug, error, info, trace, warn};
+
+#[cfg(test)]
+mod tests {
+    use super::{default_rules, matching_indent, newline, open_above, Rules};
+    use crate::buffer::BufferType;
+    use crate::highlight::{Highlight, Worker};
+    use crate::options::Options;
+    use std::sync::Arc;
+    use std::time::{Duration, Instant};
+    use syntect::highlighting::ThemeSet;
+
+    fn lines(text: &str) -> Vec<String> {
+        text.lines().map(|l| l.to_string()).collect()
+    }
+
+    #[test]
+    fn test_newline() {
+        let rust = BufferType::Lang("Rust".to_string());
+        let python = BufferType::Lang("Python".to_string());
+        let hl = Highlight::new();
+        let opts = Options::default();
+        let newline = |opts: &Options, lang: &BufferType, above: &[String], before, after| {
+            newline(opts, lang, &hl, above, before, after)
+        };
+        assert_eq!(
+            newline(&opts, &rust, &[], "  let a = 1;", ""),
+            ("  ".to_string(), None)
+        );
+        assert_eq!(
+            newline(&opts, &rust, &[], "  fn a() {", ""),
+            ("      ".to_string(), None)
+        );
+        assert_eq!(
+            newline(&opts, &rust, &[], "  fn a() {", "}"),
+            ("      ".to_string(), Some("  ".to_string()))
+        );
+        assert_eq!(
+            newline(&opts, &rust, &[], "\tif a {", ""),
+            ("\t\t".to_string(), None)
+        );
+        // `:` opens block only in Python
+        assert_eq!(newline(&opts, &rust, &[], "a:", ""), (String::new(), None));
+        assert_eq!(
+            newline(&opts, &python, &[], "if a:", ""),
+            ("    ".to_string(), None)
+        );
+        // languages without rules and plain text are only autoindented
+        let markdown = BufferType::Lang("Markdown".to_string());
+        assert_eq!(
+            newline(&opts, &markdown, &[], " a {", ""),
+            (" ".to_string(), None)
+        );
+        assert_eq!(
+            newline(&opts, &BufferType::Clear, &[], " a {", ""),
+            (" ".to_string(), None)
+        );
+
+        // closing bracket after cursor goes to indentation of opening one
+        let above = lines("f(\n    a,");
+        assert_eq!(
+            newline(&opts, &rust, &above, "    b", ")"),
+            (String::new(), None)
+        );
+
+        // configured rules replace default ones
+        let mut configured = Options::default();
+        configured.indent.insert(
+            "Markdown".to_string(),
+            Rules {
+                open: "-".to_string(),
+                close: String::new(),
+            },
+        );
+        assert_eq!(
+            newline(&configured, &markdown, &[], "a -", ""),
+            ("    ".to_string(), None)
+        );
+
+        let opts = Options {
+            smartindent: false,
+            ..Options::default()
+        };
+        assert_eq!(
+            newline(&opts, &rust, &[], "  a {", "}"),
+            ("  ".to_string(), None)
+        );
+        let opts = Options {
+            autoindent: false,
+            smartindent: false,
+            ..Options::default()
+        };
+        assert_eq!(
+            newline(&opts, &rust, &[], "  a {", "}"),
+            (String::new(), None)
+        );
+    }
+
+    #[test]
+    fn test_matching_indent() {
+        let rules = default_rules("Rust").unwrap();
+        let hl = Highlight::new();
+        let above = lines("fn a() {\n    if b {\n        c();\n    }");
+        assert_eq!(
+            matching_indent(&rules, &hl, &above, '}'),
+            Some(String::new())
+        );
+        assert_eq!(
+            matching_indent(&rules, &hl, &above[..3], '}'),
+            Some("    ".to_string())
+        );
+        assert_eq!(matching_indent(&rules, &hl, &above, ')'), None);
+        let opts = Options::default();
+        let rust = BufferType::Lang("Rust".to_string());
+        assert_eq!(open_above(&opts, &rust, "    }"), "        ");
+        assert_eq!(open_above(&opts, &rust, "  a"), "  ");
+    }
+
+    #[test]
+    fn test_strings_and_comments() {
+        let theme = Arc::new(ThemeSet::load_defaults().themes["base16-ocean.dark"].clone());
+        let worker = Worker::start(|| {});
+        let rust = BufferType::Lang("Rust".to_string());
+        let above = lines("fn a() {\n    if b {\n        let s = \"{\";\n        // (\n    }");
+        let mut hl = Highlight::new();
+        hl.request(&rust, &theme, &above, above.len() - 1, &worker);
+        let deadline = Instant::now() + Duration::from_secs(10);
+        while hl.is_pending() && Instant::now() < deadline {
+            for done in worker.results() {
+                hl.apply(done);
+            }
+            std::thread::sleep(Duration::from_millis(10));
+        }
+        let rules = default_rules("Rust").unwrap();
+        assert_eq!(
+            matching_indent(&rules, &hl, &above[..4], '}'),
+            Some("    ".to_string())
+        );
+        assert_eq!(matching_indent(&rules, &hl, &above, ')'), None);
+        let opts = Options::default();
+        assert_eq!(
+            newline(&opts, &rust, &hl, &above[..3], "        // (", ""),
+            ("        ".to_string(), None)
+        );
+    }
+}
+
+/// Indentation rules of a language
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Rules {
+    /// characters that indent next line when they end a line
+    pub open: String,
+    /// closing brackets, dedented to indentation of matching opening ones
+    pub close: String,
+}
+
+/// brackets that can be in [Rules::close] with their opening brackets
+const PAIRS: [(char, char); 4] = [('(', ')'), ('[', ']'), ('{', '}'), ('<', '>')];
+
+/// returns opening bracket of closing bracket `close`
+pub fn opening(close: char) -> Option<char> {
+    PAIRS.iter().find(|(_, c)| *c == close).map(|(open, _)| *open)
+}
+
+/// Built-in rules by syntax names, languages with indented blocks after `:`
+/// and languages with blocks in brackets. Other languages have no rules.
+const DEFAULT_RULES: &[(&[&str], &str, &str)] = &[
+    (&["Python", "YAML"], "{([:", "})]"),
+    (
+        &[
+            "Rust",
+            "C",
+            "C++",
+            "C#",
+            "Objective-C",
+            "Objective-C++",
+            "D",
+            "Go",
+            "Java",
+            "JavaScript",
+            "JSON",
+            "CSS",
+            "PHP",
+            "Scala",
+            "Groovy",
+            "Perl",
+            "Lua",
+            "R",
+            "ActionScript",
+            "Bourne Again Shell (bash)",
+        ],
+        "{([",
+        "})]",
+    ),
+];
+
+/// returns built-in rules of language with syntax `name`
+pub fn default_rules(name: &str) -> Option<Rules> {
+    DEFAULT_RULES
+        .iter()
+        .find(|(names, _, _)| names.contains(&name))
+        .map(|(_, open, close)| Rules {
+            open: open.to_string(),
+            close: close.to_string(),
+        })
+}
+
+/// returns rules of buffer language, configured or built-in
+pub fn rules(opts: &Options, buffer_type: &BufferType) -> Option<Rules> {
+    match buffer_type {
+        BufferType::Lang(name) => opts
+            .indent
+            .get(name)
+            .cloned()
+            .or_else(|| default_rules(name)),
+        BufferType::Clear => None,
+    }
+}
+
+/// returns leading whitespace of `line`
+pub fn indentation(line: &str) -> &str {
+    &line[..line.len() - line.trim_start().len()]
+}
+
+/// returns one level of indentation for lines indented like `indent`
+fn level(opts: &Options, indent: &str) -> String {
+    if indent.starts_with('\t') {
+        "\t".to_string()
+    } else {
+        " ".repeat(opts.tabstop)
+    }
+}
+
+/// Returns indentation of line with closing bracket `close`, which is the
+/// indentation of line with matching opening bracket in `above` lines
+/// (starting at row 0). Brackets in strings and comments are skipped. `None`
+/// if it isn't a closing bracket or there is no matching one.
+pub fn matching_indent(
+    rules: &Rules,
+    highlight: &Highlight,
+    above: &[String],
+    close: char,
+) -> Option<String> {
+    if !rules.close.contains(close) {
+        return None;
+    }
+    let open = opening(close)?;
+    let mut depth = 0;
+    for (row, line) in above.iter().enumerate().rev() {
+        let code: Vec<char> = line
+            .chars()
+            .enumerate()
+            .filter(|(col, _)| highlight.is_code(row, *col))
+            .map(|(_, c)| c)
+            .collect();
+        for c in code.into_iter().rev() {
+            if c == close {
+                depth += 1;
+            } else if c == open {
+                if depth == 0 {
+                    return Some(indentation(line).to_string());
+                }
+                depth -= 1;
+            }
+        }
+    }
+    None
+}
+
+/// Returns indentation of line created by splitting line into `before` and
+/// `after` cursor, `above` are lines before it. If the line was split
+/// between brackets, closing one goes to another line with the second
+/// returned indentation.
+pub fn newline(
+    opts: &Options,
+    buffer_type: &BufferType,
+    highlight: &Highlight,
+    above: &[String],
+    before: &str,
+    after: &str,
+) -> (String, Option<String>) {
+    if !opts.autoindent && !opts.smartindent {
+        return (String::new(), None);
+    }
+    let indent = indentation(before).to_string();
+    let rules = match rules(opts, buffer_type) {
+        Some(rules) if opts.smartindent => rules,
+        _ => return (indent, None),
+    };
+    let row = above.len();
+    let opened = before
+        .trim_end()
+        .chars()
+        .enumerate()
+        .last()
+        .filter(|(col, c)| rules.open.contains(*c) && highlight.is_code(row, *col));
+    let closed = after
+        .trim_start()
+        .chars()
+        .next()
+        .filter(|c| rules.close.contains(*c));
+    match (opened, closed) {
+        (Some(_), Some(_)) => (indent.clone() + &level(opts, &indent), Some(indent)),
+        (Some(_), None) => (indent.clone() + &level(opts, &indent), None),
+        (None, Some(close)) => {
+            let mut lines = above.to_vec();
+            lines.push(before.to_string());
+            (
+                matching_indent(&rules, highlight, &lines, close).unwrap_or(indent),
+                None,
+            )
+        }
+        (None, None) => (indent, None),
+    }
+}
+
+/// returns indentation of line opened above `line` by `O`
+pub fn open_above(opts: &Options, buffer_type: &BufferType, line: &str) -> String {
+    if !opts.autoindent && !opts.smartindent {
+        return String::new();
+    }
+    let indent = indentation(line).to_string();
+    match rules(opts, buffer_type) {
+        // new line is inside of block closed by `line`
+        Some(rules)
+            if opts.smartindent && line.trim_start().starts_with(|c| rules.close.contains(c)) =>
+        {
+            indent.clone() + &level(opts, &indent)
+        }
+        _ => indent,
+    }
+}
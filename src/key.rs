@@ -0,0 +1,240 @@
+//! Key events, every key press (character or special key) is converted to
+//! [Key] before it's processed by [keymap](crate::keymap).
+//!
+//! Keys can be written in Vim notation, e.g. `a`, `<Esc>`, `<C-w>`, `<S-Left>`.
+use std::fmt;
+
+#[cfg(test)]
+mod tests {
+    use super::{parse_keys, Key, KeyCode, Modifiers};
+
+    #[test]
+    fn test_parse_keys() {
+        let ctrl = Modifiers {
+            ctrl: true,
+            ..Modifiers::default()
+        };
+        assert_eq!(
+            parse_keys("a<Esc><C-W><lt>").unwrap(),
+            vec![
+                Key::char('a'),
+                Key::new(KeyCode::Esc),
+                Key {
+                    code: KeyCode::Char('w'),
+                    mods: ctrl
+                },
+                Key::char('<'),
+            ]
+        );
+        assert_eq!(parse_keys("<F12><s-left>").unwrap()[0], Key::new(KeyCode::F(12)));
+        assert!(parse_keys("<S-Left>").unwrap()[0].mods.shift);
+        assert!(parse_keys("<Foo>").is_err());
+        assert!(parse_keys("<Esc").is_err());
+
+        for s in &["a", "<Esc>", "<C-w>", "<A-S-Left>", "<lt>", "<Space>", "<F5>"] {
+            let keys = parse_keys(s).unwrap();
+            assert_eq!(keys.len(), 1);
+            assert_eq!(&keys[0].to_string(), s);
+        }
+    }
+
+    #[test]
+    fn test_from_char() {
+        assert_eq!(Key::from_char('\r'), Key::new(KeyCode::Enter));
+        assert_eq!(Key::from_char('\u{8}'), Key::new(KeyCode::Backspace));
+        assert_eq!(Key::from_char('\u{17}'), parse_keys("<C-w>").unwrap()[0]);
+        assert_eq!(Key::from_char('A'), Key::char('A'));
+    }
+}
+
+/// Key without modifiers
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum KeyCode {
+    /// printable character, shift is already applied (`A` not `<S-a>`)
+    Char(char),
+    Esc,
+    Enter,
+    Tab,
+    Backspace,
+    Delete,
+    Insert,
+    Left,
+    Right,
+    Up,
+    Down,
+    Home,
+    End,
+    PageUp,
+    PageDown,
+    /// function key F1 - F24
+    F(u8),
+}
+
+/// Modifiers held while key was pressed
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+pub struct Modifiers {
+    pub ctrl: bool,
+    pub alt: bool,
+    pub shift: bool,
+}
+
+/// Key press
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct Key {
+    pub code: KeyCode,
+    pub mods: Modifiers,
+}
+
+/// names of special keys in Vim notation, first one is used when printing
+const NAMES: &[(&str, KeyCode)] = &[
+    ("Esc", KeyCode::Esc),
+    ("CR", KeyCode::Enter),
+    ("Enter", KeyCode::Enter),
+    ("Return", KeyCode::Enter),
+    ("Tab", KeyCode::Tab),
+    ("BS", KeyCode::Backspace),
+    ("Del", KeyCode::Delete),
+    ("Insert", KeyCode::Insert),
+    ("Left", KeyCode::Left),
+    ("Right", KeyCode::Right),
+    ("Up", KeyCode::Up),
+    ("Down", KeyCode::Down),
+    ("Home", KeyCode::Home),
+    ("End", KeyCode::End),
+    ("PageUp", KeyCode::PageUp),
+    ("PageDown", KeyCode::PageDown),
+    ("Space", KeyCode::Char(' ')),
+    ("lt", KeyCode::Char('<')),
+    ("Bar", KeyCode::Char('|')),
+    ("Bslash", KeyCode::Char('\\')),
+];
+
+impl Key {
+    pub fn new(code: KeyCode) -> Self {
+        Key {
+            code,
+            mods: Modifiers::default(),
+        }
+    }
+
+    pub fn char(c: char) -> Self {
+        Key::new(KeyCode::Char(c))
+    }
+
+    /// Converts character received from window system to key, control
+    /// characters are converted to special keys or `Ctrl` + letter
+    pub fn from_char(c: char) -> Self {
+        match c {
+            '\u{1b}' => Key::new(KeyCode::Esc),
+            '\r' | '\n' => Key::new(KeyCode::Enter),
+            '\t' => Key::new(KeyCode::Tab),
+            '\u{8}' => Key::new(KeyCode::Backspace),
+            '\u{7f}' => Key::new(KeyCode::Delete),
+            '\u{1}'..='\u{1a}' => Key {
+                code: KeyCode::Char((c as u8 - 1 + b'a') as char),
+                mods: Modifiers {
+                    ctrl: true,
+                    ..Modifiers::default()
+                },
+            },
+            _ => Key::char(c),
+        }
+    }
+
+    /// returns character if key is printable character without Ctrl or Alt
+    pub fn printable(&self) -> Option<char> {
+        match self.code {
+            KeyCode::Char(c) if !self.mods.ctrl && !self.mods.alt && !c.is_control() => Some(c),
+            _ => None,
+        }
+    }
+}
+
+/// parses content of `<...>`
+fn parse_special(name: &str) -> Result<Key, failure::Error> {
+    let mut mods = Modifiers::default();
+    let mut rest = name;
+    // modifiers are single letter followed by '-', but "-" itself is a key
+    while rest.len() > 2 && rest.as_bytes()[1] == b'-' {
+        match rest.as_bytes()[0].to_ascii_lowercase() {
+            b'c' => mods.ctrl = true,
+            b'a' | b'm' => mods.alt = true,
+            b's' => mods.shift = true,
+            _ => bail!("Unknown modifier in <{}>", name),
+        }
+        rest = &rest[2..];
+    }
+
+    let code = if let Some((_, code)) = NAMES.iter().find(|(n, _)| n.eq_ignore_ascii_case(rest)) {
+        *code
+    } else if rest.chars().count() == 1 {
+        let c = rest.chars().next().unwrap();
+        // <C-W> is the same as <C-w>
+        if mods.ctrl {
+            KeyCode::Char(c.to_ascii_lowercase())
+        } else {
+            KeyCode::Char(c)
+        }
+    } else if let Some(n) = rest.strip_prefix(|c| c == 'F' || c == 'f') {
+        match n.parse::<u8>() {
+            Ok(n) if (1..=24).contains(&n) => KeyCode::F(n),
+            _ => bail!("Unknown key <{}>", name),
+        }
+    } else {
+        bail!("Unknown key <{}>", name);
+    };
+    Ok(Key { code, mods })
+}
+
+/// Parses keys in Vim notation, e.g. `dd<C-r>:w<CR>`
+pub fn parse_keys(text: &str) -> Result<Vec<Key>, failure::Error> {
+    let mut result = Vec::new();
+    let mut rest = text;
+    while let Some(c) = rest.chars().next() {
+        if c == '<' {
+            if let Some(end) = rest.find('>') {
+                if end > 1 {
+                    result.push(parse_special(&rest[1..end])?);
+                    rest = &rest[end + 1..];
+                    continue;
+                }
+            }
+            bail!("Unterminated key in {:?}", text);
+        }
+        result.push(Key::char(c));
+        rest = &rest[c.len_utf8()..];
+    }
+    Ok(result)
+}
+
+impl fmt::Display for Key {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self.code {
+            KeyCode::Char(c) => match NAMES.iter().find(|(_, code)| *code == self.code) {
+                Some((name, _)) => name.to_string(),
+                None => c.to_string(),
+            },
+            KeyCode::F(n) => format!("F{}", n),
+            code => NAMES
+                .iter()
+                .find(|(_, c)| *c == code)
+                .map(|(name, _)| name.to_string())
+                .unwrap(),
+        };
+        let special = !matches!(self.code, KeyCode::Char(_)) || name.chars().count() > 1;
+        if !special && self.mods == Modifiers::default() {
+            return write!(f, "{}", name);
+        }
+        write!(f, "<")?;
+        if self.mods.ctrl {
+            write!(f, "C-")?;
+        }
+        if self.mods.alt {
+            write!(f, "A-")?;
+        }
+        if self.mods.shift {
+            write!(f, "S-")?;
+        }
+        write!(f, "{}>", name)
+    }
+}
@@ -0,0 +1,236 @@
+//! Keyboard input of window, converted to [Key]s.
+//!
+//! Window system reports a key press (`KeyboardInput`) and then the
+//! character it produced (`ReceivedCharacter`), if any. Special keys are
+//! taken from the press with all modifiers and their character (`\r`, `\t`,
+//! ...) is ignored. Other keys are taken from the character with held Ctrl
+//! and Alt, because it respects keyboard layout. With Ctrl the character is
+//! often a control character or is not sent at all, then the key is taken
+//! from the press, so `<C-h>` is not `<BS>` and `<C-1>` works.
+use crate::key::{Key, KeyCode, Modifiers};
+use glium::glutin::event::{ElementState, ModifiersState, VirtualKeyCode};
+#[allow(unused_imports)]
+use log::{debug, error, info, trace, warn};
+use std::collections::VecDeque;
+
+#[cfg(test)]
+mod tests {
+    use super::Keyboard;
+    use crate::key::{parse_keys, Key};
+    use glium::glutin::event::{ElementState, ModifiersState, VirtualKeyCode};
+
+    /// presses `code` with `mods`, window sends character `c`
+    fn press(mods: ModifiersState, code: VirtualKeyCode, c: Option<char>) -> Vec<Key> {
+        let mut keyboard = Keyboard::default();
+        keyboard.modifiers_changed(mods);
+        keyboard.key_input(ElementState::Pressed, Some(code));
+        if let Some(c) = c {
+            keyboard.received_char(c);
+        }
+        keyboard.key_input(ElementState::Released, Some(code));
+        keyboard.flush();
+        keyboard.keys().collect()
+    }
+
+    fn keys(text: &str) -> Vec<Key> {
+        parse_keys(text).unwrap()
+    }
+
+    #[test]
+    fn test_keys() {
+        use VirtualKeyCode::*;
+        let none = ModifiersState::empty();
+        let ctrl = ModifiersState::CTRL;
+        let shift = ModifiersState::SHIFT;
+        assert_eq!(press(none, A, Some('a')), keys("a"));
+        assert_eq!(press(shift, A, Some('A')), keys("A"));
+        assert_eq!(press(ctrl, R, Some('\u{12}')), keys("<C-r>"));
+        assert_eq!(press(ctrl, H, Some('\u{8}')), keys("<C-h>"));
+        assert_eq!(press(ctrl, LBracket, Some('\u{1b}')), keys("<Esc>"));
+        assert_eq!(press(ctrl, Key1, None), keys("<C-1>"));
+        assert_eq!(press(ctrl | shift, Equals, Some('+')), keys("<C-+>"));
+        assert_eq!(press(ModifiersState::ALT, X, Some('x')), keys("<A-x>"));
+        assert_eq!(press(none, Back, Some('\u{8}')), keys("<BS>"));
+        assert_eq!(press(none, Delete, Some('\u{7f}')), keys("<Del>"));
+        assert_eq!(press(none, Return, Some('\r')), keys("<CR>"));
+        assert_eq!(press(shift, Home, None), keys("<S-Home>"));
+        assert_eq!(press(ctrl, F5, None), keys("<C-F5>"));
+        // key without virtual key code
+        let mut keyboard = Keyboard::default();
+        keyboard.key_input(ElementState::Pressed, None);
+        keyboard.received_char('é');
+        assert_eq!(keyboard.keys().collect::<Vec<_>>(), keys("é"));
+    }
+}
+
+/// Converts keyboard events of window to keys
+#[derive(Default)]
+pub struct Keyboard {
+    mods: ModifiersState,
+    /// key pressed with Ctrl or Alt, it's used if no printable character
+    /// follows
+    pending: Option<Key>,
+    /// control character of pressed special key is ignored
+    skip_control: bool,
+    /// converted keys
+    keys: VecDeque<Key>,
+}
+
+impl Keyboard {
+    pub fn modifiers_changed(&mut self, mods: ModifiersState) {
+        self.mods = mods;
+    }
+
+    /// processes `KeyboardInput` event
+    pub fn key_input(&mut self, state: ElementState, code: Option<VirtualKeyCode>) {
+        if state != ElementState::Pressed {
+            return;
+        }
+        self.flush();
+        self.skip_control = false;
+        let code = match code {
+            Some(code) => code,
+            None => return,
+        };
+        if let Some(code) = special_key(code) {
+            self.keys.push_back(Key {
+                code,
+                mods: self.modifiers(true),
+            });
+            self.skip_control = true;
+        } else if self.mods.ctrl() || self.mods.alt() {
+            self.pending = key_char(code).map(|c| Key {
+                code: KeyCode::Char(c),
+                mods: self.modifiers(false),
+            });
+        }
+    }
+
+    /// processes `ReceivedCharacter` event
+    pub fn received_char(&mut self, c: char) {
+        let pending = self.pending.take();
+        if c.is_control() {
+            if std::mem::take(&mut self.skip_control) {
+                return;
+            }
+            // <C-[> is <Esc> as in Vim
+            let key = match pending {
+                Some(key) if c != '\u{1b}' => key,
+                _ => Key::from_char(c),
+            };
+            return self.keys.push_back(key);
+        }
+        self.skip_control = false;
+        self.keys.push_back(Key {
+            code: KeyCode::Char(c),
+            mods: self.modifiers(false),
+        });
+    }
+
+    /// Converts key pressed with Ctrl or Alt that didn't produce character,
+    /// has to be called after all events are processed
+    pub fn flush(&mut self) {
+        if let Some(key) = self.pending.take() {
+            self.keys.push_back(key);
+        }
+    }
+
+    /// returns converted keys
+    pub fn keys(&mut self) -> impl Iterator<Item = Key> + '_ {
+        self.keys.drain(..)
+    }
+
+    /// returns held modifiers, shift is already applied to characters
+    fn modifiers(&self, shift: bool) -> Modifiers {
+        Modifiers {
+            ctrl: self.mods.ctrl(),
+            alt: self.mods.alt(),
+            shift: shift && self.mods.shift(),
+        }
+    }
+}
+
+/// converts keys that don't produce characters
+fn special_key(key: VirtualKeyCode) -> Option<KeyCode> {
+    use VirtualKeyCode::*;
+    Some(match key {
+        Escape => KeyCode::Esc,
+        Return | NumpadEnter => KeyCode::Enter,
+        Tab => KeyCode::Tab,
+        Back => KeyCode::Backspace,
+        Delete => KeyCode::Delete,
+        Insert => KeyCode::Insert,
+        Left => KeyCode::Left,
+        Right => KeyCode::Right,
+        Up => KeyCode::Up,
+        Down => KeyCode::Down,
+        Home => KeyCode::Home,
+        End => KeyCode::End,
+        PageUp => KeyCode::PageUp,
+        PageDown => KeyCode::PageDown,
+        F1 => KeyCode::F(1),
+        F2 => KeyCode::F(2),
+        F3 => KeyCode::F(3),
+        F4 => KeyCode::F(4),
+        F5 => KeyCode::F(5),
+        F6 => KeyCode::F(6),
+        F7 => KeyCode::F(7),
+        F8 => KeyCode::F(8),
+        F9 => KeyCode::F(9),
+        F10 => KeyCode::F(10),
+        F11 => KeyCode::F(11),
+        F12 => KeyCode::F(12),
+        F13 => KeyCode::F(13),
+        F14 => KeyCode::F(14),
+        F15 => KeyCode::F(15),
+        F16 => KeyCode::F(16),
+        F17 => KeyCode::F(17),
+        F18 => KeyCode::F(18),
+        F19 => KeyCode::F(19),
+        F20 => KeyCode::F(20),
+        F21 => KeyCode::F(21),
+        F22 => KeyCode::F(22),
+        F23 => KeyCode::F(23),
+        F24 => KeyCode::F(24),
+        _ => return None,
+    })
+}
+
+/// returns character of key on US layout without shift, used when window
+/// system sends no character for key pressed with Ctrl
+fn key_char(key: VirtualKeyCode) -> Option<char> {
+    use VirtualKeyCode::*;
+    let letters = [
+        A, B, C, D, E, F, G, H, I, J, K, L, M, N, O, P, Q, R, S, T, U, V, W, X, Y, Z,
+    ];
+    if let Some(i) = letters.iter().position(|k| *k == key) {
+        return Some((b'a' + i as u8) as char);
+    }
+    let digits = [Key0, Key1, Key2, Key3, Key4, Key5, Key6, Key7, Key8, Key9];
+    let numpad = [
+        Numpad0, Numpad1, Numpad2, Numpad3, Numpad4, Numpad5, Numpad6, Numpad7, Numpad8, Numpad9,
+    ];
+    if let Some(i) = digits.iter().position(|k| *k == key) {
+        return Some((b'0' + i as u8) as char);
+    }
+    if let Some(i) = numpad.iter().position(|k| *k == key) {
+        return Some((b'0' + i as u8) as char);
+    }
+    Some(match key {
+        Space => ' ',
+        Minus | NumpadSubtract => '-',
+        Equals | NumpadEquals => '=',
+        Plus | NumpadAdd => '+',
+        LBracket => '[',
+        RBracket => ']',
+        Backslash => '\\',
+        Semicolon => ';',
+        Apostrophe => '\'',
+        Comma | NumpadComma => ',',
+        Period | NumpadDecimal => '.',
+        Slash | NumpadDivide => '/',
+        Asterisk | NumpadMultiply => '*',
+        Grave => '`',
+        _ => return None,
+    })
+}
@@ -0,0 +1,379 @@
+//! Key bindings.
+//!
+//! Every mode has two tries of key sequences, one with built-in bindings to
+//! [Action]s and one with user mappings created by `:map` and `:noremap`.
+//! User mappings are expanded to other keys, which are processed again
+//! (`:map`) or only by built-in bindings (`:noremap`).
+//!
+//! When typed keys are prefix of longer sequence, [Keymap] waits for more
+//! keys until `timeoutlen` expires.
+use crate::key::{parse_keys, Key};
+use crate::editor::State;
+use std::collections::{HashMap, VecDeque};
+use std::time::{Duration, Instant};
+
+#[cfg(test)]
+mod tests {
+    use super::{Action, Keymap, Resolved};
+    use crate::key::{parse_keys, Key, KeyCode};
+    use crate::editor::State;
+    use std::time::{Duration, Instant};
+
+    fn feed(map: &mut Keymap, mode: State, keys: &str, now: Instant) -> Vec<Resolved> {
+        for key in parse_keys(keys).unwrap() {
+            map.push(key, now);
+        }
+        let mut result = Vec::new();
+        while let Some(r) = map.next(mode, now) {
+            result.push(r);
+        }
+        result
+    }
+
+    #[test]
+    fn test_builtin() {
+        let mut map = Keymap::new(Duration::from_millis(1000));
+        let now = Instant::now();
+        assert_eq!(
+            feed(&mut map, State::Normal, "j<Down>", now),
+            vec![Resolved::Action(Action::MoveDown), Resolved::Action(Action::MoveDown)]
+        );
+        // `g` is prefix of `gj`
+        assert_eq!(feed(&mut map, State::Normal, "g", now), vec![]);
+        assert_eq!(
+            feed(&mut map, State::Normal, "j", now),
+            vec![Resolved::Action(Action::MoveDisplayDown)]
+        );
+        assert_eq!(
+            feed(&mut map, State::Insert, "a", now),
+            vec![Resolved::Unmapped(Key::char('a'))]
+        );
+    }
+
+    #[test]
+    fn test_user_mappings() {
+        let mut map = Keymap::new(Duration::from_millis(1000));
+        let now = Instant::now();
+        let lhs = parse_keys("jk").unwrap();
+        map.map(State::Insert, &lhs, parse_keys("<Esc>").unwrap(), true);
+        assert_eq!(
+            feed(&mut map, State::Insert, "jk", now),
+            vec![Resolved::Action(Action::NormalMode)]
+        );
+        // timeout, `j` is inserted
+        assert_eq!(feed(&mut map, State::Insert, "j", now), vec![]);
+        assert_eq!(map.deadline(), Some(now + Duration::from_millis(1000)));
+        let later = now + Duration::from_millis(1001);
+        assert_eq!(
+            map.next(State::Insert, later),
+            Some(Resolved::Unmapped(Key::char('j')))
+        );
+        assert_eq!(map.deadline(), None);
+
+        // recursive mapping goes through user mappings again
+        map.map(State::Normal, &parse_keys("x").unwrap(), parse_keys("q").unwrap(), false);
+        map.map(State::Normal, &parse_keys("q").unwrap(), parse_keys("j").unwrap(), false);
+        map.map(State::Normal, &parse_keys("z").unwrap(), parse_keys("q").unwrap(), true);
+        assert_eq!(
+            feed(&mut map, State::Normal, "xz", now),
+            vec![
+                Resolved::Action(Action::MoveDown),
+                Resolved::Unmapped(Key::char('q'))
+            ]
+        );
+
+        map.unmap(State::Normal, &parse_keys("x").unwrap()).unwrap();
+        assert!(map.unmap(State::Normal, &parse_keys("x").unwrap()).is_err());
+        assert_eq!(
+            feed(&mut map, State::Normal, "x", now),
+            vec![Resolved::Unmapped(Key::char('x'))]
+        );
+
+        // infinite recursion is stopped
+        map.map(State::Normal, &parse_keys("a").unwrap(), parse_keys("a").unwrap(), false);
+        assert_eq!(feed(&mut map, State::Normal, "a", now), vec![Resolved::RecursionLimit]);
+        assert_eq!(
+            feed(&mut map, State::Normal, "<Left>", now),
+            vec![Resolved::Action(Action::MoveLeft)]
+        );
+        assert_eq!(Key::new(KeyCode::Left), parse_keys("<Left>").unwrap()[0]);
+    }
+}
+
+/// Editor actions that can be bound to keys
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Action {
+    MoveLeft,
+    MoveRight,
+    MoveUp,
+    MoveDown,
+    /// move by display line (`gj`)
+    MoveDisplayDown,
+    /// move by display line (`gk`)
+    MoveDisplayUp,
+    NormalMode,
+    InsertMode,
+    VisualMode,
+    CommandMode,
+    /// delete char before cursor
+    Backspace,
+    /// split line at cursor
+    Newline,
+    /// open new line below cursor (`o`)
+    OpenBelow,
+    /// open new line above cursor (`O`)
+    OpenAbove,
+    /// delete to start of next word into register (`dw`)
+    DeleteWord,
+    /// copy to start of next word into register (`yw`)
+    YankWord,
+    /// insert register after cursor (`p`)
+    Put,
+    /// insert `tabstop` spaces
+    Tab,
+    /// execute command line
+    ExecuteCommand,
+    /// increase font size of window
+    ZoomIn,
+    /// decrease font size of window
+    ZoomOut,
+    /// reset font size of window to configured size
+    ZoomReset,
+    /// increase font size of current buffer
+    ZoomInBuffer,
+    /// decrease font size of current buffer
+    ZoomOutBuffer,
+    /// reset font size of current buffer to font size of window
+    ZoomResetBuffer,
+}
+
+/// built-in bindings
+const DEFAULTS: &[(&[State], &str, Action)] = &[
+    (&[State::Normal, State::Visual], "h", Action::MoveLeft),
+    (&[State::Normal, State::Visual], "j", Action::MoveDown),
+    (&[State::Normal, State::Visual], "k", Action::MoveUp),
+    (&[State::Normal, State::Visual], "l", Action::MoveRight),
+    (&[State::Normal, State::Visual], "gj", Action::MoveDisplayDown),
+    (&[State::Normal, State::Visual], "gk", Action::MoveDisplayUp),
+    (&[State::Normal, State::Visual, State::Insert], "<Left>", Action::MoveLeft),
+    (&[State::Normal, State::Visual, State::Insert], "<Down>", Action::MoveDown),
+    (&[State::Normal, State::Visual, State::Insert], "<Up>", Action::MoveUp),
+    (&[State::Normal, State::Visual, State::Insert], "<Right>", Action::MoveRight),
+    (&[State::Normal], "i", Action::InsertMode),
+    (&[State::Normal], "o", Action::OpenBelow),
+    (&[State::Normal], "O", Action::OpenAbove),
+    (&[State::Normal], "dw", Action::DeleteWord),
+    (&[State::Normal], "yw", Action::YankWord),
+    (&[State::Normal], "p", Action::Put),
+    (&[State::Normal], "v", Action::VisualMode),
+    (&[State::Normal], ":", Action::CommandMode),
+    (&[State::Insert, State::Visual, State::Command], "<Esc>", Action::NormalMode),
+    (&[State::Insert, State::Command], "<BS>", Action::Backspace),
+    (&[State::Insert], "<CR>", Action::Newline),
+    (&[State::Insert], "<Tab>", Action::Tab),
+    (&[State::Command], "<CR>", Action::ExecuteCommand),
+    (&[State::Normal, State::Visual, State::Insert], "<C-=>", Action::ZoomIn),
+    (&[State::Normal, State::Visual, State::Insert], "<C-+>", Action::ZoomIn),
+    (&[State::Normal, State::Visual, State::Insert], "<C-->", Action::ZoomOut),
+    (&[State::Normal, State::Visual, State::Insert], "<C-0>", Action::ZoomReset),
+    (&[State::Normal, State::Visual, State::Insert], "<C-A-=>", Action::ZoomInBuffer),
+    (&[State::Normal, State::Visual, State::Insert], "<C-A-+>", Action::ZoomInBuffer),
+    (&[State::Normal, State::Visual, State::Insert], "<C-A-->", Action::ZoomOutBuffer),
+    (&[State::Normal, State::Visual, State::Insert], "<C-A-0>", Action::ZoomResetBuffer),
+];
+
+/// maximal depth of recursive mappings
+const MAX_MAP_DEPTH: usize = 1000;
+
+/// What key sequence is bound to
+#[derive(Clone, Debug, PartialEq)]
+enum Binding {
+    Action(Action),
+    /// keys of user mapping and whether they can be remapped
+    Keys(Vec<Key>, bool),
+}
+
+/// Node of a trie of key sequences
+#[derive(Default, Debug)]
+struct Node {
+    binding: Option<Binding>,
+    children: HashMap<Key, Node>,
+}
+
+impl Node {
+    fn insert(&mut self, keys: &[Key], binding: Binding) {
+        let mut node = self;
+        for key in keys {
+            node = node.children.entry(*key).or_default();
+        }
+        node.binding = Some(binding);
+    }
+
+    /// removes binding of `keys`, returns false if there was none
+    fn remove(&mut self, keys: &[Key]) -> bool {
+        match keys.split_first() {
+            None => self.binding.take().is_some(),
+            Some((key, rest)) => match self.children.get_mut(key) {
+                Some(child) => {
+                    let removed = child.remove(rest);
+                    if child.binding.is_none() && child.children.is_empty() {
+                        self.children.remove(key);
+                    }
+                    removed
+                }
+                None => false,
+            },
+        }
+    }
+
+    /// returns longest binding that is prefix of `keys` with its length and
+    /// true if `keys` are prefix of longer sequence
+    fn lookup<'a>(&'a self, keys: &[Key]) -> (Option<(&'a Binding, usize)>, bool) {
+        let mut node = self;
+        let mut longest = None;
+        for (i, key) in keys.iter().enumerate() {
+            node = match node.children.get(key) {
+                Some(n) => n,
+                None => return (longest, false),
+            };
+            if let Some(b) = &node.binding {
+                longest = Some((b, i + 1));
+            }
+        }
+        (longest, !node.children.is_empty())
+    }
+}
+
+/// Result of processing typed keys
+#[derive(Clone, Debug, PartialEq)]
+pub enum Resolved {
+    Action(Action),
+    /// key that's not bound to anything, e.g. text typed in Insert mode
+    Unmapped(Key),
+    /// mapping was expanded too many times, pending keys were dropped
+    RecursionLimit,
+}
+
+/// Keys typed by user waiting to be processed
+#[derive(Clone, Copy, Debug)]
+struct Pending {
+    key: Key,
+    /// key can be remapped by user mappings
+    remap: bool,
+}
+
+/// Key bindings of all modes and keys waiting to be resolved
+pub struct Keymap {
+    builtin: HashMap<State, Node>,
+    user: HashMap<State, Node>,
+    pending: VecDeque<Pending>,
+    /// time of last typed key
+    last_key: Option<Instant>,
+    /// number of expanded mappings since last typed key
+    depth: usize,
+    pub timeout: Duration,
+}
+
+impl Keymap {
+    /// creates keymap with built-in bindings
+    pub fn new(timeout: Duration) -> Self {
+        let mut builtin: HashMap<State, Node> = HashMap::new();
+        for (modes, keys, action) in DEFAULTS {
+            let keys = parse_keys(keys).unwrap();
+            for mode in modes.iter() {
+                builtin
+                    .entry(*mode)
+                    .or_default()
+                    .insert(&keys, Binding::Action(*action));
+            }
+        }
+        Keymap {
+            builtin,
+            user: HashMap::new(),
+            pending: VecDeque::new(),
+            last_key: None,
+            depth: 0,
+            timeout,
+        }
+    }
+
+    /// adds user mapping of `lhs` to `rhs` in `mode`, keys of `rhs` are
+    /// remapped again unless `noremap` is true
+    pub fn map(&mut self, mode: State, lhs: &[Key], rhs: Vec<Key>, noremap: bool) {
+        self.user
+            .entry(mode)
+            .or_default()
+            .insert(lhs, Binding::Keys(rhs, !noremap));
+    }
+
+    /// removes user mapping of `lhs` in `mode`
+    pub fn unmap(&mut self, mode: State, lhs: &[Key]) -> Result<(), failure::Error> {
+        let removed = match self.user.get_mut(&mode) {
+            Some(node) => node.remove(lhs),
+            None => false,
+        };
+        if !removed {
+            bail!("No such mapping");
+        }
+        Ok(())
+    }
+
+    /// adds key typed by user
+    pub fn push(&mut self, key: Key, now: Instant) {
+        self.pending.push_back(Pending { key, remap: true });
+        self.last_key = Some(now);
+        self.depth = 0;
+    }
+
+    /// returns time when pending keys stop waiting for longer sequence
+    pub fn deadline(&self) -> Option<Instant> {
+        match (self.pending.is_empty(), self.last_key) {
+            (false, Some(last)) => Some(last + self.timeout),
+            _ => None,
+        }
+    }
+
+    /// Resolves next action from pending keys in `mode`. Returns `None` if
+    /// there are no keys or they are waiting for longer sequence. Has to be
+    /// called again after the action is executed, as it may change mode.
+    pub fn next(&mut self, mode: State, now: Instant) -> Option<Resolved> {
+        loop {
+            let first = *self.pending.front()?;
+            let keys: Vec<Key> = self.pending.iter().map(|p| p.key).collect();
+            let timed_out = self.deadline().is_some_and(|d| now >= d);
+
+            let empty = Node::default();
+            let (user, user_more) = match self.user.get(&mode) {
+                Some(node) if first.remap => node.lookup(&keys),
+                _ => (None, false),
+            };
+            let (builtin, builtin_more) = self.builtin.get(&mode).unwrap_or(&empty).lookup(&keys);
+
+            if (user_more || builtin_more) && !timed_out {
+                return None;
+            }
+
+            let (binding, len) = match user.or(builtin) {
+                Some((binding, len)) => (binding.clone(), len),
+                None => {
+                    self.pending.pop_front();
+                    return Some(Resolved::Unmapped(first.key));
+                }
+            };
+            self.pending.drain(..len);
+            match binding {
+                Binding::Action(action) => return Some(Resolved::Action(action)),
+                Binding::Keys(rhs, remap) => {
+                    self.depth += 1;
+                    if self.depth > MAX_MAP_DEPTH {
+                        self.pending.clear();
+                        return Some(Resolved::RecursionLimit);
+                    }
+                    for key in rhs.into_iter().rev() {
+                        self.pending.push_front(Pending { key, remap });
+                    }
+                }
+            }
+        }
+    }
+}
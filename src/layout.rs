@@ -0,0 +1,689 @@
+//! Text layout shared by renderer, cursor and mouse hit-testing.
+//!
+//! Buffer lines are split into display lines according to [LayoutParams] and
+//! the result is cached per line. All coordinates are in pixels relative to
+//! top left corner of text area. Glyph sizes are provided by [Metrics], so
+//! layout can be used (and tested) without font or OpenGL context.
+use crate::font::{Face, Fonts};
+use crate::options::Options;
+use rusttype::Scale;
+
+#[cfg(test)]
+mod tests {
+    use super::{CellMetrics, Layout, LayoutParams, Metrics};
+    use rusttype::Scale;
+
+    /// monospace font, every glyph is 10px wide and line is 20px high
+    struct Mono;
+
+    impl Metrics for Mono {
+        fn advance(&self, _c: char) -> f32 {
+            10.0
+        }
+        fn kerning(&self, _a: char, _b: char) -> f32 {
+            0.0
+        }
+        fn ascent(&self) -> f32 {
+            15.0
+        }
+        fn line_height(&self) -> f32 {
+            20.0
+        }
+    }
+
+    fn params(width: f32, wrap: bool, linebreak: bool, showbreak: &str) -> LayoutParams {
+        LayoutParams {
+            scale: Scale::uniform(20.0),
+            width,
+            wrap,
+            linebreak,
+            showbreak: showbreak.to_string(),
+        }
+    }
+
+    fn text(lines: &[&str]) -> Vec<String> {
+        lines.iter().map(|l| l.to_string()).collect()
+    }
+
+    #[test]
+    fn test_wrap() {
+        let buff = text(&["abcdefghij", "ab"]);
+        let mut layout = Layout::new(params(40.0, true, false, ""));
+        let lines = layout.line(&Mono, &buff, 0);
+        assert_eq!(lines.len(), 3);
+        assert_eq!((lines[0].start, lines[0].end), (0, 4));
+        assert_eq!((lines[1].start, lines[1].end), (4, 8));
+        assert_eq!((lines[2].start, lines[2].end), (8, 10));
+        assert!(!lines[0].continuation);
+        assert!(lines[1].continuation);
+        assert_eq!(layout.line(&Mono, &buff, 1).len(), 1);
+
+        let mut layout = Layout::new(params(40.0, false, false, ""));
+        let lines = layout.line(&Mono, &buff, 0);
+        assert_eq!(lines.len(), 1);
+        assert_eq!(lines[0].end, 10);
+    }
+
+    #[test]
+    fn test_linebreak_and_showbreak() {
+        let buff = text(&["ab cdef gh"]);
+        let mut layout = Layout::new(params(60.0, true, true, ""));
+        let lines = layout.line(&Mono, &buff, 0);
+        assert_eq!((lines[0].start, lines[0].end), (0, 3));
+        assert_eq!((lines[1].start, lines[1].end), (3, 8));
+        assert_eq!((lines[2].start, lines[2].end), (8, 10));
+
+        let mut layout = Layout::new(params(60.0, true, false, ">"));
+        let lines = layout.line(&Mono, &buff, 0);
+        assert_eq!((lines[0].start, lines[0].end), (0, 6));
+        // showbreak takes space of one glyph
+        assert_eq!(lines[1].glyphs[0].x, 10.0);
+        assert_eq!((lines[1].start, lines[1].end), (6, 10));
+    }
+
+    #[test]
+    fn test_screen_pos_and_hit_test() {
+        let buff = text(&["abcdefghij", "ab", ""]);
+        let mut layout = Layout::new(params(40.0, true, false, ""));
+        let view_pos = (0, 0);
+
+        let cell = layout.screen_pos(&Mono, &buff, view_pos, (0, 5)).unwrap();
+        assert_eq!((cell.x, cell.y), (10.0, 20.0));
+        let cell = layout.screen_pos(&Mono, &buff, view_pos, (1, 2)).unwrap();
+        assert_eq!((cell.x, cell.y), (20.0, 60.0));
+        let cell = layout.screen_pos(&Mono, &buff, view_pos, (2, 0)).unwrap();
+        assert_eq!((cell.x, cell.y), (0.0, 80.0));
+
+        assert_eq!(layout.hit_test(&Mono, &buff, view_pos, (15.0, 25.0)), (0, 5));
+        assert_eq!(layout.hit_test(&Mono, &buff, view_pos, (35.0, 65.0)), (1, 2));
+        assert_eq!(layout.hit_test(&Mono, &buff, view_pos, (0.0, 500.0)), (2, 0));
+        for col in 0..10 {
+            let cell = layout.screen_pos(&Mono, &buff, view_pos, (0, col)).unwrap();
+            assert_eq!(
+                layout.hit_test(&Mono, &buff, view_pos, (cell.x + 1.0, cell.y + 1.0)),
+                (0, col)
+            );
+        }
+    }
+
+    #[test]
+    fn test_scrolling() {
+        let buff = text(&["abcdefghij", "a", "b", "c"]);
+        let mut layout = Layout::new(params(40.0, false, false, ""));
+        let mut view_pos = (0, 0);
+        layout.scroll_to_cursor(&Mono, &buff, (0, 8), 40.0, &mut view_pos);
+        assert_eq!(view_pos, (0, 5));
+        let cell = layout.screen_pos(&Mono, &buff, view_pos, (0, 8)).unwrap();
+        assert_eq!(cell.x, 30.0);
+        layout.scroll_to_cursor(&Mono, &buff, (3, 0), 40.0, &mut view_pos);
+        assert_eq!(view_pos, (2, 0));
+        assert!(layout.screen_pos(&Mono, &buff, view_pos, (0, 0)).is_none());
+
+        let mut layout = Layout::new(params(40.0, true, false, ""));
+        let mut view_pos = (0, 0);
+        layout.scroll_to_cursor(&Mono, &buff, (0, 9), 60.0, &mut view_pos);
+        assert_eq!(view_pos, (0, 0));
+        let cell = layout.screen_pos(&Mono, &buff, view_pos, (0, 9)).unwrap();
+        assert_eq!(cell.y, 40.0);
+    }
+
+    #[test]
+    fn test_scroll() {
+        let buff = text(&["abcdefghij", "a", "b", "c"]);
+        let mut layout = Layout::new(params(40.0, true, false, ""));
+        // first row has 3 display lines
+        assert_eq!(layout.scroll(&Mono, &buff, 0, 1), 1);
+        assert_eq!(layout.scroll(&Mono, &buff, 0, 4), 2);
+        assert_eq!(layout.scroll(&Mono, &buff, 1, 10), 3);
+        assert_eq!(layout.scroll(&Mono, &buff, 2, -2), 0);
+        assert_eq!(layout.scroll(&Mono, &buff, 0, -1), 0);
+    }
+
+    #[test]
+    fn test_wide_chars() {
+        let buff = vec!["a漢字b".to_string()];
+        let params = LayoutParams {
+            width: 4.0,
+            ..LayoutParams::default()
+        };
+        let mut layout = Layout::new(params);
+        let lines = layout.line(&CellMetrics, &buff, 0);
+        assert_eq!((lines[0].start, lines[0].end), (0, 2));
+        assert_eq!(lines[1].glyphs[0].x, 0.0);
+        assert_eq!(lines[1].end_x, 3.0);
+    }
+
+    #[test]
+    fn test_invalidate() {
+        let mut buff = text(&["abcdefghij"]);
+        let mut layout = Layout::new(params(40.0, true, false, ""));
+        assert_eq!(layout.line(&Mono, &buff, 0).len(), 3);
+        buff[0] = "ab".to_string();
+        layout.invalidate_from(0);
+        assert_eq!(layout.line(&Mono, &buff, 0).len(), 1);
+        layout.set_params(params(10.0, true, false, ""));
+        assert_eq!(layout.line(&Mono, &buff, 0).len(), 2);
+    }
+}
+
+/// Parameters controlling how buffer lines are split into display lines
+#[derive(Clone, Debug, PartialEq)]
+pub struct LayoutParams {
+    /// font scale in pixels
+    pub scale: Scale,
+    /// width of text area in pixels
+    pub width: f32,
+    /// wrap lines longer than `width`
+    pub wrap: bool,
+    /// wrap at word boundaries instead of at the last glyph that fits
+    pub linebreak: bool,
+    /// string drawn at the start of wrapped (continuation) lines
+    pub showbreak: String,
+}
+
+impl Default for LayoutParams {
+    fn default() -> Self {
+        LayoutParams {
+            scale: Scale::uniform(24.0),
+            width: 512.0,
+            wrap: true,
+            linebreak: false,
+            showbreak: String::new(),
+        }
+    }
+}
+
+impl LayoutParams {
+    pub fn new(opts: &Options, scale: Scale, width: f32) -> Self {
+        LayoutParams {
+            scale,
+            width,
+            wrap: opts.wrap,
+            linebreak: opts.linebreak,
+            showbreak: opts.showbreak.clone(),
+        }
+    }
+}
+
+/// Provides sizes of glyphs to layout
+pub trait Metrics {
+    /// horizontal advance of `c`
+    fn advance(&self, c: char) -> f32;
+    /// kerning between `a` and `b` when `b` follows `a`
+    fn kerning(&self, a: char, b: char) -> f32;
+    /// distance from top of line to baseline
+    fn ascent(&self) -> f32;
+    /// height of one display line
+    fn line_height(&self) -> f32;
+}
+
+/// [Metrics] of rusttype fonts at given scale, every char is measured in
+/// font that draws it
+pub struct FontMetrics<'f, 'a> {
+    fonts: &'f Fonts<'a>,
+    scale: Scale,
+}
+
+impl<'f, 'a> FontMetrics<'f, 'a> {
+    pub fn new(fonts: &'f Fonts<'a>, scale: Scale) -> Self {
+        FontMetrics { fonts, scale }
+    }
+}
+
+impl Metrics for FontMetrics<'_, '_> {
+    fn advance(&self, c: char) -> f32 {
+        let (_, font) = self.fonts.glyph_font(c, Face::Regular);
+        font.glyph(c).scaled(self.scale).h_metrics().advance_width
+    }
+    fn kerning(&self, a: char, b: char) -> f32 {
+        // there is no kerning between glyphs of different fonts
+        let (a_id, font) = self.fonts.glyph_font(a, Face::Regular);
+        let (b_id, _) = self.fonts.glyph_font(b, Face::Regular);
+        if a_id == b_id {
+            font.pair_kerning(self.scale, a, b)
+        } else {
+            0.0
+        }
+    }
+    fn ascent(&self) -> f32 {
+        self.fonts.primary().v_metrics(self.scale).ascent
+    }
+    fn line_height(&self) -> f32 {
+        let v_metrics = self.fonts.primary().v_metrics(self.scale);
+        v_metrics.ascent - v_metrics.descent + v_metrics.line_gap
+    }
+}
+
+/// [Metrics] of terminal cells, every char takes one or two cells
+pub struct CellMetrics;
+
+impl Metrics for CellMetrics {
+    fn advance(&self, c: char) -> f32 {
+        cell_width(c) as f32
+    }
+    fn kerning(&self, _a: char, _b: char) -> f32 {
+        0.0
+    }
+    fn ascent(&self) -> f32 {
+        0.0
+    }
+    fn line_height(&self) -> f32 {
+        1.0
+    }
+}
+
+/// returns number of cells taken by `c`, East Asian wide chars and emoji
+/// take two
+pub fn cell_width(c: char) -> usize {
+    match c as u32 {
+        0x1100..=0x115F
+        | 0x2E80..=0x303E
+        | 0x3041..=0x33FF
+        | 0x3400..=0x4DBF
+        | 0x4E00..=0x9FFF
+        | 0xA000..=0xA4CF
+        | 0xAC00..=0xD7A3
+        | 0xF900..=0xFAFF
+        | 0xFE30..=0xFE4F
+        | 0xFF00..=0xFF60
+        | 0xFFE0..=0xFFE6
+        | 0x1F300..=0x1F64F
+        | 0x1F900..=0x1F9FF
+        | 0x20000..=0x3FFFD => 2,
+        _ => 1,
+    }
+}
+
+/// Position of one character of a buffer line on a display line
+#[derive(Clone, Debug)]
+pub struct GlyphPos {
+    /// column (char index) in buffer line
+    pub col: usize,
+    pub ch: char,
+    /// x position in pixels relative to the left edge of text area
+    pub x: f32,
+    pub advance: f32,
+}
+
+/// Part of buffer line that is displayed on one row of screen
+#[derive(Clone, Debug)]
+pub struct DisplayLine {
+    /// row in buffer
+    pub row: usize,
+    /// first column on this display line
+    pub start: usize,
+    /// column after the last one on this display line
+    pub end: usize,
+    /// true if this is not the first display line of buffer line
+    pub continuation: bool,
+    pub glyphs: Vec<GlyphPos>,
+    /// x position after the last glyph
+    pub end_x: f32,
+}
+
+impl DisplayLine {
+    /// returns x position of `col` or of the end of line if `col` is past it
+    pub fn x_of(&self, col: usize) -> f32 {
+        match self.glyphs.iter().find(|g| g.col == col) {
+            Some(g) => g.x,
+            None => self.end_x,
+        }
+    }
+
+    /// returns column displayed at `x` or the column after last glyph
+    pub fn col_at(&self, x: f32) -> usize {
+        for g in &self.glyphs {
+            if x < g.x + g.advance {
+                return g.col;
+            }
+        }
+        self.end
+    }
+
+    /// returns true if cursor at `col` is displayed on this line, `last`
+    /// indicates if this is the last display line of buffer line
+    pub fn contains(&self, col: usize, last: bool) -> bool {
+        col >= self.start && (col < self.end || last)
+    }
+
+    /// horizontal offset in pixels of line scrolled to `left_col`
+    pub fn scroll_offset(&self, left_col: usize) -> f32 {
+        if left_col == 0 {
+            0.0
+        } else {
+            self.x_of(left_col)
+        }
+    }
+}
+
+/// Rectangle occupied by one character on screen
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Cell {
+    pub x: f32,
+    pub y: f32,
+    pub width: f32,
+    pub height: f32,
+}
+
+/// width of `text` when laid out on one line
+fn text_width(metrics: &dyn Metrics, text: &str) -> f32 {
+    let mut last = None;
+    let mut x = 0.0;
+    for c in text.chars() {
+        if let Some(l) = last.take() {
+            x += metrics.kerning(l, c);
+        }
+        last = Some(c);
+        x += metrics.advance(c);
+    }
+    x
+}
+
+/// Splits buffer line `row` into display lines.
+/// When wrapping is off, whole line is returned as single display line and
+/// it's up to caller to scroll horizontaly.
+fn layout_line(metrics: &dyn Metrics, params: &LayoutParams, row: usize, line: &str) -> Vec<DisplayLine> {
+    let chars: Vec<char> = line.chars().collect();
+    let indent = text_width(metrics, &params.showbreak);
+
+    let mut result = Vec::new();
+    let mut start = 0;
+    loop {
+        let continuation = start != 0;
+        let mut x = if continuation { indent } else { 0.0 };
+        let mut glyphs: Vec<GlyphPos> = Vec::new();
+        let mut last = None;
+        let mut i = start;
+        let mut wrapped = false;
+        while i < chars.len() {
+            let c = chars[i];
+            if let Some(l) = last.take() {
+                x += metrics.kerning(l, c);
+            }
+            last = Some(c);
+            let advance = metrics.advance(c);
+            // always put at least one glyph on a line, otherwise narrow
+            // window would loop forever
+            if params.wrap && x + advance > params.width && !glyphs.is_empty() {
+                wrapped = true;
+                if params.linebreak {
+                    if let Some(b) = glyphs.iter().rposition(|g| g.ch.is_whitespace()) {
+                        glyphs.truncate(b + 1);
+                    }
+                }
+                break;
+            }
+            glyphs.push(GlyphPos { col: i, ch: c, x, advance });
+            x += advance;
+            i += 1;
+        }
+        let (end, end_x) = match glyphs.last() {
+            Some(g) => (g.col + 1, g.x + g.advance),
+            None => (start, x),
+        };
+        result.push(DisplayLine {
+            row,
+            start,
+            end,
+            continuation,
+            glyphs,
+            end_x,
+        });
+        if !wrapped {
+            break;
+        }
+        start = end;
+    }
+    result
+}
+
+/// Display lines of a buffer, cached per buffer line
+pub struct Layout {
+    params: LayoutParams,
+    cache: Vec<Option<Vec<DisplayLine>>>,
+}
+
+impl Layout {
+    pub fn new(params: LayoutParams) -> Self {
+        Layout {
+            params,
+            cache: Vec::new(),
+        }
+    }
+
+    pub fn params(&self) -> &LayoutParams {
+        &self.params
+    }
+
+    /// changes layout parameters, cache is cleared if they differ
+    pub fn set_params(&mut self, params: LayoutParams) {
+        if params != self.params {
+            self.params = params;
+            self.cache.clear();
+        }
+    }
+
+    /// drops cached layout of `row` and all rows after it, has to be called
+    /// after buffer is edited
+    pub fn invalidate_from(&mut self, row: usize) {
+        self.cache.truncate(row);
+    }
+
+    /// returns display lines of buffer line `row`
+    pub fn line(&mut self, metrics: &dyn Metrics, buff: &[String], row: usize) -> &[DisplayLine] {
+        if self.cache.len() <= row {
+            self.cache.resize(row + 1, None);
+        }
+        if self.cache[row].is_none() {
+            self.cache[row] = Some(layout_line(metrics, &self.params, row, &buff[row]));
+        }
+        self.cache[row].as_ref().unwrap()
+    }
+
+    /// returns display lines starting at buffer row `top` until `height`
+    /// pixels are filled
+    pub fn rows(&mut self, metrics: &dyn Metrics, buff: &[String], top: usize, height: f32) -> Vec<DisplayLine> {
+        let mut result = Vec::new();
+        for row in top..buff.len() {
+            if result.len() as f32 * metrics.line_height() >= height {
+                break;
+            }
+            result.extend_from_slice(self.line(metrics, buff, row));
+        }
+        result
+    }
+
+    /// returns index of display line of `row` on which is `col`
+    fn sub_line(&mut self, metrics: &dyn Metrics, buff: &[String], (row, col): (usize, usize)) -> usize {
+        let lines = self.line(metrics, buff, row);
+        let last = lines.len() - 1;
+        lines
+            .iter()
+            .enumerate()
+            .position(|(i, l)| l.contains(col, i == last))
+            .unwrap_or(0)
+    }
+
+    /// returns screen cell of character at `(row, col)` when view is scrolled
+    /// to `view_pos`, or `None` if it is above the view.
+    /// Column past the end of line is placed after last character.
+    pub fn screen_pos(
+        &mut self,
+        metrics: &dyn Metrics,
+        buff: &[String],
+        (top, left_col): (usize, usize),
+        (row, col): (usize, usize),
+    ) -> Option<Cell> {
+        if buff.is_empty() || row < top || row >= buff.len() {
+            return None;
+        }
+        let mut display_row = 0;
+        for r in top..row {
+            display_row += self.line(metrics, buff, r).len();
+        }
+        let sub = self.sub_line(metrics, buff, (row, col));
+        display_row += sub;
+        let wrap = self.params.wrap;
+        let line = &self.line(metrics, buff, row)[sub];
+
+        let col = col.min(line.end);
+        let offset = if wrap { 0.0 } else { line.scroll_offset(left_col) };
+        // cell is as wide as char in it or the previous one if we're past
+        // last char
+        let width = match line.glyphs.iter().find(|g| g.col == col).or_else(|| line.glyphs.last()) {
+            Some(g) => g.advance,
+            None => metrics.advance(' '),
+        };
+        Some(Cell {
+            x: line.x_of(col) - offset,
+            y: display_row as f32 * metrics.line_height(),
+            width,
+            height: metrics.line_height(),
+        })
+    }
+
+    /// returns buffer position `(row, col)` displayed at `(x, y)` when view is
+    /// scrolled to `view_pos`. Positions outside of text are clamped to the
+    /// nearest line or column.
+    pub fn hit_test(
+        &mut self,
+        metrics: &dyn Metrics,
+        buff: &[String],
+        (top, left_col): (usize, usize),
+        (x, y): (f32, f32),
+    ) -> (usize, usize) {
+        if buff.is_empty() {
+            return (0, 0);
+        }
+        let target = (y.max(0.0) / metrics.line_height()).floor() as usize;
+        let mut display_row = 0;
+        let mut row = top.min(buff.len() - 1);
+        loop {
+            let count = self.line(metrics, buff, row).len();
+            if display_row + count > target || row + 1 == buff.len() {
+                let wrap = self.params.wrap;
+                let lines = self.line(metrics, buff, row);
+                let line = &lines[(target - display_row).min(count - 1)];
+                let offset = if wrap { 0.0 } else { line.scroll_offset(left_col) };
+                let mut col = line.col_at(x + offset);
+                // clicking past the end of wrapped line places cursor on
+                // its last char, not on the next display line
+                if col == line.end && line.end != lines[count - 1].end {
+                    col = line.end - 1;
+                }
+                return (row, col);
+            }
+            display_row += count;
+            row += 1;
+        }
+    }
+
+    /// returns position of cursor at `(row, col)` moved by `lines` display
+    /// lines up (negative) or down, keeping its horizontal position on screen
+    pub fn move_display(
+        &mut self,
+        metrics: &dyn Metrics,
+        buff: &[String],
+        (row, col): (usize, usize),
+        lines: i32,
+    ) -> (usize, usize) {
+        if buff.is_empty() {
+            return (row, col);
+        }
+        let mut row = row.min(buff.len() - 1);
+        let mut i = self.sub_line(metrics, buff, (row, col));
+        let x = {
+            let line = &self.line(metrics, buff, row)[i];
+            line.x_of(col.min(line.end))
+        };
+
+        for _ in 0..lines.abs() {
+            let count = self.line(metrics, buff, row).len();
+            if lines > 0 {
+                if i + 1 < count {
+                    i += 1;
+                } else if row + 1 < buff.len() {
+                    row += 1;
+                    i = 0;
+                }
+            } else if i > 0 {
+                i -= 1;
+            } else if row > 0 {
+                row -= 1;
+                i = self.line(metrics, buff, row).len() - 1;
+            }
+        }
+
+        let display = self.line(metrics, buff, row);
+        let line = &display[i];
+        let mut col = line.col_at(x);
+        // don't move to the next display line
+        if col == line.end && i + 1 < display.len() {
+            col = line.end - 1;
+        }
+        (row, col)
+    }
+
+    /// returns first visible row after scrolling view with first visible row
+    /// `top` by at least `lines` display lines up (negative) or down
+    pub fn scroll(&mut self, metrics: &dyn Metrics, buff: &[String], top: usize, lines: i32) -> usize {
+        let mut top = top.min(buff.len().saturating_sub(1));
+        let mut scrolled = 0;
+        while scrolled < lines.unsigned_abs() as usize {
+            if lines > 0 && top + 1 < buff.len() {
+                scrolled += self.line(metrics, buff, top).len();
+                top += 1;
+            } else if lines < 0 && top > 0 {
+                top -= 1;
+                scrolled += self.line(metrics, buff, top).len();
+            } else {
+                break;
+            }
+        }
+        top
+    }
+
+    /// Adjusts `view_pos` (first visible row and first visible column) so the
+    /// cursor at `(row, col)` is visible in area of `height` pixels.
+    /// First visible column is only used when wrapping is off.
+    pub fn scroll_to_cursor(
+        &mut self,
+        metrics: &dyn Metrics,
+        buff: &[String],
+        (row, col): (usize, usize),
+        height: f32,
+        view_pos: &mut (usize, usize),
+    ) {
+        if buff.is_empty() {
+            return;
+        }
+        let row = row.min(buff.len() - 1);
+        let visible = ((height / metrics.line_height()).floor() as usize).max(1);
+
+        if row < view_pos.0 {
+            view_pos.0 = row;
+        }
+        // count display lines from top to the cursor, scroll down until it fits
+        let sub = self.sub_line(metrics, buff, (row, col));
+        let mut count: usize = (view_pos.0..row).map(|r| self.line(metrics, buff, r).len()).sum();
+        while count + sub >= visible && view_pos.0 < row {
+            count -= self.line(metrics, buff, view_pos.0).len();
+            view_pos.0 += 1;
+        }
+
+        if self.params.wrap {
+            view_pos.1 = 0;
+            return;
+        }
+        let width = self.params.width;
+        let line = &self.line(metrics, buff, row)[0];
+        let col = col.min(line.end);
+        if col < view_pos.1 {
+            view_pos.1 = col;
+        }
+        let cursor_width = line.glyphs.get(col).map_or(metrics.advance(' '), |g| g.advance);
+        while view_pos.1 < col && line.x_of(col) + cursor_width - line.x_of(view_pos.1) > width {
+            view_pos.1 += 1;
+        }
+    }
+}
@@ -0,0 +1,175 @@
+//! Glyphs of buffer lines cached between frames.
+//!
+//! Laying out glyphs of a line, looking up styles of its chars and fonts
+//! drawing them is done only when the line changes. Glyphs are positioned
+//! relative to top left corner of their buffer line, so scrolling only moves
+//! them. Their quads in glyph cache texture are kept too and they're valid
+//! until the texture is reorganized, which increases its generation.
+use crate::layout::LayoutParams;
+use rusttype::{GlyphId, Point};
+
+#[cfg(test)]
+mod tests {
+    use super::{CachedLine, LineCache, Quad};
+    use crate::layout::LayoutParams;
+    use rusttype::{point, Scale};
+
+    fn line(left_col: usize, highlighted: bool) -> CachedLine {
+        CachedLine::new(left_col, highlighted, Vec::new())
+    }
+
+    #[test]
+    fn test_validity() {
+        let mut cache = LineCache::new();
+        cache.set_params(&LayoutParams::default());
+        cache.insert(0, line(0, false));
+        cache.insert(3, line(0, true));
+        assert!(cache.get(0, 0, false).is_some());
+        assert!(cache.get(1, 0, false).is_none());
+        // styles arrived or view scrolled horizontally
+        assert!(cache.get(0, 0, true).is_none());
+        assert!(cache.get(3, 2, true).is_none());
+        assert!(cache.get(3, 0, true).is_some());
+
+        cache.invalidate_from(2);
+        assert!(cache.get(0, 0, false).is_some());
+        assert!(cache.get(3, 0, true).is_none());
+
+        // quads are kept only for generation of texture they were made for
+        let quad = Quad {
+            min: point(0.0, 0.0),
+            max: point(1.0, 1.0),
+            uv_min: point(0.0, 0.0),
+            uv_max: point(1.0, 1.0),
+            color: [1.0; 4],
+        };
+        let cached = cache.get(0, 0, false).unwrap();
+        cached.set_quads(1, vec![quad]);
+        assert_eq!(cached.quads(1).map(|q| q.len()), Some(1));
+        assert!(cached.quads(2).is_none());
+
+        cache.set_params(&LayoutParams {
+            scale: Scale::uniform(99.0),
+            ..LayoutParams::default()
+        });
+        assert!(cache.get(0, 0, false).is_none());
+    }
+}
+
+/// Glyph of a line, it doesn't borrow font, so it can be kept by buffer
+#[derive(Clone, Copy, Debug)]
+pub struct LineGlyph {
+    /// id of font in glyph cache
+    pub font_id: usize,
+    pub id: GlyphId,
+    /// origin of glyph relative to top left corner of line
+    pub pos: Point<f32>,
+    pub color: [f32; 4],
+}
+
+/// Glyph placed in glyph cache texture, positions are in pixels relative to
+/// top left corner of line
+#[derive(Clone, Copy, Debug)]
+pub struct Quad {
+    pub min: Point<f32>,
+    pub max: Point<f32>,
+    pub uv_min: Point<f32>,
+    pub uv_max: Point<f32>,
+    pub color: [f32; 4],
+}
+
+/// Glyphs of all display lines of one buffer line
+pub struct CachedLine {
+    /// first visible column when line was laid out without wrapping
+    left_col: usize,
+    /// whether styles of the line were known
+    highlighted: bool,
+    pub glyphs: Vec<LineGlyph>,
+    /// texture generation and quads of glyphs that are in the texture
+    quads: Option<(u64, Vec<Quad>)>,
+}
+
+impl CachedLine {
+    pub fn new(left_col: usize, highlighted: bool, glyphs: Vec<LineGlyph>) -> Self {
+        CachedLine {
+            left_col,
+            highlighted,
+            glyphs,
+            quads: None,
+        }
+    }
+
+    /// returns quads if they were made for texture generation `generation`
+    pub fn quads(&self, generation: u64) -> Option<&[Quad]> {
+        match &self.quads {
+            Some((g, quads)) if *g == generation => Some(quads),
+            _ => None,
+        }
+    }
+
+    pub fn set_quads(&mut self, generation: u64, quads: Vec<Quad>) {
+        self.quads = Some((generation, quads));
+    }
+}
+
+/// Cached glyphs of buffer lines
+pub struct LineCache {
+    params: Option<LayoutParams>,
+    lines: Vec<Option<CachedLine>>,
+}
+
+impl LineCache {
+    pub fn new() -> Self {
+        LineCache {
+            params: None,
+            lines: Vec::new(),
+        }
+    }
+
+    /// changes layout parameters, cache is cleared if they differ
+    pub fn set_params(&mut self, params: &LayoutParams) {
+        if self.params.as_ref() != Some(params) {
+            self.params = Some(params.clone());
+            self.clear();
+        }
+    }
+
+    /// drops cached glyphs of `row` and all rows after it, has to be called
+    /// after buffer is edited
+    pub fn invalidate_from(&mut self, row: usize) {
+        self.lines.truncate(row);
+    }
+
+    /// drops all lines, has to be called when colors or fonts change
+    pub fn clear(&mut self) {
+        self.lines.clear();
+    }
+
+    /// returns glyphs of `row` if they were laid out with first visible
+    /// column `left_col` and with known styles if `highlighted`
+    pub fn get(
+        &mut self,
+        row: usize,
+        left_col: usize,
+        highlighted: bool,
+    ) -> Option<&mut CachedLine> {
+        match self.lines.get_mut(row) {
+            Some(Some(line)) if line.left_col == left_col && line.highlighted == highlighted => {
+                Some(line)
+            }
+            _ => None,
+        }
+    }
+
+    /// returns glyphs of `row` whatever they were laid out for
+    pub fn line(&mut self, row: usize) -> Option<&mut CachedLine> {
+        self.lines.get_mut(row).and_then(|l| l.as_mut())
+    }
+
+    pub fn insert(&mut self, row: usize, line: CachedLine) {
+        if self.lines.len() <= row {
+            self.lines.resize_with(row + 1, || None);
+        }
+        self.lines[row] = Some(line);
+    }
+}
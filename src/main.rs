@@ -5,6 +5,8 @@ extern crate image;
 extern crate nalgebra;
 //extern crate vec_2_10_10_10;
 use std::env;
+use std::path::PathBuf;
+use std::time::Duration;
 
 use failure::err_msg;
 #[allow(unused_imports)]
@@ -12,17 +14,46 @@ use log::{debug, error, info, trace, warn};
 
 use glium::*;
 use glutin::{
-    event::{ElementState, Event, VirtualKeyCode, WindowEvent},
+    event::{
+        ElementState, Event, Ime as WindowIme, MouseButton, MouseScrollDelta, StartCause,
+        WindowEvent,
+    },
     event_loop::ControlFlow,
 };
 
-use crate::buffer::TextBuffer;
+use crate::editor::{Editor, Ime, Input, Mouse};
+use crate::font::Fonts;
+use crate::layout::{FontMetrics, Metrics};
 use crate::debug::failure_to_string;
+use crate::key::Key;
+use crate::keyboard::Keyboard;
+use crate::options::Options;
+use crate::replay::{Frontend, Player, Recorder, Recording, Session};
 
 mod buffer;
+mod command;
+mod config;
 mod cursor;
 mod debug;
+mod editor;
+mod font;
+#[cfg(test)]
+mod harness;
+#[cfg(target_os = "linux")]
+mod headless;
+mod highlight;
+mod indent;
+mod key;
+mod keyboard;
+mod keymap;
+mod layout;
+mod line_cache;
+mod options;
+mod rects;
 mod renderer;
+mod replay;
+mod syntax;
+mod tui;
 mod window;
 
 /// Struct representing either Absolute position or relative position to e.g. cursor
@@ -38,51 +69,178 @@ pub enum PositionError {
     InvalidRelative,
 }
 
-enum State {
-    Normal,
-    Insert,
-    Visual,
-    Command,
+/// part of window width used by text
+const TEXT_WIDTH: f32 = 0.5;
+
+/// Events sent to event loop from other threads
+#[derive(Debug)]
+pub enum UserEvent {
+    /// config file was modified
+    ConfigChanged,
+    /// highlighting thread has results
+    Highlighted,
 }
 
 fn main() {
     env_logger::init();
 
-    let app = App::new();
-
-    if let Err(e) = app.sietor() {
+    let args: Vec<_> = env::args_os().skip(1).collect();
+    #[cfg(target_os = "linux")]
+    {
+        if matches!(args.first(), Some(a) if a == "--screenshot") {
+            if let Err(e) = screenshot(&args[1..]) {
+                error!("{}", failure_to_string(e));
+            }
+            return;
+        }
+    }
+    if let Err(e) = run(args) {
         error!("{}", failure_to_string(e));
     }
 }
 
-struct App<'a> {
-    buffers: Vec<TextBuffer<'a>>,
-    active_buffer: usize,
-    state: State,
+/// Runs frontend selected by `args`: `[--tui] [--record session.toml]
+/// [files]` or `--replay session.toml [--speed N] [--headless]`
+fn run(args: Vec<std::ffi::OsString>) -> Result<(), failure::Error> {
+    let mut tui = false;
+    let mut record = None;
+    let mut replay = None;
+    let mut speed = 1.0;
+    let mut headless = false;
+    let mut files = Vec::new();
+    let mut args = args.into_iter();
+    while let Some(arg) = args.next() {
+        match arg.to_str() {
+            Some("--tui") => tui = true,
+            Some("--headless") => headless = true,
+            Some("--record") => match args.next() {
+                Some(path) => record = Some(PathBuf::from(path)),
+                None => bail!("Usage: sietor --record session.toml [files]"),
+            },
+            Some("--replay") => match args.next() {
+                Some(path) => replay = Some(PathBuf::from(path)),
+                None => bail!("Usage: sietor --replay session.toml [--speed N] [--headless]"),
+            },
+            Some("--speed") => {
+                speed = match args.next().and_then(|s| s.to_str()?.parse().ok()) {
+                    Some(speed) => speed,
+                    None => bail!("--speed needs a number"),
+                }
+            }
+            _ => files.push(PathBuf::from(arg)),
+        }
+    }
+
+    let path = match replay {
+        Some(path) => path,
+        None if tui => return tui::run(Session::Edit { files, record }),
+        None => return App::new().sietor(Session::Edit { files, record }),
+    };
+    let rec = Recording::load(&path)?;
+    if headless {
+        let editor = replay::replay_headless(&rec)?;
+        let buff = &editor.buffers[editor.active_buffer];
+        let (row, col) = buff.cursor.text_pos;
+        println!("{:?} mode, cursor {}:{}", editor.state, row + 1, col + 1);
+        println!("{}", buff.buffer.join("\n"));
+        return Ok(());
+    }
+    match rec.frontend {
+        Frontend::Terminal => tui::run(Session::Replay(rec, speed)),
+        Frontend::Window => App::new().sietor(Session::Replay(rec, speed)),
+    }
+}
+
+/// `--screenshot out.png [file]`, renders file without window
+#[cfg(target_os = "linux")]
+fn screenshot(args: &[std::ffi::OsString]) -> Result<(), failure::Error> {
+    use std::path::Path;
+
+    let out = match args.first() {
+        Some(out) => Path::new(out),
+        None => bail!("Usage: sietor --screenshot out.png [file]"),
+    };
+    let opts = match config::config_path() {
+        Some(path) => config::load(&path)?,
+        None => Options::default(),
+    };
+    headless::screenshot(out, args.get(1).map(Path::new), &opts)
+}
+
+/// Window frontend of [Editor]
+struct App {
+    editor: Editor,
+    /// options currently used by renderer, window and buffers
+    applied: Options,
+    keyboard: Keyboard,
+    /// replayed session, user input is ignored until it finishes
+    player: Option<Player>,
+    /// position of mouse in window in pixels
+    mouse_pos: (f32, f32),
+    /// left mouse button is held
+    mouse_pressed: bool,
+    /// wheel steps of smooth scrolling not yet applied
+    wheel_lines: f32,
 }
 
-impl App<'static> {
-    fn sietor(mut self) -> Result<(), failure::Error> {
+impl App {
+    fn sietor(mut self, session: Session) -> Result<(), failure::Error> {
         if cfg!(target_os = "linux") && env::var("WINIT_UNIX_BACKEND").is_err() {
             env::set_var("WINIT_UNIX_BACKEND", "x11");
         }
 
+        let mut config_path = None;
+        let (files, record) = match session {
+            Session::Edit { files, record } => {
+                config_path = self.editor.load_config();
+                (files, record)
+            }
+            Session::Replay(rec, speed) => {
+                self.editor = rec.editor()?;
+                self.editor.options.window_size = rec.size;
+                self.player = Some(Player::new(&rec, speed)?);
+                (vec![], None)
+            }
+        };
+
         trace!("Opening a window");
-        let win = window::WindowContext::new("Sietor").map_err(err_msg)?;
+        let win = window::WindowContext::new("Sietor", self.editor.options.window_size).map_err(err_msg)?;
         let disp = win.display.clone();
-        let mut rend = renderer::Renderer::new(&win)?;
-
-        let buff = TextBuffer::new(
-            buffer::BufferOrigin::Buffer(
-                "Welcome to Sietor
-idk, welcome text"
-                    .to_string(),
-            ),
-            None,
-            None,
-            disp.gl_window().window().scale_factor() as f32,
-        );
-        self.buffers.push(buff);
+        let proxy = win.event_loop.create_proxy();
+        let scale = disp.gl_window().window().scale_factor();
+        // composed text comes as `WindowEvent::Ime`
+        disp.gl_window().window().set_ime_allowed(true);
+        let mut rend = renderer::Renderer::new(&disp, scale, move || {
+            let _ = proxy.send_event(UserEvent::Highlighted);
+        })?;
+        self.applied.window_size = self.editor.options.window_size;
+
+        if let Some(path) = config_path.clone() {
+            let proxy = win.event_loop.create_proxy();
+            config::watch(path, move || {
+                let _ = proxy.send_event(UserEvent::ConfigChanged);
+            });
+        }
+
+        let scale_factor = disp.gl_window().window().scale_factor() as f32;
+        if self.player.is_none() {
+            self.editor.open_files(&files, scale_factor);
+        }
+        if let Some(path) = record {
+            let size = disp.gl_window().window().inner_size();
+            self.editor.recorder = Some(Recorder::start(
+                &path,
+                &self.editor,
+                config_path.as_deref(),
+                Frontend::Window,
+                (size.width, size.height),
+                scale_factor,
+            )?);
+        }
+        // renderer is created with built-in font only
+        self.applied.font.clear();
+        self.applied.fallback.clear();
+        self.sync_options(&mut rend, &disp);
 
         win.event_loop.run(move |event, _, control_flow| {
             // ControlFlow::Wait pauses the event loop if no events are available to process.
@@ -96,48 +254,146 @@ idk, welcome text"
                     ..
                 } => *control_flow = ControlFlow::Exit,
 
+                Event::WindowEvent {
+                    event: WindowEvent::ModifiersChanged(mods),
+                    ..
+                } => self.keyboard.modifiers_changed(mods),
+
+                Event::WindowEvent {
+                    event:
+                        WindowEvent::KeyboardInput { .. }
+                        | WindowEvent::ReceivedCharacter(_)
+                        | WindowEvent::MouseInput { .. }
+                        | WindowEvent::MouseWheel { .. }
+                        | WindowEvent::Ime(_),
+                    ..
+                } if self.player.is_some() => {}
+
                 Event::WindowEvent {
                     event: WindowEvent::KeyboardInput { input, .. },
                     ..
                 } => {
-                    if input.state == ElementState::Pressed {
-                        match input.virtual_keycode {
-                            Some(VirtualKeyCode::Left) => {
-                                self.buffers[self.active_buffer].move_cursor_relative(0, -1);
-                            }
-                            Some(VirtualKeyCode::Right) => {
-                                self.buffers[self.active_buffer].move_cursor_relative(0, 1);
-                            }
-                            Some(VirtualKeyCode::Up) => {
-                                self.buffers[self.active_buffer].move_cursor_relative(-1, 0);
-                            }
-                            Some(VirtualKeyCode::Down) => {
-                                self.buffers[self.active_buffer].move_cursor_relative(1, 0);
-                            }
-                            None => {}
-                            _ => {}
-                        }
+                    self.keyboard.key_input(input.state, input.virtual_keycode);
+                    self.process_keys(&mut rend, &disp);
+                }
+
+                Event::WindowEvent {
+                    event: WindowEvent::ReceivedCharacter(c),
+                    ..
+                } => {
+                    self.keyboard.received_char(c);
+                    self.process_keys(&mut rend, &disp);
+                }
+
+                Event::WindowEvent {
+                    event: WindowEvent::Ime(ime),
+                    ..
+                } => {
+                    let ime = match ime {
+                        WindowIme::Preedit(text, cursor) => Some(Ime::Preedit(text, cursor.map(|(start, _)| start))),
+                        WindowIme::Commit(text) => Some(Ime::Commit(text)),
+                        WindowIme::Disabled => Some(Ime::Preedit(String::new(), None)),
+                        WindowIme::Enabled => None,
+                    };
+                    if let Some(ime) = ime {
+                        self.editor.apply(Input::Ime(ime), &self.metrics(rend.fonts()));
+                        self.sync_options(&mut rend, &disp);
                         win.display.gl_window().window().request_redraw();
                     }
                 }
 
                 Event::WindowEvent {
-                    event: WindowEvent::ReceivedCharacter(c),
+                    event: WindowEvent::CursorMoved { position, .. },
+                    ..
+                } => {
+                    self.mouse_pos = (position.x as f32, position.y as f32);
+                    if self.mouse_pressed && self.player.is_none() {
+                        let (x, y) = self.mouse_pos;
+                        self.editor.apply(Input::Mouse(Mouse::Drag(x, y)), &self.metrics(rend.fonts()));
+                        win.display.gl_window().window().request_redraw();
+                    }
+                }
+
+                Event::WindowEvent {
+                    event: WindowEvent::MouseInput { state, button: MouseButton::Left, .. },
+                    ..
+                } => {
+                    self.mouse_pressed = state == ElementState::Pressed;
+                    if self.mouse_pressed {
+                        let (x, y) = self.mouse_pos;
+                        self.editor.apply(Input::Mouse(Mouse::Press(x, y)), &self.metrics(rend.fonts()));
+                        win.display.gl_window().window().request_redraw();
+                    }
+                }
+
+                Event::WindowEvent {
+                    event: WindowEvent::MouseWheel { delta, .. },
                     ..
                 } => {
-                    self.process_input(c);
-                    match c {
-                        '\u{8}' => {
-                            //         text.pop();
+                    let metrics = self.metrics(rend.fonts());
+                    // wheel up scrolls towards start of buffer, touchpads
+                    // scroll by pixels, `mousescroll` lines are one step
+                    self.wheel_lines -= match delta {
+                        MouseScrollDelta::LineDelta(_, steps) => steps,
+                        MouseScrollDelta::PixelDelta(pos) => {
+                            let step = metrics.line_height() * self.editor.options.mousescroll.max(1) as f32;
+                            pos.y as f32 / step
                         }
-                        _ if c != '\u{7f}' => {
-                            //text.push(c)
+                    };
+                    let steps = self.wheel_lines.trunc();
+                    if steps != 0.0 {
+                        self.wheel_lines -= steps;
+                        self.editor.apply(Input::Mouse(Mouse::Wheel(steps as i32)), &metrics);
+                        win.display.gl_window().window().request_redraw();
+                    }
+                }
+
+                Event::NewEvents(StartCause::ResumeTimeReached { .. }) if self.player.is_some() => {
+                    self.replay(&mut rend, &disp);
+                    win.display.gl_window().window().request_redraw();
+                }
+
+                Event::NewEvents(StartCause::ResumeTimeReached { .. }) => {
+                    // mapping timeout expired
+                    self.editor.apply(Input::Tick, &self.metrics(rend.fonts()));
+                    self.sync_options(&mut rend, &disp);
+                    win.display.gl_window().window().request_redraw();
+                }
+
+                Event::UserEvent(UserEvent::ConfigChanged) => {
+                    if let Some(path) = &config_path {
+                        match config::load(path) {
+                            Ok(opts) => {
+                                info!("Reloaded config {:?}", path);
+                                self.editor.options = opts;
+                                self.sync_options(&mut rend, &disp);
+                            }
+                            Err(e) => error!("{}", failure_to_string(e)),
                         }
-                        _ => {}
                     }
                     win.display.gl_window().window().request_redraw();
                 }
 
+                Event::UserEvent(UserEvent::Highlighted) => {
+                    for done in rend.highlight_results() {
+                        let id = done.id();
+                        let buff = self.editor.buffers.iter().find(|b| b.highlight.borrow().id() == id);
+                        if let Some(buff) = buff {
+                            if buff.highlight.borrow_mut().apply(done) {
+                                win.display.gl_window().window().request_redraw();
+                            }
+                        }
+                    }
+                }
+
+                Event::WindowEvent {
+                    event: WindowEvent::Resized(size),
+                    ..
+                } if self.player.is_none() => {
+                    let input = Input::Resize(size.width, size.height);
+                    self.editor.apply(input, &self.metrics(rend.fonts()));
+                }
+
                 /*
                     Event::WindowEvent {
                     event: WindowEvent::Resized(size),
@@ -149,7 +405,9 @@ idk, welcome text"
                 }
                      */
                 Event::MainEventsCleared => {
-                    // Application update code.
+                    // key pressed with Ctrl may have no character
+                    self.keyboard.flush();
+                    self.process_keys(&mut rend, &disp);
                 }
                 Event::RedrawRequested(_) => {
                     // Redraw the application.
@@ -160,88 +418,144 @@ idk, welcome text"
                     //
                     // Could be usefull for gui
                     //                buf.draw(0.0, 0.0, 0.0, 0.0);
-                    rend.draw(
-                        0.0,
-                        0.0,
-                        0.5,
-                        24.0,
-                        &disp,
-                        &mut self.buffers[self.active_buffer],
-                    );
+                    let view = self.editor.view();
+                    rend.draw(0.0, 0.0, TEXT_WIDTH, view.options, &disp, view.buffer, view.preedit);
+                    self.set_ime_position(&disp);
                 }
                 _ => (),
             }
+
+            let deadline = match &self.player {
+                Some(player) => player.deadline(),
+                None => self.editor.deadline(),
+            };
+            if let Some(deadline) = deadline {
+                *control_flow = ControlFlow::WaitUntil(deadline);
+            }
+            if self.editor.quit {
+                *control_flow = ControlFlow::Exit;
+            }
         });
     }
 
-    fn process_input(&mut self, ch: char) {
-        match self.state {
-            State::Normal => match ch {
-                'i' => {
-                    self.state = State::Insert;
-                    trace!("Switched to Insert mode");
-                }
-                'v' => {
-                    self.state = State::Visual;
-                    trace!("Switched to Visual mode");
-                }
-                ':' => {
-                    self.state = State::Command;
-                    trace!("Switched to Command mode");
-                }
-                'h' => self.buffers[self.active_buffer].move_cursor_relative(0, -1),
-                'j' => self.buffers[self.active_buffer].move_cursor_relative(1, 0),
-                'k' => self.buffers[self.active_buffer].move_cursor_relative(-1, 0),
-                'l' => self.buffers[self.active_buffer].move_cursor_relative(0, 1),
-                _ => {
-                    trace!("Unprocessed state result. Input {:?} in Normal mode", ch);
-                }
-            },
-            State::Insert => match ch {
-                '\u{1b}' => {
-                    self.state = State::Normal;
-                    trace!("Switched to Normal mode");
-                }
-                '\u{8}' => {
-                    self.buffers[self.active_buffer].delete(None, None);
-                }
-                '\t' => {
-                    for i in 0..4 {
-                        trace!("Inserting {:?}", i);
-                        self.buffers[self.active_buffer].insert(' ', None);
+    /// processes keys converted by [Keyboard], redraws window if there were
+    /// any
+    fn process_keys(&mut self, rend: &mut renderer::Renderer<'static>, disp: &Display) {
+        let keys: Vec<Key> = self.keyboard.keys().collect();
+        for key in &keys {
+            self.editor.apply(Input::Key(*key), &self.metrics(rend.fonts()));
+            self.sync_options(rend, disp);
+        }
+        if !keys.is_empty() {
+            disp.gl_window().window().request_redraw();
+        }
+    }
+
+    /// Processes inputs of replayed session that are due, resizes window to
+    /// recorded size
+    fn replay(&mut self, rend: &mut renderer::Renderer<'static>, disp: &Display) {
+        let mut player = match self.player.take() {
+            Some(player) => player,
+            None => return,
+        };
+        while let Some((input, at)) = player.next(std::time::Instant::now()) {
+            if let Input::Resize(width, height) = input {
+                disp.gl_window()
+                    .window()
+                    .set_inner_size(glutin::dpi::PhysicalSize::new(width, height));
+            }
+            self.editor.apply_at(input, at, &self.metrics(rend.fonts()));
+            self.sync_options(rend, disp);
+        }
+        if player.deadline().is_some() {
+            self.player = Some(player);
+        } else {
+            info!("Replay finished");
+        }
+    }
+
+    /// Places candidate window of input method under the cursor, it has
+    /// to be called after drawing, which updates position of the cursor
+    fn set_ime_position(&self, disp: &Display) {
+        let window = disp.gl_window();
+        let window = window.window();
+        let size = window.inner_size();
+        let cursor = &self.editor.view().buffer.cursor;
+        let (x, y) = cursor.screen_pos.get();
+        let x = (x + 1.0) * size.width as f32 / 2.0;
+        let y = (1.0 - y) * size.height as f32 / 2.0 + cursor.height.get();
+        window.set_ime_position(glutin::dpi::PhysicalPosition::new(x, y));
+    }
+
+    /// returns metrics of `fonts` at size of active buffer
+    fn metrics<'f>(&self, fonts: &'f Fonts<'static>) -> FontMetrics<'f, 'static> {
+        let scale = self.editor.view().buffer.layout.borrow().params().scale;
+        FontMetrics::new(fonts, scale)
+    }
+
+    /// Applies options that changed since last call to renderer, window and
+    /// buffers. If option can't be applied, it's reverted to previous value.
+    fn sync_options(&mut self, rend: &mut renderer::Renderer<'static>, disp: &Display) {
+        let editor = &mut self.editor;
+        if editor.options.theme != self.applied.theme {
+            match rend.set_theme(&editor.options.theme) {
+                Ok(()) => {
+                    for buff in &mut editor.buffers {
+                        buff.highlight.get_mut().clear();
+                        buff.glyphs.get_mut().clear();
                     }
                 }
-                _ => {
-                    trace!("Inserting {:?}", ch);
-                    self.buffers[self.active_buffer].insert(ch, None);
-                }
-            },
-            State::Visual => match ch {
-                '\u{1b}' => {
-                    self.state = State::Normal;
-                    trace!("Switched to Normal mode");
+                Err(e) => {
+                    error!("{}", failure_to_string(e));
+                    editor.options.theme = self.applied.theme.clone();
                 }
-                _ => {
-                    trace!("Unprocessed state result. Input {:?} in Visual mode", ch);
-                }
-            },
-            State::Command => match ch {
-                '\u{1b}' => {
-                    self.state = State::Normal;
-                    trace!("Switched to Normal mode");
+            }
+        }
+        if editor.options.font != self.applied.font
+            || editor.options.fallback != self.applied.fallback
+        {
+            match Fonts::load(&editor.options.font, &editor.options.fallback) {
+                Ok(fonts) => {
+                    rend.set_fonts(fonts);
+                    for buff in &mut editor.buffers {
+                        buff.layout.get_mut().invalidate_from(0);
+                        buff.glyphs.get_mut().clear();
+                    }
                 }
-                _ => {
-                    trace!("Unprocessed state result. Input {:?} in Command mode", ch);
+                Err(e) => {
+                    error!("{}", failure_to_string(e));
+                    // neither font nor fallback was loaded, options have to match
+                    // loaded fonts
+                    editor.options.font = self.applied.font.clone();
+                    editor.options.fallback = self.applied.fallback.clone();
                 }
-            },
+            }
+        }
+        if editor.options.window_size != self.applied.window_size {
+            let (width, height) = editor.options.window_size;
+            disp.gl_window()
+                .window()
+                .set_inner_size(glutin::dpi::PhysicalSize::new(width, height));
+        }
+        rend.set_font_size(editor.font_size());
+        if let Some(path) = editor.atlas_dump.take() {
+            if let Err(e) = rend.dump_atlas(&path) {
+                error!("{}", failure_to_string(e));
+            }
         }
+        editor.keymap.timeout = Duration::from_millis(editor.options.timeoutlen);
+        self.applied = editor.options.clone();
     }
 
     fn new() -> Self {
         App {
-            active_buffer: 0,
-            buffers: vec![],
-            state: State::Normal,
+            editor: Editor::new(),
+            applied: Options::default(),
+            keyboard: Keyboard::default(),
+            player: None,
+            mouse_pos: (0.0, 0.0),
+            mouse_pressed: false,
+            wheel_lines: 0.0,
         }
     }
 }
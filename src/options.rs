@@ -0,0 +1,160 @@
+use crate::indent::{opening, Rules};
+use std::collections::HashMap;
+
+/// Editor options, loaded from [config file](crate::config) and changed with `:set`
+#[derive(Clone, Debug, PartialEq)]
+pub struct Options {
+    /// wrap long lines, when off text is scrolled horizontaly
+    pub wrap: bool,
+    /// wrap long lines at word boundary
+    pub linebreak: bool,
+    /// string displayed at start of wrapped lines
+    pub showbreak: String,
+    /// number of spaces inserted by tab
+    pub tabstop: usize,
+    /// font family name or path to font file, empty is built-in font
+    pub font: String,
+    /// fonts used for chars missing in `font`, family names, paths or file
+    /// names of installed fonts
+    pub fallback: Vec<String>,
+    /// font size in points
+    pub font_size: f32,
+    /// name of syntax highlighting theme
+    pub theme: String,
+    /// size of window in pixels
+    pub window_size: (u32, u32),
+    /// time in milliseconds to wait for next key of mapped sequence
+    pub timeoutlen: u64,
+    /// number of display lines scrolled by one step of mouse wheel
+    pub mousescroll: usize,
+    /// new line gets indentation of previous line
+    pub autoindent: bool,
+    /// new line is indented by rules of buffer language
+    pub smartindent: bool,
+    /// smartindent rules of languages by syntax name, override built-in ones
+    pub indent: HashMap<String, Rules>,
+}
+
+impl Default for Options {
+    fn default() -> Self {
+        Options {
+            wrap: true,
+            linebreak: false,
+            showbreak: String::new(),
+            tabstop: 4,
+            font: String::new(),
+            fallback: [
+                "DejaVuSansMono.ttf",
+                "DejaVuSans.ttf",
+                "NotoSansMono-Regular.ttf",
+                "NotoSansCJK-Regular.ttc",
+                "NotoSansSymbols2-Regular.ttf",
+                "NotoEmoji-Regular.ttf",
+                "Symbola.ttf",
+            ]
+            .iter()
+            .map(|f| f.to_string())
+            .collect(),
+            font_size: 24.0,
+            theme: "base16-ocean.dark".to_string(),
+            window_size: (512, 512),
+            timeoutlen: 1000,
+            mousescroll: 3,
+            autoindent: true,
+            smartindent: true,
+            indent: HashMap::new(),
+        }
+    }
+}
+
+/// limit of zoom steps in both directions
+pub const MAX_ZOOM: i32 = 20;
+
+/// Returns font size `size` zoomed in by `zoom` steps (or zoomed out if
+/// negative), every step is 10%
+pub fn zoomed_size(size: f32, zoom: i32) -> f32 {
+    (size * 1.1f32.powi(zoom.clamp(-MAX_ZOOM, MAX_ZOOM))).clamp(1.0, 512.0)
+}
+
+/// parses value of numeric option
+fn parse<T: std::str::FromStr>(name: &str, value: &str) -> Result<T, failure::Error> {
+    value
+        .parse()
+        .map_err(|_| format_err!("Invalid value \"{}\" of option \"{}\"", value, name))
+}
+
+impl Options {
+    /// Processes one argument of `:set`, which can be `name`, `noname`,
+    /// `invname` or `name=value`
+    pub fn set(&mut self, arg: &str) -> Result<(), failure::Error> {
+        if let Some((name, value)) = arg.split_once('=') {
+            let mut new = self.clone();
+            match name {
+                "showbreak" | "sbr" => new.showbreak = value.to_string(),
+                "tabstop" | "ts" => new.tabstop = parse(name, value)?,
+                "font" => new.font = value.to_string(),
+                "fallback" => {
+                    new.fallback = value
+                        .split(',')
+                        .filter(|f| !f.is_empty())
+                        .map(|f| f.to_string())
+                        .collect()
+                }
+                "fontsize" => new.font_size = parse(name, value)?,
+                "theme" => new.theme = value.to_string(),
+                "windowwidth" => new.window_size.0 = parse(name, value)?,
+                "windowheight" => new.window_size.1 = parse(name, value)?,
+                "timeoutlen" | "tm" => new.timeoutlen = parse(name, value)?,
+                "mousescroll" => new.mousescroll = parse(name, value)?,
+                "wrap" | "linebreak" | "lbr" | "autoindent" | "ai" | "smartindent" | "si" => {
+                    bail!("Option \"{}\" is a boolean, use \"{}\" or \"no{}\"", name, name, name)
+                }
+                _ => bail!("Unknown option \"{}\"", name),
+            };
+            new.validate()?;
+            *self = new;
+            return Ok(());
+        }
+
+        let (name, value) = if let Some(name) = arg.strip_prefix("no") {
+            (name, Some(false))
+        } else if let Some(name) = arg.strip_prefix("inv") {
+            (name, None)
+        } else {
+            (arg, Some(true))
+        };
+
+        let opt = match name {
+            "wrap" => &mut self.wrap,
+            "linebreak" | "lbr" => &mut self.linebreak,
+            "autoindent" | "ai" => &mut self.autoindent,
+            "smartindent" | "si" => &mut self.smartindent,
+            _ => bail!("Unknown option \"{}\"", arg),
+        };
+        *opt = value.unwrap_or(!*opt);
+        Ok(())
+    }
+
+    /// checks that values are in allowed range
+    pub fn validate(&self) -> Result<(), failure::Error> {
+        if self.tabstop == 0 || self.tabstop > 32 {
+            bail!("tabstop must be between 1 and 32, not {}", self.tabstop);
+        }
+        if !(self.font_size >= 1.0 && self.font_size <= 512.0) {
+            bail!("font size must be between 1 and 512, not {}", self.font_size);
+        }
+        if self.window_size.0 == 0 || self.window_size.1 == 0 {
+            bail!(
+                "window size must not be zero, is {}x{}",
+                self.window_size.0,
+                self.window_size.1
+            );
+        }
+        for (lang, rules) in &self.indent {
+            if let Some(c) = rules.close.chars().find(|c| opening(*c).is_none()) {
+                bail!("{:?} is not a closing bracket in indent rules of {}", c, lang);
+            }
+        }
+        Ok(())
+    }
+}
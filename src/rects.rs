@@ -0,0 +1,199 @@
+//! Batched drawing of solid rectangles.
+//!
+//! Rectangles pushed during a frame are drawn by one instanced draw call. The
+//! shader rounds their corners and draws their borders using distance from
+//! the rectangle edge, which also antialiases rounded corners.
+use crate::renderer::{ColorRGBA, Rect};
+use glium::backend::Facade;
+use glium::*;
+#[allow(unused_imports)]
+use log::{debug, error, info, trace, warn};
+
+/// Look of rectangle besides its fill color
+#[derive(Clone, Copy, Debug)]
+pub struct RectStyle {
+    /// radius of corners in pixels
+    pub radius: f32,
+    /// width of border in pixels, border is drawn inside of rectangle
+    pub border: f32,
+    pub border_color: ColorRGBA,
+}
+
+impl Default for RectStyle {
+    fn default() -> Self {
+        RectStyle {
+            radius: 0.0,
+            border: 0.0,
+            border_color: ColorRGBA::new(),
+        }
+    }
+}
+
+/// corner of unit square, instances stretch it over their rectangles
+#[derive(Copy, Clone)]
+struct Corner {
+    corner: [f32; 2],
+}
+
+implement_vertex!(Corner, corner);
+
+/// one rectangle, coordinates are in pixels
+#[derive(Copy, Clone, Debug)]
+struct RectInstance {
+    /// top left and bottom right corner
+    rect: [f32; 4],
+    color: [f32; 4],
+    border_color: [f32; 4],
+    radius: f32,
+    border: f32,
+}
+
+implement_vertex!(RectInstance, rect, color, border_color, radius, border);
+
+/// Rectangles collected during a frame
+pub struct RectBatch {
+    program: Program,
+    corners: VertexBuffer<Corner>,
+    rects: Vec<RectInstance>,
+    /// instances of rectangles, reused by frames until more space is needed
+    instances: Option<VertexBuffer<RectInstance>>,
+}
+
+impl RectBatch {
+    pub fn new<F: Facade>(facade: &F) -> Result<Self, failure::Error> {
+        trace!("Compiling rectangle shader");
+        let program = program!(
+        facade,
+        140 => {
+            vertex: "
+#version 140
+
+uniform vec2 screen;
+
+in vec2 corner;
+in vec4 rect;
+in vec4 color;
+in vec4 border_color;
+in float radius;
+in float border;
+
+out vec2 v_pos;
+out vec2 v_half;
+out vec4 v_color;
+out vec4 v_border_color;
+out float v_radius;
+out float v_border;
+
+void main() {
+    vec2 pos = mix(rect.xy, rect.zw, corner);
+    // fragment position is relative to center of rectangle
+    v_half = abs(rect.zw - rect.xy) * 0.5;
+    v_pos = pos - (rect.xy + rect.zw) * 0.5;
+    v_color = color;
+    v_border_color = border_color;
+    v_radius = min(radius, min(v_half.x, v_half.y));
+    v_border = border;
+    gl_Position = vec4(pos.x / screen.x * 2.0 - 1.0, 1.0 - pos.y / screen.y * 2.0, 0.0, 1.0);
+}
+            ",
+            fragment: "
+#version 140
+
+in vec2 v_pos;
+in vec2 v_half;
+in vec4 v_color;
+in vec4 v_border_color;
+in float v_radius;
+in float v_border;
+
+out vec4 f_color;
+
+// signed distance from edge of rounded rectangle, negative inside
+float edge_distance(vec2 pos, vec2 half_size, float radius) {
+    vec2 q = abs(pos) - half_size + radius;
+    return length(max(q, 0.0)) + min(max(q.x, q.y), 0.0) - radius;
+}
+
+void main() {
+    float d = edge_distance(v_pos, v_half, v_radius);
+    vec4 color = v_color;
+    if (v_border > 0.0) {
+        color = mix(v_border_color, v_color, clamp(0.5 - (d + v_border), 0.0, 1.0));
+    }
+    f_color = vec4(color.rgb, color.a * clamp(0.5 - d, 0.0, 1.0));
+}
+                        "
+        })?;
+        let corners = VertexBuffer::new(
+            facade,
+            &[
+                Corner { corner: [0.0, 0.0] },
+                Corner { corner: [1.0, 0.0] },
+                Corner { corner: [0.0, 1.0] },
+                Corner { corner: [1.0, 1.0] },
+            ],
+        )?;
+        Ok(RectBatch {
+            program,
+            corners,
+            rects: Vec::new(),
+            instances: None,
+        })
+    }
+
+    /// adds rectangle filled with `color`
+    pub fn push(&mut self, rect: &Rect, color: ColorRGBA) {
+        self.push_styled(rect, color, RectStyle::default());
+    }
+
+    /// adds rectangle with rounded corners or border
+    pub fn push_styled(&mut self, rect: &Rect, color: ColorRGBA, style: RectStyle) {
+        let (x1, y1, x2, y2) = rect.to_px();
+        self.rects.push(RectInstance {
+            rect: [x1, y1, x2, y2],
+            color: color.as_arr(),
+            border_color: style.border_color.as_arr(),
+            radius: style.radius,
+            border: style.border,
+        });
+    }
+
+    /// draws all pushed rectangles in the order they were pushed and clears
+    /// the batch
+    pub fn draw<F: Facade, S: Surface>(&mut self, facade: &F, target: &mut S) {
+        if self.rects.is_empty() {
+            return;
+        }
+        let too_small = match &self.instances {
+            Some(vb) => vb.len() < self.rects.len(),
+            None => true,
+        };
+        if too_small {
+            let size = self.rects.len().next_power_of_two().max(256);
+            trace!("Allocating instance buffer for {} rectangles", size);
+            self.instances = Some(VertexBuffer::empty_dynamic(facade, size).unwrap());
+        }
+        let instances = self.instances.as_ref().unwrap();
+        instances.invalidate();
+        let slice = instances.slice(0..self.rects.len()).unwrap();
+        slice.write(&self.rects);
+
+        let (width, height) = target.get_dimensions();
+        let uniforms = uniform! {
+            screen: [width as f32, height as f32],
+        };
+        target
+            .draw(
+                (&self.corners, slice.per_instance().unwrap()),
+                glium::index::NoIndices(glium::index::PrimitiveType::TriangleStrip),
+                &self.program,
+                &uniforms,
+                &glium::DrawParameters {
+                    blend: glium::Blend::alpha_blending(),
+                    ..Default::default()
+                },
+            )
+            .unwrap();
+        self.rects.clear();
+    }
+}
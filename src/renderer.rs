@@ -1,24 +1,52 @@
 use crate::buffer::TextBuffer;
-use crate::window;
+use crate::editor::Preedit;
+use crate::config;
+use crate::font::{Face, FontRegistry, Fonts};
+use crate::highlight::{Done, Worker};
+use crate::layout::{DisplayLine, FontMetrics, LayoutParams, Metrics};
+use crate::line_cache::{CachedLine, LineGlyph, Quad};
+use crate::options::Options;
+use crate::rects::RectBatch;
+use glium::backend::Facade;
 use glium::*;
 #[allow(unused_imports)]
 use log::{debug, error, info, trace, warn};
-use rusttype::gpu_cache::Cache;
-use rusttype::{point, vector, Font, PositionedGlyph, Scale};
+use rusttype::gpu_cache::{Cache, CacheWriteErr, CachedBy};
+use rusttype::{point, Scale};
 use std::borrow::Cow;
-use syntect::highlighting::{ThemeSet, Highlighter};
-use syntect::parsing::SyntaxSet;
+use std::path::Path;
+use std::sync::mpsc::TryIter;
+use std::sync::Arc;
+use syntect::highlighting::{Color, FontStyle, Style, Theme, ThemeSet};
+
+/// Sets layout parameters of `buff` for text area `width` × `height` pixels
+/// and scrolls it to cursor, like [Renderer::render] does before drawing.
+/// `scale_dis` is scale factor of the screen.
+pub fn scroll_to_cursor(
+    fonts: &FontRegistry,
+    opts: &Options,
+    scale_dis: f32,
+    width: f32,
+    height: f32,
+    buff: &TextBuffer,
+) {
+    let params = LayoutParams::new(opts, Scale::uniform(fonts.size(buff.font_size) * scale_dis), width);
+    let metrics = FontMetrics::new(fonts.fonts(), params.scale);
+    let mut layout = buff.layout.borrow_mut();
+    layout.set_params(params);
+    let mut view_pos = buff.view_pos.get();
+    layout.scroll_to_cursor(&metrics, &buff.buffer, buff.cursor.text_pos, height, &mut view_pos);
+    buff.view_pos.set(view_pos);
+}
 
-/// Intented to represent size on screen, can be either normalized (e.g. 0.0 is
-/// center, 1.0 is right and -1.0 is left of screen)
+/// Intented to represent size on screen in pixels
 #[derive(Clone, Copy, Debug)]
 pub enum ScreenSize {
-    Normalized(f32),
     Px(i32),
 }
 /// Struct representing on screen rectangle
 /// Has `x` and `y` position, `width` and `height`
-/// all values are of type [ScreenSize](ScreenSize) which can be either normalized or absolute
+/// all values are of type [ScreenSize](ScreenSize)
 #[derive(Debug)]
 pub struct Rect {
     x: ScreenSize,
@@ -28,58 +56,26 @@ pub struct Rect {
 }
 
 impl Rect {
-    /// convert coordinate to normalized
-    fn normalize(val: ScreenSize, size: u32) -> f32 {
-        let size = size as i32;
-        match val {
-            ScreenSize::Normalized(v) => v,
-            ScreenSize::Px(v) => ((v - size / 2) as f32 / (size / 2) as f32) as f32,
-        }
-    }
     /// convert coordinate to absoluite
-    fn pxize(val: ScreenSize, size: u32) -> f32 {
+    fn pxize(val: ScreenSize) -> f32 {
         match val {
-            ScreenSize::Normalized(v) => (v * size as f32),
             ScreenSize::Px(v) => v as f32,
         }
     }
 
-    /// returns tuple containing normalized coordinates (x, y, width, height)
-    pub fn to_noramalized(&self, screen_width: u32, screen_height: u32) -> (f32, f32, f32, f32) {
-        (
-            Self::normalize(self.x, screen_width),
-            Self::normalize(self.y, screen_height) * -1.0,
-            Self::normalize(self.width, screen_width),
-            Self::normalize(self.height, screen_height) * -1.0,
-        )
-    }
     /// returns tuple containing coordinates converted to pixels (x, y, width, height)
-    pub fn to_px(&self, screen_width: u32, screen_height: u32) -> (f32, f32, f32, f32) {
+    pub fn to_px(&self) -> (f32, f32, f32, f32) {
         (
-            Self::pxize(self.x, screen_width),
-            Self::pxize(self.y, screen_height),
-            Self::pxize(self.width, screen_width),
-            Self::pxize(self.height, screen_height),
+            Self::pxize(self.x),
+            Self::pxize(self.y),
+            Self::pxize(self.width),
+            Self::pxize(self.height),
         )
     }
-
-    /// normalize all coordinates
-    pub fn self_to_noramalized(&mut self, screen_width: u32, screen_height: u32) {
-        self.x = ScreenSize::Normalized(Self::normalize(self.x, screen_width));
-        self.y = ScreenSize::Normalized(Self::normalize(self.y, screen_height));
-        self.width = ScreenSize::Normalized(Self::normalize(self.width, screen_width));
-        self.height = ScreenSize::Normalized(Self::normalize(self.height, screen_height));
-    }
-    /// convert all values to ScreenSize::Px
-    pub fn self_to_px(&mut self, screen_width: u32, screen_height: u32) {
-        self.x = ScreenSize::Px(Self::pxize(self.x, screen_width) as i32);
-        self.y = ScreenSize::Px(Self::pxize(self.y, screen_height) as i32);
-        self.width = ScreenSize::Px(Self::pxize(self.width, screen_width) as i32);
-        self.height = ScreenSize::Px(Self::pxize(self.height, screen_height) as i32);
-    }
 }
 
 /// Struct for representing RGBA color with normalized values
+#[derive(Clone, Copy, Debug)]
 pub struct ColorRGBA {
     r: f32,
     g: f32,
@@ -93,7 +89,7 @@ impl ColorRGBA {
         [self.r, self.g, self.b, self.a]
     }
     /// returns new black color
-    fn new() -> Self {
+    pub fn new() -> Self {
         ColorRGBA {
             r: 0.0,
             g: 0.0,
@@ -110,6 +106,104 @@ impl ColorRGBA {
     }
 }
 
+impl From<Color> for ColorRGBA {
+    fn from(c: Color) -> Self {
+        ColorRGBA::new().from_8bit(c.r, c.g, c.b, c.a)
+    }
+}
+
+/// Colors of editor UI taken from theme, missing ones are derived from
+/// background and foreground
+#[derive(Clone, Copy, Debug)]
+pub struct UiColors {
+    pub background: Color,
+    pub foreground: Color,
+    pub caret: Color,
+    pub selection: Color,
+    /// background of line with cursor, not drawn when theme doesn't set it
+    pub line_highlight: Option<Color>,
+    /// background of showbreak
+    pub gutter: Option<Color>,
+    /// color of showbreak
+    pub gutter_foreground: Color,
+}
+
+impl UiColors {
+    pub fn from_theme(theme: &Theme) -> Self {
+        let s = &theme.settings;
+        let background = s.background.unwrap_or(Color::WHITE);
+        let foreground = s.foreground.unwrap_or(Color::BLACK);
+        UiColors {
+            background,
+            foreground,
+            caret: s.caret.unwrap_or(foreground),
+            selection: s.selection.unwrap_or(Color {
+                a: 0x40,
+                ..foreground
+            }),
+            line_highlight: s.line_highlight,
+            gutter: s.gutter,
+            gutter_foreground: s.gutter_foreground.unwrap_or(foreground),
+        }
+    }
+}
+
+/// returns rectangle with top left corner at `x`, `y` in pixels
+pub fn px_rect(x: f32, y: f32, width: f32, height: f32) -> Rect {
+    Rect {
+        x: ScreenSize::Px(x as i32),
+        y: ScreenSize::Px(y as i32),
+        width: ScreenSize::Px((x + width) as i32),
+        height: ScreenSize::Px((y + height) as i32),
+    }
+}
+
+/// vertex of glyph quad, position is in pixels
+#[derive(Copy, Clone)]
+struct TextVertex {
+    position: [f32; 2],
+    tex_coords: [f32; 2],
+    color: [f32; 4],
+}
+
+implement_vertex!(TextVertex, position, tex_coords, color);
+
+/// Text composed by input method laid out at cursor, it's inserted into
+/// display line of cursor and following glyphs are moved right
+struct PreeditLayout {
+    /// row of buffer line with cursor
+    row: usize,
+    /// top of display line of cursor relative to top of buffer line
+    line_top: f32,
+    /// height of display line
+    height: f32,
+    /// x of cursor, where text is inserted
+    x: f32,
+    /// width of text
+    width: f32,
+    /// right edge of text area, moved glyphs past it are hidden
+    right: f32,
+    /// positions are relative to top of buffer line
+    glyphs: Vec<LineGlyph>,
+}
+
+/// adds two triangles of quad `q` of line at `top` to `vertices`
+fn push_quad(vertices: &mut Vec<TextVertex>, q: &Quad, dx: f32, top: f32) {
+    let vertex = |x: f32, y: f32, u: f32, v: f32| TextVertex {
+        position: [x + dx, top + y],
+        tex_coords: [u, v],
+        color: q.color,
+    };
+    vertices.extend_from_slice(&[
+        vertex(q.min.x, q.max.y, q.uv_min.x, q.uv_max.y),
+        vertex(q.min.x, q.min.y, q.uv_min.x, q.uv_min.y),
+        vertex(q.max.x, q.min.y, q.uv_max.x, q.uv_min.y),
+        vertex(q.max.x, q.min.y, q.uv_max.x, q.uv_min.y),
+        vertex(q.max.x, q.max.y, q.uv_max.x, q.uv_max.y),
+        vertex(q.min.x, q.max.y, q.uv_min.x, q.uv_max.y),
+    ]);
+}
+
 /// struct resposible for rendering text and decoration
 pub struct Renderer<'a> {
     cache: Cache<'a>,
@@ -117,47 +211,51 @@ pub struct Renderer<'a> {
     /// OpenGL texture used for caching of font
     text_program: Program,
     ///shader for drwaing text
-    decor_program: Program,
-    ///sjader for drawing solid rectangles
+    rects: RectBatch,
+    /// decorations and cursor drawn before text
     //highlighter: Highlighter<'a>,
-    theme: syntect::highlighting::Theme,
-    ps: SyntaxSet,
+    theme: Arc<Theme>,
+    ui: UiColors,
+    ts: ThemeSet,
+    /// thread highlighting visible lines
+    highlighter: Worker,
+    fonts: FontRegistry<'a>,
+    /// increased whenever glyphs move in glyph cache texture
+    generation: u64,
+    /// vertices of glyphs, reused by frames until more space is needed
+    text_vertices: Option<VertexBuffer<TextVertex>>,
 }
 
 impl<'a> Renderer<'a> {
-    pub fn new(win: &window::WindowContext) -> Result<Self, failure::Error> {
+    /// Creates renderer drawing to `facade`, `notify` is called from
+    /// highlighting thread when highlighted lines are ready
+    pub fn new<F, N>(facade: &F, scale_factor: f64, notify: N) -> Result<Self, failure::Error>
+    where
+        F: Facade,
+        N: Fn() + Send + 'static,
+    {
         trace!("Initializing syntect");
-        let ps = SyntaxSet::load_defaults_newlines();
-        let ts = ThemeSet::load_defaults();
-        //let _syntax = ps.find_syntax_by_extension("rs").unwrap();
+        let mut ts = ThemeSet::load_defaults();
+        load_user_themes(&mut ts);
 
-        let theme = ts.themes["base16-ocean.dark"].clone();
+        let theme = Arc::new(ts.themes["base16-ocean.dark"].clone());
+        let ui = UiColors::from_theme(&theme);
+        let highlighter = Worker::start(notify);
         trace!("Initializing gpu font cache");
-        let scale = win.display.gl_window().window().scale_factor();
+        let scale = scale_factor;
         let (cache_width, cache_height) = ((512.0 * scale) as u32, (512.0 * scale) as u32);
-        let cache = Cache::builder()
-            .dimensions(cache_width, cache_height)
-            .build();
-
-        let cache_tex = glium::texture::Texture2d::with_format(
-            &win.display,
-            glium::texture::RawImage2d {
-                data: Cow::Owned(vec![128u8; cache_width as usize * cache_height as usize]),
-                width: cache_width,
-                height: cache_height,
-                format: glium::texture::ClientFormat::U8,
-            },
-            glium::texture::UncompressedFloatFormat::U8,
-            glium::texture::MipmapsOption::NoMipmap,
-        )?;
+        let (cache, cache_tex) = glyph_cache(facade, cache_width, cache_height)?;
 
         trace!("Compiling font shader");
         let text_program = program!(
-        &win.display,
+        facade,
         140 => {
             vertex: "
 #version 150
 
+uniform vec2 origin;
+uniform vec2 screen;
+
 in vec2 position;
 in vec2 tex_coords;
 in vec4 color;
@@ -168,7 +266,8 @@ out VS_OUTPUT{
 }OUT;
 
 void main() {
-    gl_Position = vec4(position, 0.0, 1.0);
+    vec2 pos = vec2(position.x / screen.x * 2.0 - 1.0, 1.0 - position.y / screen.y * 2.0);
+    gl_Position = vec4(origin + pos, 0.0, 1.0);
     OUT.tex_coords = tex_coords;
     OUT.color = color;
 }
@@ -190,167 +289,490 @@ void main() {
                         "
         })?;
 
-        let decor_program = program!(
-        &win.display,
-        140 => {
-            vertex: "
-#version 140
-
-in vec2 position;
-in vec4 color;
-
-out vec4 v_color;
-
-void main() {
-    gl_Position = vec4(position, 0.0, 1.0);
-    v_color = color;
-}
-            ",
-            fragment: "
-#version 140
-in vec4 v_color;
-out vec4 f_color;
-
-void main() {
-    f_color = v_color;
-}
-                        "
-        })?;
+        let rects = RectBatch::new(facade)?;
 
         Ok(Self {
-            ps,
+            ts,
             cache,
             cache_tex,
             text_program,
-            decor_program,
+            rects,
             theme,
+            ui,
+            highlighter,
+            fonts: FontRegistry::new(Fonts::embedded(), Options::default().font_size),
+            generation: 0,
+            text_vertices: None,
         })
     }
 
-    fn layout_paragraph(
+    /// returns fonts used to draw all buffers
+    pub fn fonts(&self) -> &Fonts<'a> {
+        self.fonts.fonts()
+    }
+
+    /// replaces fonts of all buffers, their layout has to be invalidated
+    pub fn set_fonts(&mut self, fonts: Fonts<'a>) {
+        self.fonts.set_fonts(fonts);
+        // font ids in glyph cache now belong to other fonts
+        self.clear_glyph_cache();
+    }
+
+    /// sets font size of buffers that don't have their own
+    pub fn set_font_size(&mut self, size: f32) {
+        if self.fonts.size(None) != size {
+            // glyphs of old size would only take space in cache
+            self.clear_glyph_cache();
+        }
+        self.fonts.set_size(size);
+    }
+
+    fn clear_glyph_cache(&mut self) {
+        self.cache.clear();
+        self.generation += 1;
+    }
+
+    /// Replaces glyph cache by one with doubled size, returns false if it
+    /// can't be larger
+    fn grow_glyph_cache(
+        cache: &mut Cache<'a>,
+        cache_tex: &mut Texture2d,
+        facade: &impl Facade,
+    ) -> bool {
+        let max = facade.get_context().get_capabilities().max_texture_size as u32;
+        let (width, height) = cache.dimensions();
+        if width >= max && height >= max {
+            return false;
+        }
+        let (width, height) = ((width * 2).min(max), (height * 2).min(max));
+        debug!("Growing glyph cache to {}x{}", width, height);
+        match glyph_cache(facade, width, height) {
+            Ok(new) => {
+                (*cache, *cache_tex) = new;
+                true
+            }
+            Err(e) => {
+                error!("Could not create glyph cache: {}", e);
+                false
+            }
+        }
+    }
+
+    /// saves glyph cache texture to PNG image `path`
+    pub fn dump_atlas(&self, path: &Path) -> Result<(), failure::Error> {
+        let raw: glium::texture::RawImage2d<u8> = self.cache_tex.read();
+        // glyph coverage is in red channel
+        let image = image::GrayImage::from_fn(raw.width, raw.height, |x, y| {
+            image::Luma([raw.data[(y * raw.width + x) as usize * 4]])
+        });
+        image
+            .save_with_format(path, image::ImageFormat::Png)
+            .map_err(|e| format_err!("Could not save {:?}: {}", path, e))?;
+        info!("Glyph cache saved to {:?}", path);
+        Ok(())
+    }
+
+    /// returns lines highlighted since last call, they have to be applied to
+    /// their buffers
+    pub fn highlight_results(&self) -> TryIter<'_, Done> {
+        self.highlighter.results()
+    }
+
+    /// Switches syntax highlighting theme and UI colors. Themes from config
+    /// directory are loaded again if `name` is not known, so new theme files
+    /// can be used without restart.
+    pub fn set_theme(&mut self, name: &str) -> Result<(), failure::Error> {
+        if !self.ts.themes.contains_key(name) {
+            load_user_themes(&mut self.ts);
+        }
+        match self.ts.themes.get(name) {
+            Some(theme) => {
+                trace!("Switching theme to {:?}", name);
+                self.theme = Arc::new(theme.clone());
+                self.ui = UiColors::from_theme(theme);
+                Ok(())
+            }
+            None => bail!("Unknown theme \"{}\"", name),
+        }
+    }
+
+    /// returns highlighting style of char at `col` of `row`
+    fn style_at(&self, buff: &TextBuffer, row: usize, col: usize) -> Style {
+        match buff.highlight.borrow().line(row).and_then(|s| s.get(col)) {
+            Some(style) => *style,
+            // plain text is drawn with theme's foreground
+            None => Style {
+                foreground: self.ui.foreground,
+                ..Default::default()
+            },
+        }
+    }
+
+    /// Lays out glyphs of display `lines` of one buffer line with their
+    /// highlighting styles and faces. Showbreak markers are laid out at the
+    /// start of continuation lines. Glyphs are positioned relative to top of
+    /// the buffer line and scrolled to `left_col`.
+    fn line_glyphs(
         &self,
-        font: &Font<'a>,
-        scale: Scale,
-        width: u32,
-        text: &Vec<String>,
-    ) -> Vec<(PositionedGlyph<'a>, syntect::highlighting::Style)> {
-        let syntax = self.ps.find_syntax_by_extension("rs").unwrap();
-        let ps = SyntaxSet::load_defaults_newlines();
-        let mut highlight =
-            syntect::easy::HighlightLines::new(syntax, &self.theme);
+        lines: &[DisplayLine],
+        metrics: &dyn Metrics,
+        params: &LayoutParams,
+        buff: &TextBuffer,
+        left_col: usize,
+    ) -> Vec<LineGlyph> {
+        let fonts = self.fonts.fonts();
+        let break_color = ColorRGBA::from(self.ui.gutter_foreground).as_arr();
 
         let mut result = Vec::new();
-        let v_metrics = font.v_metrics(scale);
-        let advance_height = v_metrics.ascent - v_metrics.descent + v_metrics.line_gap;
-        let mut caret = point(0.0, v_metrics.ascent);
-        let mut last_glyph_id = None;
-        for l in text {
-            let line = highlight.highlight_line(l, &self.ps).unwrap();
-            for word in line {
-                let style = word.0;
-                for c in word.1.chars() {
-                    if c.is_control() {
-                        //check if line contains \n - should not contain, [text] should be vector of inidvidual lines
-                        match c {
-                            '\n' => {
-                                caret = point(0.0, caret.y + advance_height);
-                                error!("Line \"{}\" is not separated properly, should be splitted into two", l);
-                            }
-                            '\r' => {
-                                caret = point(0.0, caret.y + advance_height);
-                                error!("Line \"{}\" is not separated properly, should be splitted into two", l);
-                            }
-                            _ => {}
-                        }
-                        continue;
-                    }
-                    let base_glyph = font.glyph(c);
-                    if let Some(id) = last_glyph_id.take() {
-                        caret.x += font.pair_kerning(scale, id, base_glyph.id());
-                    }
-                    last_glyph_id = Some(base_glyph.id());
-                    let mut glyph = base_glyph.scaled(scale).positioned(caret);
-                    if let Some(bb) = glyph.pixel_bounding_box() {
-                        if bb.max.x > width as i32 {
-                            caret = point(0.0, caret.y + advance_height);
-                            glyph.set_position(caret);
-                            last_glyph_id = None;
-                        }
+        for (i, line) in lines.iter().enumerate() {
+            let y = metrics.ascent() + i as f32 * metrics.line_height();
+            let offset = line.scroll_offset(left_col);
+            if line.continuation {
+                let mut x = 0.0;
+                for c in params.showbreak.chars() {
+                    let (font_id, font) = fonts.glyph_font(c, Face::Regular);
+                    let glyph = font.glyph(c);
+                    result.push(LineGlyph {
+                        font_id,
+                        id: glyph.id(),
+                        pos: point(x, y),
+                        color: break_color,
+                    });
+                    x += glyph.scaled(params.scale).h_metrics().advance_width;
+                }
+            }
+            for g in &line.glyphs {
+                if g.ch.is_control() {
+                    //check if line contains \n - should not contain, [text] should be vector of inidvidual lines
+                    if g.ch == '\n' || g.ch == '\r' {
+                        error!("Line \"{}\" is not separated properly, should be splitted into two", buff.buffer[line.row]);
                     }
-                    caret.x += glyph.unpositioned().h_metrics().advance_width;
-                    result.push((glyph, style));
+                    continue;
+                }
+                let x = g.x - offset;
+                // clip glyphs scrolled out of text area
+                if x < 0.0 || x + g.advance > params.width {
+                    continue;
                 }
+                let style = self.style_at(buff, line.row, g.col);
+                let (font_id, font) = fonts.glyph_font(g.ch, Face::from_style(style.font_style));
+                result.push(LineGlyph {
+                    font_id,
+                    id: font.glyph(g.ch).id(),
+                    pos: point(x, y),
+                    color: ColorRGBA::from(style.foreground).as_arr(),
+                });
             }
-            caret = point(0.0, caret.y + advance_height);
         }
         result
     }
+
+    /// Lays out glyphs of buffer lines shown on display `lines` that aren't
+    /// cached by buffer yet. Returns rows of the buffer lines with their top
+    /// in pixels.
+    fn cache_lines(
+        &self,
+        lines: &[DisplayLine],
+        metrics: &dyn Metrics,
+        params: &LayoutParams,
+        buff: &TextBuffer,
+    ) -> Vec<(usize, f32)> {
+        // lines are scrolled horizontally only without wrapping
+        let left_col = if params.wrap { 0 } else { buff.view_pos.get().1 };
+        let mut cache = buff.glyphs.borrow_mut();
+        cache.set_params(params);
+        let mut rows = Vec::new();
+        let mut start = 0;
+        while start < lines.len() {
+            let row = lines[start].row;
+            let count = lines[start..].iter().take_while(|l| l.row == row).count();
+            let highlighted = buff.highlight.borrow().line(row).is_some();
+            if cache.get(row, left_col, highlighted).is_none() {
+                let lines = &lines[start..start + count];
+                let glyphs = self.line_glyphs(lines, metrics, params, buff, left_col);
+                cache.insert(row, CachedLine::new(left_col, highlighted, glyphs));
+            }
+            // whole pixels keep glyphs of moved lines sharp
+            rows.push((row, (start as f32 * metrics.line_height()).round()));
+            start += count;
+        }
+        rows
+    }
+
+    #[allow(clippy::too_many_arguments)]
     pub fn draw(
         &mut self,
         x: f32,
         y: f32,
         width_factor: f32,
-        scale: f32,
+        opts: &Options,
         disp: &Display,
-        buff: &mut TextBuffer<'a>,
+        buff: &TextBuffer,
+        preedit: Option<&Preedit>,
     ) {
+        let scale_dis = disp.gl_window().window().scale_factor() as f32;
         let mut target = disp.draw();
-        target.clear_color(1.0, 1.0, 1.0, 1.0);
-        let rect = Rect {
-            x: ScreenSize::Px(10),
-            y: ScreenSize::Px(10),
-            width: ScreenSize::Px(20),
-            height: ScreenSize::Px(20),
-        };
-        let col = ColorRGBA::new().from_8bit(255, 0, 0, 255);
-        self.draw_rect(&rect, &col, disp, &mut target);
-        self.draw_cursor(buff, disp, &mut target);
-        self.draw_text(
-            x,
-            y,
-            width_factor,
-            scale,
-            disp,
-            buff,
-            &mut target,
-        );
+        self.render(x, y, width_factor, scale_dis, opts, disp, &mut target, buff, preedit);
         target.finish().unwrap();
     }
 
-    ///draws text, `x` and `y` is normalized position of top left corner
-    /// `width_factor` and `height_factor` is normalized width and height
-    /// `scale is size of font`
-    fn draw_text(
+    /// Draws `buff` to `target`, which can be window or offscreen
+    /// framebuffer. `scale_dis` is scale factor of the screen. `preedit` is
+    /// drawn underlined at cursor.
+    #[allow(clippy::too_many_arguments)]
+    pub fn render<F: Facade, S: Surface>(
         &mut self,
         x: f32,
         y: f32,
         width_factor: f32,
-        scale: f32,
-        disp: &Display,
-        buff: &TextBuffer<'a>,
-        target: &mut Frame,
+        scale_dis: f32,
+        opts: &Options,
+        facade: &F,
+        target: &mut S,
+        buff: &TextBuffer,
+        preedit: Option<&Preedit>,
     ) {
-        //get size of window
-        let (mut width, _): (f32, f32) = disp.gl_window().window().inner_size().into();
-        width = width_factor * width;
-        let width = width.ceil() as u32;
-
-        let scale_dis = disp.gl_window().window().scale_factor() as f32;
-
-        let glyphs = self.layout_paragraph(
-            &buff.font,
-            Scale::uniform(scale * scale_dis),
-            width,
+        let (width, height) = target.get_dimensions();
+        let (width, height) = (width as f32, height as f32);
+        scroll_to_cursor(&self.fonts, opts, scale_dis, (width_factor * width).ceil(), height, buff);
+        let mut layout = buff.layout.borrow_mut();
+        let params = layout.params().clone();
+        let metrics = FontMetrics::new(self.fonts.fonts(), params.scale);
+        let view_pos = buff.view_pos.get();
+        let lines = layout.rows(&metrics, &buff.buffer, view_pos.0, height);
+        let last_row = lines.last().map_or(view_pos.0, |l| l.row);
+        buff.highlight.borrow_mut().request(
+            &buff.buffer_type,
+            &self.theme,
             &buff.buffer,
+            last_row,
+            &self.highlighter,
         );
+        buff.cursor.calc_screen_pos(
+            &mut layout,
+            &metrics,
+            &buff.buffer,
+            view_pos,
+            width as i32,
+            height as i32,
+        );
+        drop(layout);
+        let rows = self.cache_lines(&lines, &metrics, &params, buff);
+
+        let decorations = self.decorations(&lines, &metrics, &params, buff);
 
-        for glyph in &glyphs {
-            self.cache.queue_glyph(0, glyph.0.clone());
+        let [r, g, b, a] = ColorRGBA::from(self.ui.background).as_arr();
+        target.clear_color(r, g, b, a);
+        let screen = (width as u32, height as u32);
+        for (rect, color) in &decorations {
+            self.rects.push(rect, *color);
         }
+        let cursor = self.cursor_rect(buff, screen);
+        let preedit = preedit.and_then(|p| self.layout_preedit(p, &rows, &metrics, &params, buff, &cursor));
+        match &preedit {
+            Some((layout, rects)) => {
+                for (rect, color) in rects {
+                    self.rects.push(rect, *color);
+                }
+                trace!("Preedit of {} glyphs at {}", layout.glyphs.len(), layout.x);
+            }
+            None => self.rects.push(&cursor, self.ui.caret.into()),
+        }
+        self.rects.draw(facade, target);
+        let preedit = preedit.as_ref().map(|(layout, _)| layout);
+        self.draw_text(x, y, &rows, params.scale, buff, preedit, facade, target);
+    }
+
+    /// Lays out `preedit` at `cursor`, returns it with its underline and
+    /// caret. `None` if the cursor isn't on visible `rows`.
+    fn layout_preedit(
+        &self,
+        preedit: &Preedit,
+        rows: &[(usize, f32)],
+        metrics: &dyn Metrics,
+        params: &LayoutParams,
+        buff: &TextBuffer,
+        cursor: &Rect,
+    ) -> Option<(PreeditLayout, Vec<(Rect, ColorRGBA)>)> {
+        let (row, row_top) = *rows.iter().find(|(row, _)| *row == buff.cursor.text_pos.0)?;
+        let (ScreenSize::Px(x), ScreenSize::Px(top)) = (cursor.x, cursor.y);
+        let (x, top) = (x as f32, top as f32);
+        let fonts = self.fonts.fonts();
+        let color = ColorRGBA::from(self.ui.foreground).as_arr();
+        let mut glyphs = Vec::new();
+        let mut caret = None;
+        let mut advance = 0.0;
+        for (i, c) in preedit.text.char_indices() {
+            if preedit.cursor == Some(i) {
+                caret = Some(advance);
+            }
+            let (font_id, font) = fonts.glyph_font(c, Face::Regular);
+            let glyph = font.glyph(c);
+            let id = glyph.id();
+            let glyph_advance = glyph.scaled(params.scale).h_metrics().advance_width;
+            if x + advance + glyph_advance <= params.width {
+                glyphs.push(LineGlyph {
+                    font_id,
+                    id,
+                    pos: point(x + advance, top - row_top + metrics.ascent()),
+                    color,
+                });
+            }
+            advance += glyph_advance;
+        }
+        if preedit.cursor == Some(preedit.text.len()) {
+            caret = Some(advance);
+        }
+
+        let height = metrics.line_height();
+        let thickness = (height / 16.0).max(1.0).round();
+        let width = advance.min(params.width - x).max(0.0);
+        let mut rects = vec![(
+            px_rect(x, top + metrics.ascent() + thickness, width, thickness),
+            ColorRGBA::from(self.ui.foreground),
+        )];
+        if let Some(caret) = caret.filter(|caret| *caret <= width) {
+            rects.push((px_rect(x + caret, top, thickness.max(2.0), height), self.ui.caret.into()));
+        }
+        let layout = PreeditLayout {
+            row,
+            line_top: top - row_top,
+            height,
+            x,
+            width: advance,
+            right: params.width,
+            glyphs,
+        };
+        Some((layout, rects))
+    }
+
+    /// Returns backgrounds of visible display `lines` (highlight of cursor
+    /// line, gutter behind showbreak and visual selection) and underlines
+    fn decorations(
+        &self,
+        lines: &[DisplayLine],
+        metrics: &dyn Metrics,
+        params: &LayoutParams,
+        buff: &TextBuffer,
+    ) -> Vec<(Rect, ColorRGBA)> {
+        let cursor = buff.cursor.text_pos;
+        let selection = buff
+            .selection
+            .map(|anchor| (anchor.min(cursor), anchor.max(cursor)));
+        let showbreak_width: f32 = params.showbreak.chars().map(|c| metrics.advance(c)).sum();
+        let height = metrics.line_height();
+
+        let thickness = (height / 16.0).max(1.0).round();
 
-        self.cache
-            .cache_queued(|rect, data| {
-                self.cache_tex.main_level().write(
+        let mut result = Vec::new();
+        for (i, line) in lines.iter().enumerate() {
+            let top = i as f32 * height;
+            let offset = if params.wrap {
+                0.0
+            } else {
+                line.scroll_offset(buff.view_pos.get().1)
+            };
+            if let (Some(color), true) = (self.ui.line_highlight, line.row == cursor.0) {
+                result.push((px_rect(0.0, top, params.width, height), color.into()));
+            }
+            if let (Some(color), true) = (self.ui.gutter, line.continuation) {
+                result.push((px_rect(0.0, top, showbreak_width, height), color.into()));
+            }
+            if let Some((start, end)) = selection {
+                // selected glyphs of display line are one span
+                let span = line
+                    .glyphs
+                    .iter()
+                    .filter(|g| (line.row, g.col) >= start && (line.row, g.col) <= end)
+                    .fold(None, |span: Option<(f32, f32)>, g| {
+                        let (min, max) = span.unwrap_or((g.x, g.x + g.advance));
+                        Some((min.min(g.x), max.max(g.x + g.advance)))
+                    });
+                if let Some((min, max)) = span {
+                    let min = (min - offset).max(0.0);
+                    let max = (max - offset).min(params.width);
+                    if max > min {
+                        result.push((
+                            px_rect(min, top, max - min, height),
+                            self.ui.selection.into(),
+                        ));
+                    }
+                }
+            }
+
+            // underline of neighbouring glyphs with the same color is one rect
+            let underline_y = top + metrics.ascent() + thickness;
+            let underline = |min: f32, max: f32, color: Color| {
+                (px_rect(min, underline_y, max - min, thickness), color.into())
+            };
+            let mut span: Option<(f32, f32, Color)> = None;
+            for g in &line.glyphs {
+                let style = self.style_at(buff, line.row, g.col);
+                let x = g.x - offset;
+                let next = if style.font_style.contains(FontStyle::UNDERLINE)
+                    && x >= 0.0
+                    && x + g.advance <= params.width
+                {
+                    Some((x, x + g.advance, style.foreground))
+                } else {
+                    None
+                };
+                span = match (span, next) {
+                    (Some((min, _, color)), Some((_, max, c))) if c == color => {
+                        Some((min, max, color))
+                    }
+                    (prev, next) => {
+                        if let Some((min, max, color)) = prev {
+                            result.push(underline(min, max, color));
+                        }
+                        next
+                    }
+                };
+            }
+            if let Some((min, max, color)) = span {
+                result.push(underline(min, max, color));
+            }
+        }
+        result
+    }
+
+    /// Draws cached glyphs of buffer `rows` whose tops are in pixels, `x` and
+    /// `y` is normalized position of top left corner. Quads of lines are
+    /// made again only when glyph cache texture is reorganized.
+    #[allow(clippy::too_many_arguments)]
+    fn draw_text<F: Facade, S: Surface>(
+        &mut self,
+        x: f32,
+        y: f32,
+        rows: &[(usize, f32)],
+        scale: Scale,
+        buff: &TextBuffer,
+        preedit: Option<&PreeditLayout>,
+        facade: &F,
+        target: &mut S,
+    ) {
+        let fonts = self.fonts.fonts();
+        let mut cache = buff.glyphs.borrow_mut();
+        let glyph = |g: &LineGlyph| {
+            let font = fonts.font(g.font_id);
+            font.glyph(g.id).scaled(scale).positioned(g.pos)
+        };
+        loop {
+            // all visible glyphs are queued, so glyphs of cached quads aren't
+            // evicted from the texture
+            for (row, _) in rows {
+                if let Some(line) = cache.line(*row) {
+                    for g in &line.glyphs {
+                        self.cache.queue_glyph(g.font_id, glyph(g));
+                    }
+                }
+            }
+            for g in preedit.iter().flat_map(|p| &p.glyphs) {
+                self.cache.queue_glyph(g.font_id, glyph(g));
+            }
+            let cache_tex = &self.cache_tex;
+            let cached = self.cache.cache_queued(|rect, data| {
+                cache_tex.main_level().write(
                     glium::Rect {
                         left: rect.min.x,
                         bottom: rect.min.y,
@@ -364,161 +786,111 @@ void main() {
                         format: glium::texture::ClientFormat::U8,
                     },
                 );
-            })
-            .unwrap();
-
-        let uniforms = uniform! {
-            tex: self.cache_tex.sampled().magnify_filter(glium::uniforms::MagnifySamplerFilter::Nearest)
-        };
-
-        let vertex_buffer = {
-            #[derive(Copy, Clone)]
-            struct Vertex {
-                position: [f32; 2],
-                tex_coords: [f32; 2],
-                color: [f32; 4],
+            });
+            match cached {
+                Ok(CachedBy::Adding) => {}
+                Ok(CachedBy::Reordering) => self.generation += 1,
+                Err(CacheWriteErr::NoRoomForWholeQueue)
+                    if Self::grow_glyph_cache(&mut self.cache, &mut self.cache_tex, facade) =>
+                {
+                    // glyphs are queued again to new empty cache
+                    self.generation += 1;
+                    continue;
+                }
+                Err(e) => error!("Could not cache glyphs: {}", e),
             }
+            break;
+        }
 
-            implement_vertex!(Vertex, position, tex_coords, color);
-            let mut color = [0.0, 0.0, 0.0, 1.0];
-            let (screen_width, screen_height) = {
-                let (w, h) = disp.get_framebuffer_dimensions();
-                (w as f32, h as f32)
+        let mut vertices = Vec::new();
+        for (row, top) in rows {
+            let line = match cache.line(*row) {
+                Some(line) => line,
+                None => continue,
             };
-            let origin = point(x, y);
-            let vertices: Vec<Vertex> = glyphs
-                .iter()
-                .filter_map(|g| match self.cache.rect_for(0, &g.0).ok().flatten() {
-                    Some(rect) => Some((rect, &g.1)),
-                    None => None,
-                })
-                .flat_map(|(rect, style)| {
-                    let (uv_rect, screen_rect) = rect;
-                    let gl_rect = rusttype::Rect {
-                        min: origin
-                            + (vector(
-                                screen_rect.min.x as f32 / screen_width - 0.5,
-                                1.0 - screen_rect.min.y as f32 / screen_height - 0.5,
-                            )) * 2.0,
-                        max: origin
-                            + (vector(
-                                screen_rect.max.x as f32 / screen_width - 0.5,
-                                1.0 - screen_rect.max.y as f32 / screen_height - 0.5,
-                            )) * 2.0,
+            if line.quads(self.generation).is_none() {
+                let quads = line
+                    .glyphs
+                    .iter()
+                    .filter_map(|g| {
+                        let (uv, rect) =
+                            self.cache.rect_for(g.font_id, &glyph(g)).ok().flatten()?;
+                        Some(Quad {
+                            min: point(rect.min.x as f32, rect.min.y as f32),
+                            max: point(rect.max.x as f32, rect.max.y as f32),
+                            uv_min: uv.min,
+                            uv_max: uv.max,
+                            color: g.color,
+                        })
+                    })
+                    .collect();
+                line.set_quads(self.generation, quads);
+            }
+            for q in line.quads(self.generation).unwrap_or_default() {
+                // glyphs after cursor make room for preedit
+                let dx = match preedit {
+                    Some(p)
+                        if p.row == *row
+                            && (q.min.x + q.max.x) / 2.0 >= p.x
+                            && (q.min.y + q.max.y) / 2.0 >= p.line_top
+                            && (q.min.y + q.max.y) / 2.0 < p.line_top + p.height =>
+                    {
+                        if q.max.x + p.width > p.right {
+                            continue;
+                        }
+                        p.width
+                    }
+                    _ => 0.0,
+                };
+                push_quad(&mut vertices, q, dx, *top);
+            }
+        }
+        if let Some(p) = preedit {
+            let top = rows.iter().find(|(row, _)| *row == p.row).map_or(0.0, |(_, top)| *top);
+            for g in &p.glyphs {
+                if let Ok(Some((uv, rect))) = self.cache.rect_for(g.font_id, &glyph(g)) {
+                    let q = Quad {
+                        min: point(rect.min.x as f32, rect.min.y as f32),
+                        max: point(rect.max.x as f32, rect.max.y as f32),
+                        uv_min: uv.min,
+                        uv_max: uv.max,
+                        color: g.color,
                     };
-                    color[0] = style.foreground.r as f32 / 255.0;
-                    color[1] = style.foreground.g as f32 / 255.0;
-                    color[2] = style.foreground.b as f32 / 255.0;
-                    color[3] = style.foreground.a as f32 / 255.0;
-                    vec![
-                        Vertex {
-                            position: [gl_rect.min.x, gl_rect.max.y],
-                            tex_coords: [uv_rect.min.x, uv_rect.max.y],
-                            color,
-                        },
-                        Vertex {
-                            position: [gl_rect.min.x, gl_rect.min.y],
-                            tex_coords: [uv_rect.min.x, uv_rect.min.y],
-                            color,
-                        },
-                        Vertex {
-                            position: [gl_rect.max.x, gl_rect.min.y],
-                            tex_coords: [uv_rect.max.x, uv_rect.min.y],
-                            color,
-                        },
-                        Vertex {
-                            position: [gl_rect.max.x, gl_rect.min.y],
-                            tex_coords: [uv_rect.max.x, uv_rect.min.y],
-                            color,
-                        },
-                        Vertex {
-                            position: [gl_rect.max.x, gl_rect.max.y],
-                            tex_coords: [uv_rect.max.x, uv_rect.max.y],
-                            color,
-                        },
-                        Vertex {
-                            position: [gl_rect.min.x, gl_rect.max.y],
-                            tex_coords: [uv_rect.min.x, uv_rect.max.y],
-                            color,
-                        },
-                    ]
-                })
-                .collect();
-
-            glium::VertexBuffer::new(disp, &vertices).unwrap()
-        };
-
-        target
-            .draw(
-                &vertex_buffer,
-                glium::index::NoIndices(glium::index::PrimitiveType::TrianglesList),
-                &self.text_program,
-                &uniforms,
-                &glium::DrawParameters {
-                    blend: glium::Blend::alpha_blending(),
-                    ..Default::default()
-                },
-            )
-            .unwrap();
-    }
-    /// draw cursor,...
-    pub fn draw_rect(
-        &mut self,
-        rect: &Rect,
-        color: &ColorRGBA,
-        disp: &Display,
-        target: &mut Frame,
-    ) {
-        //get size of window
-        let (width, height): (f32, f32) = disp.gl_window().window().inner_size().into();
-        let (x, y, width, height) = rect.to_noramalized(width.ceil() as u32, height.ceil() as u32);
-
-        let vertex_buffer = {
-            #[derive(Copy, Clone)]
-            struct Vertex {
-                position: [f32; 2],
-                color: [f32; 4],
+                    push_quad(&mut vertices, &q, 0.0, top);
+                }
             }
+        }
+        if vertices.is_empty() {
+            return;
+        }
 
-            implement_vertex!(Vertex, position, color);
-            let verts: Vec<Vertex> = vec![
-                Vertex {
-                    position: [x, y],
-                    color: color.as_arr(),
-                },
-                Vertex {
-                    position: [x, height],
-                    color: color.as_arr(),
-                },
-                Vertex {
-                    position: [width, y],
-                    color: color.as_arr(),
-                },
-                Vertex {
-                    position: [x, height],
-                    color: color.as_arr(),
-                },
-                Vertex {
-                    position: [width, y],
-                    color: color.as_arr(),
-                },
-                Vertex {
-                    position: [width, height],
-                    color: color.as_arr(),
-                },
-            ];
-
-            glium::VertexBuffer::new(disp, &verts).unwrap()
+        // buffer is replaced only when it's too small
+        let too_small = match &self.text_vertices {
+            Some(vb) => vb.len() < vertices.len(),
+            None => true,
         };
+        if too_small {
+            let size = vertices.len().next_power_of_two().max(1024);
+            trace!("Allocating vertex buffer for {} glyphs", size / 6);
+            self.text_vertices = Some(VertexBuffer::empty_dynamic(facade, size).unwrap());
+        }
+        let vertex_buffer = self.text_vertices.as_ref().unwrap();
+        // previous frame can still be drawn from old content
+        vertex_buffer.invalidate();
+        let slice = vertex_buffer.slice(0..vertices.len()).unwrap();
+        slice.write(&vertices);
 
+        let (screen_width, screen_height) = target.get_dimensions();
         let uniforms = uniform! {
-            tex: self.cache_tex.sampled().magnify_filter(glium::uniforms::MagnifySamplerFilter::Nearest)
+            tex: self.cache_tex.sampled().magnify_filter(glium::uniforms::MagnifySamplerFilter::Nearest),
+            origin: [x, y],
+            screen: [screen_width as f32, screen_height as f32],
         };
         target
             .draw(
-                &vertex_buffer,
+                slice,
                 glium::index::NoIndices(glium::index::PrimitiveType::TrianglesList),
-                &self.decor_program,
+                &self.text_program,
                 &uniforms,
                 &glium::DrawParameters {
                     blend: glium::Blend::alpha_blending(),
@@ -527,30 +899,74 @@ void main() {
             )
             .unwrap();
     }
+    /// returns rectangle of cursor in pixels
+    fn cursor_rect(&self, buff: &TextBuffer, (width, height): (u32, u32)) -> Rect {
+        let (width, height) = (width as f32, height as f32);
 
-    fn draw_cursor(
-        &mut self,
-        buff: &mut TextBuffer,
-        disp: &Display,
-        target: &mut Frame,
-    ) {
-        let (width, height): (f32, f32) = disp.gl_window().window().inner_size().into();
-
-        buff.cursor
-            .calc_screen_pos(&buff.font, &buff.buffer, width as i32, height as i32);
-        let x = (buff.cursor.screen_pos.0 + 1.0) * (width / 2.0);
-        let y = (1.0 - buff.cursor.screen_pos.1) * (height / 2.0);
-        let width = x + buff.cursor.width;
-        let height = y + buff.cursor.height;
+        let (screen_x, screen_y) = buff.cursor.screen_pos.get();
+        let x = (screen_x + 1.0) * (width / 2.0);
+        let y = (1.0 - screen_y) * (height / 2.0);
+        let width = x + buff.cursor.width.get();
+        let height = y + buff.cursor.height.get();
 
-        let rect = Rect {
+        Rect {
             x: ScreenSize::Px(x as i32),
             y: ScreenSize::Px(y as i32),
             width: ScreenSize::Px(width as i32),
             height: ScreenSize::Px(height as i32),
-        };
+        }
+    }
+}
+
+/// creates empty glyph cache and texture for it
+fn glyph_cache<'a>(
+    facade: &impl Facade,
+    width: u32,
+    height: u32,
+) -> Result<(Cache<'a>, Texture2d), failure::Error> {
+    let cache = Cache::builder().dimensions(width, height).build();
+    let cache_tex = glium::texture::Texture2d::with_format(
+        facade,
+        glium::texture::RawImage2d {
+            data: Cow::Owned(vec![128u8; width as usize * height as usize]),
+            width,
+            height,
+            format: glium::texture::ClientFormat::U8,
+        },
+        glium::texture::UncompressedFloatFormat::U8,
+        glium::texture::MipmapsOption::NoMipmap,
+    )?;
+    Ok((cache, cache_tex))
+}
 
-        let col = ColorRGBA::new();
-        self.draw_rect(&rect, &col, disp, target);
+/// Loads `.tmTheme` files from `themes` directory in config directory, theme
+/// name is the file name without extension
+pub fn load_user_themes(ts: &mut ThemeSet) {
+    let dir = match config::config_dir() {
+        Some(dir) => dir.join("themes"),
+        None => return,
+    };
+    if !dir.is_dir() {
+        return;
+    }
+    let paths = match ThemeSet::discover_theme_paths(&dir) {
+        Ok(paths) => paths,
+        Err(e) => {
+            error!("Could not read themes from {:?}: {}", dir, e);
+            return;
+        }
+    };
+    for path in paths {
+        let name = match path.file_stem().and_then(|s| s.to_str()) {
+            Some(name) => name.to_string(),
+            None => continue,
+        };
+        match ThemeSet::get_theme(&path) {
+            Ok(theme) => {
+                trace!("Loaded theme {:?} from {:?}", name, path);
+                ts.themes.insert(name, theme);
+            }
+            Err(e) => error!("Could not load theme {:?}: {}", path, e),
+        }
     }
 }
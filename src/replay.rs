@@ -0,0 +1,404 @@
+//! Recording and replaying of input sessions, used to reproduce bugs.
+//!
+//! `--record session.toml` saves config, size of window (or terminal) and
+//! buffers at start, then appends every input processed by [Editor] with
+//! its time, so the file is complete even if the editor crashes.
+//! `--replay session.toml` runs the session again in the frontend it was
+//! recorded in, `--speed 2` plays it twice as fast. With `--headless`
+//! nothing is drawn and resulting state is printed.
+//!
+//! Inputs are processed at recorded times, so mappings time out the same
+//! way. Reloads of config file are not recorded.
+
+use std::collections::VecDeque;
+use std::fs::{self, File};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+
+#[allow(unused_imports)]
+use log::{debug, error, info, trace, warn};
+use serde::{Deserialize, Serialize};
+
+use crate::buffer::{BufferOrigin, BufferType, TextBuffer};
+use crate::config;
+use crate::debug::failure_to_string;
+use crate::editor::{Editor, Ime, Input, Mouse};
+use crate::font::{FontRegistry, Fonts};
+use crate::key::parse_keys;
+use crate::layout::{CellMetrics, FontMetrics};
+use crate::options::Options;
+use crate::renderer;
+use crate::syntax;
+use crate::tui;
+
+#[cfg(test)]
+mod tests {
+    use super::{replay_headless, Frontend, Recorder, Recording};
+    use crate::editor::{Ime, Mouse};
+    use crate::harness::Harness;
+
+    #[test]
+    fn test_record_and_replay() {
+        let path =
+            std::env::temp_dir().join(format!("sietor-test-{}/session.toml", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let mut h = Harness::new("fn main() {\n}");
+        h.editor.recorder = Some(
+            Recorder::start(&path, &h.editor, None, Frontend::Terminal, (80, 25), 1.0).unwrap(),
+        );
+        h.keys(":setl ft=rust<CR>:inoremap jk <lt>Esc><CR>");
+        h.keys("jilet<lt>&>j");
+        h.ime(Ime::Preedit("ご".to_string(), Some(3)));
+        h.ime(Ime::Commit("語".to_string()));
+        h.keys("jk");
+        h.mouse(Mouse::Press(2.0, 0.0)).mouse(Mouse::Drag(4.0, 1.0));
+
+        let rec = Recording::load(&path).unwrap();
+        assert_eq!(rec.frontend, Frontend::Terminal);
+        let editor = replay_headless(&rec).unwrap();
+        let buff = &editor.buffers[editor.active_buffer];
+        assert_eq!(buff.buffer, h.buffer().buffer);
+        assert_eq!(buff.buffer_type, h.buffer().buffer_type);
+        assert_eq!(buff.cursor.text_pos, h.cursor());
+        assert_eq!(editor.state, h.mode());
+        assert_eq!(h.text(), "fn main() {\nlet<&>j語}");
+        assert_eq!(buff.selection, Some((0, 2)));
+        std::fs::remove_file(&path).unwrap();
+    }
+}
+
+/// Frontend that recorded session, replay uses the same one
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Frontend {
+    Window,
+    Terminal,
+}
+
+/// Buffer at start of recording
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RecordedBuffer {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    path: Option<PathBuf>,
+    /// name of syntax or `text`
+    filetype: String,
+    cursor: (usize, usize),
+    text: String,
+}
+
+/// Input of recorded session, one of the optional fields is set, without
+/// them it is [Input::Tick]
+#[derive(Debug, Default, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Event {
+    /// nanoseconds since start of recording
+    time: u64,
+    /// key in Vim notation
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    key: Option<String>,
+    /// new size of window or terminal
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    resize: Option<(u32, u32)>,
+    /// position of mouse button press
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    press: Option<(f32, f32)>,
+    /// position of mouse moved with button held
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    drag: Option<(f32, f32)>,
+    /// steps of mouse wheel
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    wheel: Option<i32>,
+    /// text composed by input method
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    preedit: Option<String>,
+    /// byte offset of cursor in `preedit`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    preedit_cursor: Option<usize>,
+    /// text committed by input method
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    commit: Option<String>,
+}
+
+impl Event {
+    fn new(input: &Input, time: Duration) -> Self {
+        let mut event = Event {
+            time: time.as_nanos() as u64,
+            ..Default::default()
+        };
+        match *input {
+            Input::Key(key) => event.key = Some(key.to_string()),
+            Input::Resize(width, height) => event.resize = Some((width, height)),
+            Input::Mouse(Mouse::Press(x, y)) => event.press = Some((x, y)),
+            Input::Mouse(Mouse::Drag(x, y)) => event.drag = Some((x, y)),
+            Input::Mouse(Mouse::Wheel(steps)) => event.wheel = Some(steps),
+            Input::Ime(Ime::Preedit(ref text, cursor)) => {
+                event.preedit = Some(text.clone());
+                event.preedit_cursor = cursor;
+            }
+            Input::Ime(Ime::Commit(ref text)) => event.commit = Some(text.clone()),
+            Input::Tick => {}
+        }
+        event
+    }
+
+    fn input(&self) -> Result<Input, failure::Error> {
+        if let Some((width, height)) = self.resize {
+            return Ok(Input::Resize(width, height));
+        }
+        if let Some((x, y)) = self.press {
+            return Ok(Input::Mouse(Mouse::Press(x, y)));
+        }
+        if let Some((x, y)) = self.drag {
+            return Ok(Input::Mouse(Mouse::Drag(x, y)));
+        }
+        if let Some(steps) = self.wheel {
+            return Ok(Input::Mouse(Mouse::Wheel(steps)));
+        }
+        if let Some(text) = &self.preedit {
+            return Ok(Input::Ime(Ime::Preedit(text.clone(), self.preedit_cursor)));
+        }
+        if let Some(text) = &self.commit {
+            return Ok(Input::Ime(Ime::Commit(text.clone())));
+        }
+        match &self.key {
+            Some(text) => match parse_keys(text)?[..] {
+                [key] => Ok(Input::Key(key)),
+                _ => bail!("\"{}\" is not a single key", text),
+            },
+            None => Ok(Input::Tick),
+        }
+    }
+}
+
+/// Recorded session, saved as TOML
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Recording {
+    pub frontend: Frontend,
+    /// size of window in pixels or of terminal in cells
+    pub size: (u32, u32),
+    /// scale factor of screen
+    scale_factor: f32,
+    /// text of config file, default options are used without it
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    config: Option<String>,
+    zoom: i32,
+    active_buffer: usize,
+    buffers: Vec<RecordedBuffer>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    events: Vec<Event>,
+}
+
+impl Recording {
+    pub fn load(path: &Path) -> Result<Self, failure::Error> {
+        let text = fs::read_to_string(path)
+            .map_err(|e| format_err!("Could not read {:?}: {}", path, e))?;
+        let rec: Recording = toml::from_str(&text)
+            .map_err(|e| format_err!("Invalid recording {:?}: {}", path, e))?;
+        if rec.active_buffer >= rec.buffers.len() {
+            bail!("Recording {:?} has no active buffer", path);
+        }
+        Ok(rec)
+    }
+
+    /// returns editor in state at start of recording
+    pub fn editor(&self) -> Result<Editor, failure::Error> {
+        let mut editor = Editor::new();
+        if let Some(text) = &self.config {
+            match config::parse(text) {
+                Ok(opts) => editor.options = opts,
+                Err(e) => error!("{}", failure_to_string(e)),
+            }
+        }
+        editor.zoom = self.zoom;
+        editor.keymap.timeout = Duration::from_millis(editor.options.timeoutlen);
+        for recorded in &self.buffers {
+            let origin = BufferOrigin::Buffer(recorded.text.clone());
+            let mut buff = TextBuffer::new(origin, Some(recorded.cursor), None, self.scale_factor);
+            buff.buffer_type = syntax::find(&recorded.filetype)?;
+            buff.path = recorded.path.clone();
+            editor.buffers.push(buff);
+        }
+        editor.active_buffer = self.active_buffer;
+        Ok(editor)
+    }
+
+    /// returns inputs and times when they were processed
+    fn inputs(&self) -> Result<Vec<(Duration, Input)>, failure::Error> {
+        self.events
+            .iter()
+            .map(|e| Ok((Duration::from_nanos(e.time), e.input()?)))
+            .collect()
+    }
+}
+
+/// Appends inputs processed by editor to recording file
+pub struct Recorder {
+    file: File,
+    start: Instant,
+}
+
+impl Recorder {
+    /// Starts recording to `path` from current state of `editor`, `config`
+    /// is path of its config file and `size` is size of window in pixels or
+    /// terminal in cells
+    pub fn start(
+        path: &Path,
+        editor: &Editor,
+        config: Option<&Path>,
+        frontend: Frontend,
+        size: (u32, u32),
+        scale_factor: f32,
+    ) -> Result<Self, failure::Error> {
+        let buffers = editor
+            .buffers
+            .iter()
+            .map(|buff| RecordedBuffer {
+                path: buff.path.clone(),
+                filetype: match &buff.buffer_type {
+                    BufferType::Lang(name) => name.clone(),
+                    BufferType::Clear => "text".to_string(),
+                },
+                cursor: buff.cursor.text_pos,
+                text: buff.buffer.join("\n"),
+            })
+            .collect();
+        let rec = Recording {
+            frontend,
+            size,
+            scale_factor,
+            config: config.and_then(|path| fs::read_to_string(path).ok()),
+            zoom: editor.zoom,
+            active_buffer: editor.active_buffer,
+            buffers,
+            events: vec![],
+        };
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        let mut file =
+            File::create(path).map_err(|e| format_err!("Could not create {:?}: {}", path, e))?;
+        file.write_all(toml::to_string(&rec)?.as_bytes())?;
+        info!("Recording session to {:?}", path);
+        Ok(Recorder {
+            file,
+            start: Instant::now(),
+        })
+    }
+
+    /// appends `input` processed at `now`
+    pub fn record(&mut self, input: &Input, now: Instant) -> Result<(), failure::Error> {
+        let event = Event::new(input, now.saturating_duration_since(self.start));
+        let text = format!("\n[[events]]\n{}", toml::to_string(&event)?);
+        self.file.write_all(text.as_bytes())?;
+        Ok(())
+    }
+}
+
+/// Feeds inputs of recording to frontend when they are due
+pub struct Player {
+    inputs: VecDeque<(Duration, Input)>,
+    /// when replay started, recorded times are relative to it
+    start: Instant,
+    speed: f64,
+}
+
+impl Player {
+    /// replays `rec` `speed` times faster than it was recorded
+    pub fn new(rec: &Recording, speed: f64) -> Result<Self, failure::Error> {
+        if speed <= 0.0 || !speed.is_finite() {
+            bail!("Invalid replay speed {}", speed);
+        }
+        Ok(Player {
+            inputs: rec.inputs()?.into(),
+            start: Instant::now(),
+            speed,
+        })
+    }
+
+    /// returns when next input is due, `None` when replay is finished
+    pub fn deadline(&self) -> Option<Instant> {
+        let (time, _) = self.inputs.front()?;
+        Some(self.start + time.div_f64(self.speed))
+    }
+
+    /// Returns next input if it is due at `now`, with time when editor has
+    /// to process it. It's time of recording, not of replay, so mappings
+    /// time out the same way at any speed.
+    pub fn next(&mut self, now: Instant) -> Option<(Input, Instant)> {
+        if self.deadline()? > now {
+            return None;
+        }
+        let (time, input) = self.inputs.pop_front()?;
+        Some((input, self.start + time))
+    }
+}
+
+/// What frontend runs
+pub enum Session {
+    /// opens `files`, inputs are recorded to `record` if set
+    Edit {
+        files: Vec<PathBuf>,
+        record: Option<PathBuf>,
+    },
+    /// replays recording at given speed
+    Replay(Recording, f64),
+}
+
+/// Replays `rec` without drawing, returns editor in resulting state.
+/// Buffers are laid out as recording frontend would draw them.
+pub fn replay_headless(rec: &Recording) -> Result<Editor, failure::Error> {
+    let mut editor = rec.editor()?;
+    let mut size = rec.size;
+    // fonts are only needed to lay out text like window does
+    let mut fonts: Option<(FontRegistry<'static>, Options)> = None;
+    let start = Instant::now();
+    for (time, input) in rec.inputs()? {
+        if let Input::Resize(width, height) = input {
+            size = (width, height);
+        }
+        let buff = &editor.buffers[editor.active_buffer];
+        match rec.frontend {
+            Frontend::Terminal => {
+                let (width, height) = (size.0 as u16, size.1 as u16);
+                tui::scroll_to_cursor(&editor.options, width, height.saturating_sub(1), buff);
+                editor.apply_at(input, start + time, &CellMetrics);
+            }
+            Frontend::Window => {
+                let opts = &editor.options;
+                let reload = match &fonts {
+                    Some((_, applied)) => {
+                        opts.font != applied.font || opts.fallback != applied.fallback
+                    }
+                    None => true,
+                };
+                if reload {
+                    let loaded = Fonts::load(&opts.font, &opts.fallback)?;
+                    fonts = Some((FontRegistry::new(loaded, opts.font_size), opts.clone()));
+                }
+                let (registry, applied) = fonts.as_mut().unwrap();
+                *applied = opts.clone();
+                registry.set_size(editor.font_size());
+                let width = (crate::TEXT_WIDTH * size.0 as f32).ceil();
+                renderer::scroll_to_cursor(
+                    registry,
+                    opts,
+                    rec.scale_factor,
+                    width,
+                    size.1 as f32,
+                    buff,
+                );
+                let scale = buff.layout.borrow().params().scale;
+                let metrics = FontMetrics::new(registry.fonts(), scale);
+                editor.apply_at(input, start + time, &metrics);
+            }
+        }
+        editor.keymap.timeout = Duration::from_millis(editor.options.timeoutlen);
+        if editor.quit {
+            break;
+        }
+    }
+    Ok(editor)
+}
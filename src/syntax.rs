@@ -0,0 +1,172 @@
+//! Detection of buffer language.
+//!
+//! Language is detected (in this order) from modeline, file name or
+//! extension, shebang and first line regexes of syntect syntax definitions.
+use crate::buffer::BufferType;
+#[allow(unused_imports)]
+use log::{debug, error, info, trace, warn};
+use once_cell::sync::Lazy;
+use std::path::Path;
+use syntect::parsing::{SyntaxReference, SyntaxSet};
+
+// tests are at the top of the module like in other modules
+#[cfg(test)]
+#[allow(clippy::items_after_test_module)]
+mod tests {
+    use super::detect;
+    use crate::buffer::BufferType;
+    use std::path::Path;
+
+    fn lines(text: &str) -> Vec<String> {
+        text.lines().map(|l| l.to_string()).collect()
+    }
+
+    #[test]
+    fn test_detect() {
+        let lang = |name: &str| BufferType::Lang(name.to_string());
+        assert_eq!(detect(Some(Path::new("src/main.rs")), &[]), lang("Rust"));
+        assert_eq!(detect(Some(Path::new("Makefile")), &[]), lang("Makefile"));
+        assert_eq!(detect(None, &lines("#!/usr/bin/env python3\nprint(1)")), lang("Python"));
+        assert_eq!(detect(None, &lines("#!/bin/sh -e\necho")), lang("Bourne Again Shell (bash)"));
+        assert_eq!(detect(None, &lines("<?xml version=\"1.0\"?>")), lang("XML"));
+        // modeline overrides extension
+        assert_eq!(
+            detect(Some(Path::new("build.txt")), &lines("fn main() {}\n// vim: set ft=rust:")),
+            lang("Rust")
+        );
+        assert_eq!(detect(Some(Path::new("notes.txt")), &lines("hello")), BufferType::Clear);
+        assert_eq!(detect(None, &[]), BufferType::Clear);
+    }
+}
+
+static SYNTAX_SET: Lazy<SyntaxSet> = Lazy::new(|| {
+    trace!("Loading syntax definitions");
+    SyntaxSet::load_defaults_newlines()
+});
+
+/// returns syntax definitions shared by all buffers
+pub fn syntax_set() -> &'static SyntaxSet {
+    &SYNTAX_SET
+}
+
+/// converts syntax to buffer type, plain text is not highlighted
+fn to_buffer_type(syntax: Option<&SyntaxReference>) -> BufferType {
+    match syntax {
+        Some(s) if s.name != syntax_set().find_syntax_plain_text().name => {
+            BufferType::Lang(s.name.clone())
+        }
+        _ => BufferType::Clear,
+    }
+}
+
+/// Finds syntax by name used in `:set filetype=`, which can be syntax name
+/// (`Rust`), extension (`rs`) or name in lowercase (`rust`). Empty name,
+/// `text` or `plain` means plain text.
+pub fn find(name: &str) -> Result<BufferType, failure::Error> {
+    if name.is_empty() || name == "text" || name == "plain" {
+        return Ok(BufferType::Clear);
+    }
+    let ps = syntax_set();
+    let syntax = ps
+        .find_syntax_by_name(name)
+        .or_else(|| ps.find_syntax_by_token(name));
+    match syntax {
+        Some(s) => Ok(to_buffer_type(Some(s))),
+        None => bail!("Unknown filetype \"{}\"", name),
+    }
+}
+
+/// returns filetype set by modeline, e.g. `vim: set ft=rust:` or `-*- mode: rust -*-`
+/// Modelines are searched in first and last 5 lines.
+fn modeline(lines: &[String]) -> Option<String> {
+    let head = lines.iter().take(5);
+    let tail = lines.iter().skip(lines.len().saturating_sub(5).max(5));
+    for line in head.chain(tail) {
+        if let Some(pos) = line.find("vim:").or_else(|| line.find("vi:")) {
+            let rest = &line[pos..];
+            for word in rest.split(|c: char| c.is_whitespace() || c == ':') {
+                if let Some(ft) = word
+                    .strip_prefix("filetype=")
+                    .or_else(|| word.strip_prefix("ft="))
+                    .or_else(|| word.strip_prefix("syntax="))
+                    .or_else(|| word.strip_prefix("syn="))
+                {
+                    return Some(ft.to_string());
+                }
+            }
+        }
+        let emacs = line
+            .split_once("-*-")
+            .and_then(|(_, rest)| rest.split_once("-*-"))
+            .map(|(mode, _)| mode);
+        if let Some(rest) = emacs {
+            for part in rest.split(';') {
+                let part = part.trim();
+                match part.split_once(':') {
+                    Some((key, value)) if key.trim().eq_ignore_ascii_case("mode") => {
+                        return Some(value.trim().to_lowercase())
+                    }
+                    // `-*- rust -*-`
+                    None if !part.is_empty() => return Some(part.to_lowercase()),
+                    _ => {}
+                }
+            }
+        }
+    }
+    None
+}
+
+/// returns name of interpreter from shebang, e.g. `python` from
+/// `#!/usr/bin/env python3`
+fn shebang(first_line: &str) -> Option<String> {
+    let rest = first_line.strip_prefix("#!")?;
+    let mut words = rest.split_whitespace();
+    let mut interpreter = words.next()?.rsplit('/').next()?;
+    if interpreter == "env" {
+        interpreter = words.find(|w| !w.starts_with('-'))?;
+    }
+    // python3 -> python
+    Some(
+        interpreter
+            .trim_end_matches(|c: char| c.is_ascii_digit() || c == '.')
+            .to_string(),
+    )
+}
+
+/// Detects language of buffer from its path and content
+pub fn detect(path: Option<&Path>, lines: &[String]) -> BufferType {
+    let ps = syntax_set();
+
+    if let Some(ft) = modeline(lines) {
+        match find(&ft) {
+            Ok(t) => return t,
+            Err(_) => warn!("Unknown filetype \"{}\" in modeline", ft),
+        }
+    }
+
+    if let Some(path) = path {
+        let name = path.file_name().and_then(|n| n.to_str()).unwrap_or("");
+        let extension = path.extension().and_then(|e| e.to_str()).unwrap_or("");
+        let syntax = ps
+            .find_syntax_by_extension(name)
+            .or_else(|| ps.find_syntax_by_extension(extension));
+        if syntax.is_some() {
+            return to_buffer_type(syntax);
+        }
+    }
+
+    let first_line = match lines.first() {
+        Some(l) => l,
+        None => return BufferType::Clear,
+    };
+    if let Some(interpreter) = shebang(first_line) {
+        let syntax = match interpreter.as_str() {
+            "sh" | "dash" | "zsh" | "ksh" => ps.find_syntax_by_extension("sh"),
+            i => ps.find_syntax_by_token(i),
+        };
+        if syntax.is_some() {
+            return to_buffer_type(syntax);
+        }
+    }
+    to_buffer_type(ps.find_syntax_by_first_line(first_line))
+}
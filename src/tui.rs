@@ -0,0 +1,637 @@
+//! Terminal frontend, selected by `--tui`.
+//!
+//! It shares buffers, keymap, modes and commands with the window frontend,
+//! only drawing and input differ. Text is laid out in terminal cells: every
+//! char is one cell wide (two for wide chars) and display lines are one cell
+//! high. Colors are sent as 24-bit true color.
+use crate::buffer::TextBuffer;
+use crate::config;
+use crate::debug::failure_to_string;
+use crate::editor::{Editor, Input, Mouse, State, View};
+use crate::highlight::Worker;
+use crate::key::{Key, KeyCode, Modifiers};
+use crate::layout::{cell_width, CellMetrics, DisplayLine, LayoutParams};
+use crate::options::Options;
+use crate::renderer::{load_user_themes, UiColors};
+use crate::replay::{Frontend, Player, Recorder, Session};
+use crossterm::cursor::{self, CursorShape};
+use crossterm::event::{
+    self, Event, KeyEvent, KeyEventKind, KeyModifiers, MouseButton, MouseEvent, MouseEventKind,
+};
+use crossterm::style::{
+    Attribute, Color as TermColor, Print, SetAttribute, SetBackgroundColor, SetForegroundColor,
+};
+use crossterm::terminal::{self, ClearType};
+use crossterm::{execute, queue};
+#[allow(unused_imports)]
+use log::{debug, error, info, trace, warn};
+use rusttype::Scale;
+use std::io::{self, BufWriter, Stdout, Write};
+use std::path::PathBuf;
+use std::sync::mpsc::{self, Receiver};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use syntect::highlighting::{Color, FontStyle, Style, Theme, ThemeSet};
+
+#[cfg(test)]
+mod tests {
+    use super::{convert_key, convert_mouse, status_line};
+    use crate::buffer::{BufferOrigin, TextBuffer};
+    use crate::editor::{Mouse, State};
+    use crate::key::parse_keys;
+    use crossterm::event::{KeyCode, KeyEvent, KeyModifiers, MouseButton, MouseEvent, MouseEventKind};
+
+    #[test]
+    fn test_convert_key() {
+        let key = |code, mods| convert_key(KeyEvent::new(code, mods)).unwrap();
+        assert_eq!(
+            key(KeyCode::Char('w'), KeyModifiers::CONTROL),
+            parse_keys("<C-w>").unwrap()[0]
+        );
+        assert_eq!(
+            key(KeyCode::Char('A'), KeyModifiers::SHIFT),
+            parse_keys("A").unwrap()[0]
+        );
+        assert_eq!(
+            key(KeyCode::Esc, KeyModifiers::NONE),
+            parse_keys("<Esc>").unwrap()[0]
+        );
+        assert_eq!(
+            key(KeyCode::Left, KeyModifiers::SHIFT),
+            parse_keys("<S-Left>").unwrap()[0]
+        );
+        assert_eq!(
+            key(KeyCode::F(5), KeyModifiers::NONE),
+            parse_keys("<F5>").unwrap()[0]
+        );
+    }
+
+    #[test]
+    fn test_convert_mouse() {
+        let mouse = |kind, column, row| {
+            convert_mouse(
+                MouseEvent {
+                    kind,
+                    column,
+                    row,
+                    modifiers: KeyModifiers::NONE,
+                },
+                10,
+            )
+        };
+        let left = MouseButton::Left;
+        assert_eq!(mouse(MouseEventKind::Down(left), 3, 2), Some(Mouse::Press(3.0, 2.0)));
+        // status line
+        assert_eq!(mouse(MouseEventKind::Down(left), 3, 10), None);
+        assert_eq!(mouse(MouseEventKind::Drag(left), 3, 11), Some(Mouse::Drag(3.0, 9.0)));
+        assert_eq!(mouse(MouseEventKind::ScrollUp, 0, 0), Some(Mouse::Wheel(-1)));
+    }
+
+    #[test]
+    fn test_status_line() {
+        let mut buff = TextBuffer::new(BufferOrigin::Buffer("ab\ncd".to_string()), None, None, 1.0);
+        buff.move_cursor_absolute(1, 1);
+        assert_eq!(
+            status_line(State::Normal, "", &buff, 20),
+            "[No Name]        2:2"
+        );
+        assert_eq!(
+            status_line(State::Insert, "", &buff, 20),
+            "-- INSERT -- [No Na"
+        );
+        assert_eq!(status_line(State::Command, "set", &buff, 10), ":set");
+    }
+}
+
+/// how long to wait for input when there is nothing else to do, config file
+/// changes are checked this often
+const IDLE_POLL: Duration = Duration::from_millis(250);
+/// how long to wait for input while lines are being highlighted
+const HIGHLIGHT_POLL: Duration = Duration::from_millis(20);
+
+/// converts key event of terminal, shift is kept only for special keys like
+/// in the window frontend
+fn convert_key(key: KeyEvent) -> Option<Key> {
+    let code = match key.code {
+        event::KeyCode::Char(c) => KeyCode::Char(c),
+        event::KeyCode::Esc => KeyCode::Esc,
+        event::KeyCode::Enter => KeyCode::Enter,
+        event::KeyCode::Tab | event::KeyCode::BackTab => KeyCode::Tab,
+        event::KeyCode::Backspace => KeyCode::Backspace,
+        event::KeyCode::Delete => KeyCode::Delete,
+        event::KeyCode::Insert => KeyCode::Insert,
+        event::KeyCode::Left => KeyCode::Left,
+        event::KeyCode::Right => KeyCode::Right,
+        event::KeyCode::Up => KeyCode::Up,
+        event::KeyCode::Down => KeyCode::Down,
+        event::KeyCode::Home => KeyCode::Home,
+        event::KeyCode::End => KeyCode::End,
+        event::KeyCode::PageUp => KeyCode::PageUp,
+        event::KeyCode::PageDown => KeyCode::PageDown,
+        event::KeyCode::F(n) => KeyCode::F(n),
+        _ => return None,
+    };
+    let shift = match code {
+        KeyCode::Char(_) => false,
+        _ => key.modifiers.contains(KeyModifiers::SHIFT) || key.code == event::KeyCode::BackTab,
+    };
+    Some(Key {
+        code,
+        mods: Modifiers {
+            ctrl: key.modifiers.contains(KeyModifiers::CONTROL),
+            alt: key.modifiers.contains(KeyModifiers::ALT),
+            shift,
+        },
+    })
+}
+
+/// converts mouse event of terminal with `text_height` rows of text, clicks
+/// on status line are ignored and dragging over it is clamped to text
+fn convert_mouse(mouse: MouseEvent, text_height: u16) -> Option<Mouse> {
+    let (x, y) = (mouse.column as f32, mouse.row as f32);
+    let last_row = text_height.saturating_sub(1) as f32;
+    match mouse.kind {
+        MouseEventKind::Down(MouseButton::Left) if mouse.row < text_height => {
+            Some(Mouse::Press(x, y))
+        }
+        MouseEventKind::Drag(MouseButton::Left) => Some(Mouse::Drag(x, y.min(last_row))),
+        MouseEventKind::ScrollDown => Some(Mouse::Wheel(1)),
+        MouseEventKind::ScrollUp => Some(Mouse::Wheel(-1)),
+        _ => None,
+    }
+}
+
+/// Returns text of status line `width` cells wide: mode, file name and
+/// cursor position, or command line in Command mode
+fn status_line(state: State, command_line: &str, buff: &TextBuffer, width: usize) -> String {
+    if state == State::Command {
+        return format!(":{}", command_line).chars().take(width).collect();
+    }
+    let name = match &buff.path {
+        Some(path) => path.display().to_string(),
+        None => "[No Name]".to_string(),
+    };
+    let left = match state {
+        State::Insert => format!("-- INSERT -- {}", name),
+        State::Visual => format!("-- VISUAL -- {}", name),
+        _ => name,
+    };
+    let (row, col) = buff.cursor.text_pos;
+    let right = format!("{}:{}", row + 1, col + 1);
+    let len = left.chars().count();
+    if len + 1 + right.len() > width {
+        return left.chars().take(width.saturating_sub(1)).collect();
+    }
+    format!("{}{:>pad$}", left, right, pad = width - len)
+}
+
+fn term_color(c: Color) -> TermColor {
+    TermColor::Rgb {
+        r: c.r,
+        g: c.g,
+        b: c.b,
+    }
+}
+
+/// returns `fg` drawn over opaque `bg`, terminal colors have no alpha
+fn blend(fg: Color, bg: Color) -> Color {
+    let a = fg.a as u32;
+    let mix = |f: u8, b: u8| ((f as u32 * a + b as u32 * (255 - a)) / 255) as u8;
+    Color {
+        r: mix(fg.r, bg.r),
+        g: mix(fg.g, bg.g),
+        b: mix(fg.b, bg.b),
+        a: 0xFF,
+    }
+}
+
+/// colors and font style of text written to terminal
+#[derive(Clone, Copy, PartialEq)]
+struct Pen {
+    fg: Color,
+    bg: Color,
+    font_style: FontStyle,
+}
+
+/// Sets layout parameters of `buff` for text area `width` × `height` cells
+/// and scrolls it to cursor, like [Tui::draw] does before drawing
+pub fn scroll_to_cursor(opts: &Options, width: u16, height: u16, buff: &TextBuffer) {
+    let params = LayoutParams::new(opts, Scale::uniform(1.0), width as f32);
+    let mut layout = buff.layout.borrow_mut();
+    layout.set_params(params);
+    let mut view_pos = buff.view_pos.get();
+    layout.scroll_to_cursor(
+        &CellMetrics,
+        &buff.buffer,
+        buff.cursor.text_pos,
+        height as f32,
+        &mut view_pos,
+    );
+    buff.view_pos.set(view_pos);
+}
+
+/// puts terminal back to normal mode, used on exit and panic
+fn restore_terminal() {
+    let _ = execute!(
+        io::stdout(),
+        event::DisableMouseCapture,
+        terminal::LeaveAlternateScreen,
+        cursor::SetCursorShape(CursorShape::Block),
+        cursor::Show
+    );
+    let _ = terminal::disable_raw_mode();
+}
+
+/// Runs editor in terminal until `:quit`
+pub fn run(session: Session) -> Result<(), failure::Error> {
+    let mut editor = Editor::new();
+    let mut config_path = None;
+    let mut player = None;
+    let (config_tx, config_rx) = mpsc::channel();
+    match session {
+        Session::Edit { files, record } => {
+            config_path = editor.load_config();
+            if let Some(path) = config_path.clone() {
+                config::watch(path, move || {
+                    let _ = config_tx.send(());
+                });
+            }
+            // cells are not scaled
+            editor.open_files(&files, 1.0);
+            if let Some(path) = record {
+                let (width, height) = terminal::size()?;
+                editor.recorder = Some(Recorder::start(
+                    &path,
+                    &editor,
+                    config_path.as_deref(),
+                    Frontend::Terminal,
+                    (width as u32, height as u32),
+                    1.0,
+                )?);
+            }
+        }
+        Session::Replay(rec, speed) => {
+            if terminal::size()? != (rec.size.0 as u16, rec.size.1 as u16) {
+                warn!("Terminal size differs from recorded {:?}", rec.size);
+            }
+            editor = rec.editor()?;
+            player = Some(Player::new(&rec, speed)?);
+        }
+    }
+    let mut tui = Tui::new();
+    tui.sync_options(&mut editor);
+
+    terminal::enable_raw_mode()?;
+    let hook = std::panic::take_hook();
+    std::panic::set_hook(Box::new(move |info| {
+        restore_terminal();
+        hook(info);
+    }));
+    execute!(
+        tui.out,
+        terminal::EnterAlternateScreen,
+        event::EnableMouseCapture
+    )?;
+    let result = tui.event_loop(&mut editor, player, config_path, config_rx);
+    restore_terminal();
+    result
+}
+
+/// Terminal and highlighting state of terminal frontend
+struct Tui {
+    out: BufWriter<Stdout>,
+    ts: ThemeSet,
+    theme: Arc<Theme>,
+    ui: UiColors,
+    /// thread highlighting visible lines, it's polled for results
+    highlighter: Worker,
+    /// options currently used by terminal and buffers
+    applied: Options,
+    /// number of rows showing text, the last row of terminal is status line
+    text_height: u16,
+    /// style of the last written text, it's not sent again for next chars
+    pen: Option<Pen>,
+}
+
+impl Tui {
+    fn new() -> Self {
+        let mut ts = ThemeSet::load_defaults();
+        load_user_themes(&mut ts);
+        let theme = Arc::new(ts.themes["base16-ocean.dark"].clone());
+        Tui {
+            out: BufWriter::new(io::stdout()),
+            ui: UiColors::from_theme(&theme),
+            ts,
+            theme,
+            highlighter: Worker::start(|| {}),
+            applied: Options::default(),
+            text_height: 0,
+            pen: None,
+        }
+    }
+
+    /// Processes events until `:quit`, inputs of `player` are processed
+    /// instead of user input until replay finishes
+    fn event_loop(
+        &mut self,
+        editor: &mut Editor,
+        mut player: Option<Player>,
+        config_path: Option<PathBuf>,
+        config_changes: Receiver<()>,
+    ) -> Result<(), failure::Error> {
+        let mut redraw = true;
+        while !editor.quit {
+            if redraw {
+                self.draw(editor.view())?;
+            }
+            // everything is drawn again after any event
+            redraw = true;
+
+            let replay_due = player.as_ref().and_then(Player::deadline);
+            if player.is_some() && replay_due.is_none() {
+                info!("Replay finished");
+                player = None;
+            }
+            let mut timeout = match replay_due.or_else(|| editor.deadline()) {
+                Some(deadline) => deadline.saturating_duration_since(Instant::now()),
+                None => IDLE_POLL,
+            };
+            if editor
+                .buffers
+                .iter()
+                .any(|b| b.highlight.borrow().is_pending())
+            {
+                timeout = timeout.min(HIGHLIGHT_POLL);
+            }
+            if event::poll(timeout)? {
+                match event::read()? {
+                    _ if player.is_some() => (),
+                    Event::Key(key) if key.kind != KeyEventKind::Release => {
+                        if let Some(key) = convert_key(key) {
+                            editor.apply(Input::Key(key), &CellMetrics);
+                        }
+                    }
+                    Event::Mouse(mouse) => {
+                        if let Some(mouse) = convert_mouse(mouse, self.text_height) {
+                            editor.apply(Input::Mouse(mouse), &CellMetrics);
+                        }
+                    }
+                    Event::Resize(width, height) => {
+                        editor.apply(Input::Resize(width as u32, height as u32), &CellMetrics)
+                    }
+                    _ => (),
+                }
+            } else if let Some(player) = &mut player {
+                // terminal can't be resized to recorded size
+                while let Some((input, at)) = player.next(Instant::now()) {
+                    editor.apply_at(input, at, &CellMetrics);
+                }
+            } else if editor.deadline().is_some() {
+                editor.apply(Input::Tick, &CellMetrics);
+            } else {
+                redraw = false;
+            }
+
+            for done in self.highlighter.results() {
+                let id = done.id();
+                let buff = editor
+                    .buffers
+                    .iter()
+                    .find(|b| b.highlight.borrow().id() == id);
+                if let Some(buff) = buff {
+                    redraw |= buff.highlight.borrow_mut().apply(done);
+                }
+            }
+            if config_changes.try_iter().count() > 0 {
+                if let Some(path) = &config_path {
+                    match config::load(path) {
+                        Ok(opts) => {
+                            info!("Reloaded config {:?}", path);
+                            editor.options = opts;
+                            redraw = true;
+                        }
+                        Err(e) => error!("{}", failure_to_string(e)),
+                    }
+                }
+            }
+            self.sync_options(editor);
+        }
+        Ok(())
+    }
+
+    /// Applies options that changed since last call, options of fonts and
+    /// window have no effect in terminal
+    fn sync_options(&mut self, editor: &mut Editor) {
+        if editor.options.theme != self.applied.theme {
+            match self.set_theme(&editor.options.theme) {
+                Ok(()) => {
+                    for buff in &mut editor.buffers {
+                        buff.highlight.get_mut().clear();
+                    }
+                }
+                Err(e) => {
+                    error!("{}", failure_to_string(e));
+                    editor.options.theme = self.applied.theme.clone();
+                }
+            }
+        }
+        if editor.atlas_dump.take().is_some() {
+            error!("Glyph cache is not used in terminal");
+        }
+        editor.keymap.timeout = Duration::from_millis(editor.options.timeoutlen);
+        self.applied = editor.options.clone();
+    }
+
+    /// switches highlighting theme and UI colors like
+    /// [Renderer::set_theme](crate::renderer::Renderer::set_theme)
+    fn set_theme(&mut self, name: &str) -> Result<(), failure::Error> {
+        if !self.ts.themes.contains_key(name) {
+            load_user_themes(&mut self.ts);
+        }
+        match self.ts.themes.get(name) {
+            Some(theme) => {
+                trace!("Switching theme to {:?}", name);
+                self.theme = Arc::new(theme.clone());
+                self.ui = UiColors::from_theme(theme);
+                Ok(())
+            }
+            None => bail!("Unknown theme \"{}\"", name),
+        }
+    }
+
+    /// draws active buffer, status line and cursor
+    fn draw(&mut self, view: View) -> Result<(), failure::Error> {
+        let (width, height) = terminal::size()?;
+        self.text_height = height.saturating_sub(1);
+        let buff = view.buffer;
+        scroll_to_cursor(view.options, width, self.text_height, buff);
+        let mut layout = buff.layout.borrow_mut();
+        let params = layout.params().clone();
+        let view_pos = buff.view_pos.get();
+        let lines = layout.rows(
+            &CellMetrics,
+            &buff.buffer,
+            view_pos.0,
+            self.text_height as f32,
+        );
+        let last_row = lines.last().map_or(view_pos.0, |l| l.row);
+        buff.highlight.borrow_mut().request(
+            &buff.buffer_type,
+            &self.theme,
+            &buff.buffer,
+            last_row,
+            &self.highlighter,
+        );
+
+        queue!(self.out, cursor::Hide)?;
+        for i in 0..self.text_height {
+            queue!(self.out, cursor::MoveTo(0, i))?;
+            match lines.get(i as usize) {
+                Some(line) => self.draw_line(line, &params, buff)?,
+                None => self.clear_line(self.ui.background)?,
+            }
+        }
+
+        let status = status_line(view.state, view.command_line, buff, width as usize);
+        let pen = Pen {
+            fg: self.ui.background,
+            bg: self.ui.foreground,
+            font_style: FontStyle::empty(),
+        };
+        queue!(self.out, cursor::MoveTo(0, self.text_height))?;
+        self.set_pen(pen)?;
+        queue!(self.out, Print(&status))?;
+        self.clear_line(pen.bg)?;
+
+        let cursor = match view.state {
+            State::Command => Some((status.chars().count() as u16, self.text_height)),
+            _ => layout
+                .screen_pos(&CellMetrics, &buff.buffer, view_pos, buff.cursor.text_pos)
+                .filter(|cell| cell.x < width as f32 && cell.y < self.text_height as f32)
+                .map(|cell| (cell.x as u16, cell.y as u16)),
+        };
+        if let Some((x, y)) = cursor {
+            let shape = match view.state {
+                State::Insert | State::Command => CursorShape::Line,
+                _ => CursorShape::Block,
+            };
+            queue!(
+                self.out,
+                cursor::MoveTo(x, y),
+                cursor::SetCursorShape(shape),
+                cursor::Show
+            )?;
+        }
+        self.out.flush()?;
+        Ok(())
+    }
+
+    /// Draws one display line with highlighting styles, selection and
+    /// showbreak. Glyphs scrolled out of view are skipped.
+    fn draw_line(
+        &mut self,
+        line: &DisplayLine,
+        params: &LayoutParams,
+        buff: &TextBuffer,
+    ) -> io::Result<()> {
+        let ui = self.ui;
+        let cursor = buff.cursor.text_pos;
+        let selection = buff
+            .selection
+            .map(|anchor| (anchor.min(cursor), anchor.max(cursor)));
+        let background = match ui.line_highlight {
+            Some(color) if line.row == cursor.0 => color,
+            _ => ui.background,
+        };
+        let offset = if params.wrap {
+            0.0
+        } else {
+            line.scroll_offset(buff.view_pos.get().1)
+        };
+
+        // cells already written
+        let mut x = 0.0;
+        if line.continuation {
+            self.set_pen(Pen {
+                fg: ui.gutter_foreground,
+                bg: ui.gutter.unwrap_or(background),
+                font_style: FontStyle::empty(),
+            })?;
+            queue!(self.out, Print(&params.showbreak))?;
+            x = params.showbreak.chars().map(|c| cell_width(c) as f32).sum();
+        }
+        for g in &line.glyphs {
+            let gx = g.x - offset;
+            if gx < x || gx + g.advance > params.width {
+                continue;
+            }
+            let style = match buff
+                .highlight
+                .borrow()
+                .line(line.row)
+                .and_then(|s| s.get(g.col))
+            {
+                Some(style) => *style,
+                None => Style {
+                    foreground: ui.foreground,
+                    ..Default::default()
+                },
+            };
+            let selected = matches!(selection,
+                Some((start, end)) if (line.row, g.col) >= start && (line.row, g.col) <= end);
+            let bg = if selected {
+                blend(ui.selection, background)
+            } else {
+                background
+            };
+            self.set_pen(Pen {
+                fg: blend(style.foreground, bg),
+                bg,
+                font_style: style.font_style,
+            })?;
+            // control chars would move terminal cursor
+            let ch = if g.ch.is_control() { ' ' } else { g.ch };
+            if gx > x {
+                // part of wide char scrolled out of view
+                queue!(self.out, Print(" ".repeat((gx - x) as usize)))?;
+            }
+            queue!(self.out, Print(ch))?;
+            x = gx + g.advance;
+        }
+        self.clear_line(background)
+    }
+
+    /// fills rest of the line with `bg`
+    fn clear_line(&mut self, bg: Color) -> io::Result<()> {
+        self.pen = None;
+        queue!(
+            self.out,
+            SetAttribute(Attribute::Reset),
+            SetBackgroundColor(term_color(bg)),
+            terminal::Clear(ClearType::UntilNewLine)
+        )
+    }
+
+    fn set_pen(&mut self, pen: Pen) -> io::Result<()> {
+        if self.pen == Some(pen) {
+            return Ok(());
+        }
+        self.pen = Some(pen);
+        queue!(
+            self.out,
+            SetAttribute(Attribute::Reset),
+            SetForegroundColor(term_color(pen.fg)),
+            SetBackgroundColor(term_color(pen.bg))
+        )?;
+        if pen.font_style.contains(FontStyle::BOLD) {
+            queue!(self.out, SetAttribute(Attribute::Bold))?;
+        }
+        if pen.font_style.contains(FontStyle::ITALIC) {
+            queue!(self.out, SetAttribute(Attribute::Italic))?;
+        }
+        if pen.font_style.contains(FontStyle::UNDERLINE) {
+            queue!(self.out, SetAttribute(Attribute::Underlined))?;
+        }
+        Ok(())
+    }
+}
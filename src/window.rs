@@ -1,22 +1,23 @@
+use crate::UserEvent;
 use glium::*;
 use glutin::event_loop:: EventLoop;
 extern crate gl;
 
 pub struct WindowContext {
-    pub event_loop: EventLoop<()>,
+    pub event_loop: EventLoop<UserEvent>,
     pub display: Display,
     pub gl: gl::Gl,
 }
 
 
 impl WindowContext {
-    pub fn new(title: &str) -> Result<Self, failure::Error> {
+    pub fn new(title: &str, (width, height): (u32, u32)) -> Result<Self, failure::Error> {
 
         let window = glium::glutin::window::WindowBuilder::new()
-            .with_inner_size(glium::glutin::dpi::PhysicalSize::new(512u32, 512u32))
+            .with_inner_size(glium::glutin::dpi::PhysicalSize::new(width, height))
             .with_title(title);
         let context = glium::glutin::ContextBuilder::new().with_vsync(true);
-        let event_loop = glium::glutin::event_loop::EventLoop::new();
+        let event_loop = glium::glutin::event_loop::EventLoopBuilder::with_user_event().build();
 
         let display = glium::Display::new(window, context, &event_loop)?;
 